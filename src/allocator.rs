@@ -3,6 +3,7 @@ use alloc::boxed::Box;
 use alloc::vec::Vec;
 use core::ptr::{null, null_mut};
 use linked_list_allocator::LockedHeap;
+use crate::trace;
 use x86_64::{
     structures::paging::{
         mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB
@@ -11,7 +12,7 @@ use x86_64::{
 
 
 pub const HEAP_START: usize = 0x4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100KiB
+pub const HEAP_SIZE: usize = 1024 * 1024; // 1MiB, room for the desktop canvases
 
 pub struct Dummy;
 unsafe impl GlobalAlloc for Dummy{
@@ -41,14 +42,28 @@ pub fn init_heap(
         let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
         unsafe {
             mapper.map_to(page, frame, flags, frame_allocator)?.flush();
-            ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+            ALLOCATOR.0.lock().init(HEAP_START, HEAP_SIZE);
         }
     }
     Ok(())
 }
 
+///The heap, hitting the allocation tracepoints.
+pub struct TracedHeap(LockedHeap);
+unsafe impl GlobalAlloc for TracedHeap{
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8{
+        let ptr = self.0.alloc(layout);
+        trace::ALLOC.hit(layout.size() as u64, ptr as u64);
+        ptr
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout){
+        trace::DEALLOC.hit(layout.size() as u64, ptr as u64);
+        self.0.dealloc(ptr, layout)
+    }
+}
+
 #[global_allocator]
-static ALLOCATOR:LockedHeap = LockedHeap::empty();
+static ALLOCATOR: TracedHeap = TracedHeap(LockedHeap::empty());
 
 #[test_case]
 fn test_heap_box(){
@@ -0,0 +1,248 @@
+//! Parser for the subset of ANSI/VT100 escape sequences the consoles understand.
+//!
+//! Supported are SGR colours and bold (`ESC[...m`), cursor positioning (`ESC[r;cH`, `ESC[nA` to `ESC[nD`),
+//! erasing the screen and line (`ESC[nJ`, `ESC[nK`) and saving/restoring the cursor (`ESC7`/`ESC8`,
+//! `ESC[s`/`ESC[u`). Anything else is swallowed so it does not show up as garbage.
+
+const ESC: char = '\x1b';
+const MAX_PARAMS: usize = 8;
+
+///Which part of the screen or line to erase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EraseMode{
+    ///From the cursor to the end.
+    ToEnd,
+    ///From the start up to and including the cursor.
+    ToStart,
+    All,
+}
+impl EraseMode{
+    fn from_param(param: u16) -> EraseMode{
+        match param{
+            1 => EraseMode::ToStart,
+            2 | 3 => EraseMode::All,
+            _ => EraseMode::ToEnd,
+        }
+    }
+}
+
+///Parameters of a select graphic rendition sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SgrParams{
+    params: [u16; MAX_PARAMS],
+    len: usize,
+}
+impl SgrParams{
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_{
+        self.params[..self.len].iter().copied()
+    }
+}
+
+///What the console should do in response to the characters fed to the parser.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnsiAction{
+    ///Print a character; control characters like '\n' are left to the console.
+    Print(char),
+    ///Change colours or intensity.
+    Sgr(SgrParams),
+    ///Move the cursor to the 0-based (row, column).
+    CursorTo(usize, usize),
+    ///Move the cursor by (rows, columns).
+    CursorMove(isize, isize),
+    EraseDisplay(EraseMode),
+    EraseLine(EraseMode),
+    SaveCursor,
+    RestoreCursor,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State{
+    Normal,
+    Escape,
+    Csi,
+}
+
+///Escape sequence state machine; feed it every character written to the console.
+pub struct AnsiParser{
+    state: State,
+    params: [u16; MAX_PARAMS],
+    len: usize,
+    ///Set for private sequences (`ESC[?...`), which are ignored.
+    private: bool,
+}
+impl AnsiParser{
+    pub const fn new() -> AnsiParser{
+        AnsiParser{state: State::Normal, params: [0; MAX_PARAMS], len: 0, private: false}
+    }
+    ///Process one character, returning the action it completes, if any.
+    pub fn advance(&mut self, c: char) -> Option<AnsiAction>{
+        match self.state{
+            State::Normal => {
+                if c == ESC{
+                    self.state = State::Escape;
+                    None
+                } else {
+                    Some(AnsiAction::Print(c))
+                }
+            },
+            State::Escape => {
+                self.state = State::Normal;
+                match c{
+                    '[' => {
+                        self.state = State::Csi;
+                        self.params = [0; MAX_PARAMS];
+                        self.len = 0;
+                        self.private = false;
+                        None
+                    },
+                    '7' => Some(AnsiAction::SaveCursor),
+                    '8' => Some(AnsiAction::RestoreCursor),
+                    _ => None,
+                }
+            },
+            State::Csi => match c{
+                '0'..='9' => {
+                    if self.len == 0{
+                        self.len = 1;
+                    }
+                    if let Some(param) = self.params.get_mut(self.len - 1){
+                        *param = param.saturating_mul(10).saturating_add(c as u16 - '0' as u16);
+                    }
+                    None
+                },
+                ';' => {
+                    //an empty first parameter still counts
+                    self.len = (self.len.max(1) + 1).min(MAX_PARAMS + 1);
+                    None
+                },
+                '?' => {
+                    self.private = true;
+                    None
+                },
+                '\x40'..='\x7e' => {
+                    self.state = State::Normal;
+                    if self.private{
+                        None
+                    } else {
+                        self.execute(c)
+                    }
+                },
+                //parameter and intermediate bytes we do not support
+                '\x20'..='\x3f' => None,
+                _ => {
+                    self.state = State::Normal;
+                    None
+                },
+            },
+        }
+    }
+    ///Parameter `index`, or `default` if it is missing or zero.
+    fn param(&self, index: usize, default: u16) -> u16{
+        match self.params.get(index){
+            Some(&param) if index < self.len && param != 0 => param,
+            _ => default,
+        }
+    }
+    fn execute(&self, command: char) -> Option<AnsiAction>{
+        let count = self.param(0, 1) as isize;
+        match command{
+            'm' => Some(AnsiAction::Sgr(SgrParams{params: self.params, len: self.len.min(MAX_PARAMS)})),
+            'H' | 'f' => Some(AnsiAction::CursorTo(self.param(0, 1) as usize - 1, self.param(1, 1) as usize - 1)),
+            'A' => Some(AnsiAction::CursorMove(-count, 0)),
+            'B' => Some(AnsiAction::CursorMove(count, 0)),
+            'C' => Some(AnsiAction::CursorMove(0, count)),
+            'D' => Some(AnsiAction::CursorMove(0, -count)),
+            'J' => Some(AnsiAction::EraseDisplay(EraseMode::from_param(self.param(0, 0)))),
+            'K' => Some(AnsiAction::EraseLine(EraseMode::from_param(self.param(0, 0)))),
+            's' => Some(AnsiAction::SaveCursor),
+            'u' => Some(AnsiAction::RestoreCursor),
+            _ => None,
+        }
+    }
+}
+impl Default for AnsiParser{
+    fn default() -> AnsiParser{
+        AnsiParser::new()
+    }
+}
+
+///VGA palette index of the ANSI colours black, red, green, yellow, blue, magenta, cyan and white.
+const ANSI_TO_VGA: [u8; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+
+///Colours and intensity set through SGR sequences, as indices into the 16 colour VGA palette.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextAttributes{
+    pub foreground: u8,
+    pub background: u8,
+    pub bold: bool,
+}
+impl TextAttributes{
+    pub const fn new(foreground: u8, background: u8) -> TextAttributes{
+        TextAttributes{foreground, background, bold: false}
+    }
+    ///Foreground to draw with; bold brightens the eight dark colours.
+    pub fn effective_foreground(&self) -> u8{
+        if self.bold && self.foreground < 8 {self.foreground + 8} else {self.foreground}
+    }
+    ///Apply an SGR sequence. `defaults` are used for resets and the default colour codes 39 and 49.
+    pub fn apply_sgr(&mut self, sgr: &SgrParams, defaults: TextAttributes){
+        if sgr.len == 0{
+            *self = defaults;
+        }
+        for param in sgr.iter(){
+            match param{
+                0 => *self = defaults,
+                1 => self.bold = true,
+                22 => self.bold = false,
+                30..=37 => self.foreground = ANSI_TO_VGA[(param - 30) as usize],
+                39 => self.foreground = defaults.foreground,
+                40..=47 => self.background = ANSI_TO_VGA[(param - 40) as usize],
+                49 => self.background = defaults.background,
+                90..=97 => self.foreground = ANSI_TO_VGA[(param - 90) as usize] + 8,
+                100..=107 => self.background = ANSI_TO_VGA[(param - 100) as usize] + 8,
+                _ => {},
+            }
+        }
+    }
+}
+
+//----------TEST CASES------------
+#[test_case]
+fn parses_cursor_and_erase(){
+    let mut parser = AnsiParser::new();
+    let actions: [Option<AnsiAction>; 6] = [
+        "\x1b[5;10H", "\x1b[2J", "\x1b[K", "\x1b[3D", "\x1b7", "a",
+    ].map(|sequence| sequence.chars().filter_map(|c| parser.advance(c)).last());
+    assert_eq!(actions, [
+        Some(AnsiAction::CursorTo(4, 9)),
+        Some(AnsiAction::EraseDisplay(EraseMode::All)),
+        Some(AnsiAction::EraseLine(EraseMode::ToEnd)),
+        Some(AnsiAction::CursorMove(0, -3)),
+        Some(AnsiAction::SaveCursor),
+        Some(AnsiAction::Print('a')),
+    ]);
+}
+#[test_case]
+fn sgr_sets_colours(){
+    let mut parser = AnsiParser::new();
+    let defaults = TextAttributes::new(7, 0);
+    let mut attributes = defaults;
+    for c in "\x1b[1;31;44m".chars(){
+        if let Some(AnsiAction::Sgr(sgr)) = parser.advance(c){
+            attributes.apply_sgr(&sgr, defaults);
+        }
+    }
+    assert_eq!((attributes.effective_foreground(), attributes.background), (12, 1));
+    for c in "\x1b[m".chars(){
+        if let Some(AnsiAction::Sgr(sgr)) = parser.advance(c){
+            attributes.apply_sgr(&sgr, defaults);
+        }
+    }
+    assert_eq!(attributes, defaults);
+}
+#[test_case]
+fn private_sequences_are_swallowed(){
+    let mut parser = AnsiParser::new();
+    let printed = "\x1b[?25lok".chars().filter_map(|c| parser.advance(c)).count();
+    assert_eq!(printed, 2);
+}
@@ -0,0 +1,229 @@
+//! Stack unwinding and symbolized backtraces.
+//!
+//! The kernel is built with frame pointers (see the target file), so every frame starts with the caller's `rbp`
+//! followed by the return address. The symbol table lives in the `.ksyms` section, which `tools/runner.py` fills
+//! in from the kernel's ELF symbols before the boot image is made. A kernel that did not go through it has an
+//! empty table, and its backtraces show bare addresses.
+//!
+//! A frame is only read if the page tables map it, so a corrupt chain ends the backtrace instead of faulting.
+//! Until `memory::init` has run the page tables cannot be looked at, and backtraces only hold the first address.
+
+use core::arch::asm;
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::VirtAddr;
+use crate::memory;
+
+///Frames kept in a backtrace at most.
+pub const MAX_FRAMES: usize = 32;
+///Largest step between two frames the unwinder follows; a bigger one means the chain is broken.
+const MAX_FRAME_SIZE: u64 = 1024 * 1024;
+
+const SYMBOL_TABLE_SIZE: usize = 512 * 1024;
+const SYMBOL_TABLE_MAGIC: [u8; 8] = *b"KSYMTAB\0";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 16;
+
+const fn empty_symbol_table() -> [u8; SYMBOL_TABLE_SIZE]{
+    let mut table = [0; SYMBOL_TABLE_SIZE];
+    let mut i = 0;
+    while i < SYMBOL_TABLE_MAGIC.len(){
+        table[i] = SYMBOL_TABLE_MAGIC[i];
+        i += 1;
+    }
+    table
+}
+
+///Filled in after linking: the magic, the number of symbols and the offset of their names (both u32), then per
+///symbol its address (u64), size and name offset (both u32) sorted by address, then the names, each ending in NUL.
+#[used]
+#[link_section = ".ksyms"]
+static mut SYMBOL_TABLE: [u8; SYMBOL_TABLE_SIZE] = empty_symbol_table();
+
+///Set while a backtrace is taken, so a fault in the unwinder does not unwind again.
+static UNWINDING: AtomicBool = AtomicBool::new(false);
+
+fn symbol_table() -> &'static [u8]{
+    //hide where the pointer comes from, as the table is never written to after compilation as far as the
+    //compiler can tell, and reads from it could be folded to the empty table
+    let table = core::hint::black_box(ptr::addr_of!(SYMBOL_TABLE));
+    unsafe{&*table}
+}
+fn read_u32(bytes: &[u8], offset: usize) -> u32{
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+fn read_u64(bytes: &[u8], offset: usize) -> u64{
+    read_u32(bytes, offset) as u64 | (read_u32(bytes, offset + 4) as u64) << 32
+}
+
+///A function of the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol{
+    pub name: &'static str,
+    pub address: u64,
+    ///Size in bytes; 0 if unknown.
+    pub size: u64,
+}
+
+///The symbols and their names, if the table was filled in.
+fn symbols() -> Option<(&'static [u8], &'static [u8])>{
+    let table = symbol_table();
+    if table[..8] != SYMBOL_TABLE_MAGIC{
+        return None;
+    }
+    let count = read_u32(table, 8) as usize;
+    let names = read_u32(table, 12) as usize;
+    let entries = table.get(HEADER_SIZE..HEADER_SIZE + count * ENTRY_SIZE)?;
+    Some((entries, table.get(names..)?))
+}
+fn symbol_at(entries: &[u8], names: &'static [u8], index: usize) -> Symbol{
+    let entry = &entries[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE];
+    let name = names.get(read_u32(entry, 12) as usize..).unwrap_or(&[]);
+    let name = &name[..name.iter().position(|&byte| byte == 0).unwrap_or(name.len())];
+    Symbol{
+        name: core::str::from_utf8(name).unwrap_or("?"),
+        address: read_u64(entry, 0),
+        size: read_u32(entry, 8) as u64,
+    }
+}
+
+///Number of symbols embedded in the kernel.
+pub fn symbol_count() -> usize{
+    symbols().map_or(0, |(entries, _)| entries.len() / ENTRY_SIZE)
+}
+
+///The function containing an address.
+pub fn lookup(address: u64) -> Option<Symbol>{
+    let (entries, names) = symbols()?;
+    //number of symbols starting at or before the address
+    let (mut low, mut high) = (0, entries.len() / ENTRY_SIZE);
+    while low < high{
+        let middle = (low + high) / 2;
+        if read_u64(entries, middle * ENTRY_SIZE) <= address{
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    let symbol = symbol_at(entries, names, low.checked_sub(1)?);
+    if symbol.size != 0 && address - symbol.address >= symbol.size{
+        return None;
+    }
+    Some(symbol)
+}
+
+///Value of `rbp` in the calling function.
+#[inline(always)]
+pub fn frame_pointer() -> u64{
+    let rbp: u64;
+    unsafe{asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags))};
+    rbp
+}
+
+///Return addresses of the functions on the stack, innermost first.
+#[derive(Clone)]
+pub struct Backtrace{
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+    ///Whether the first address is where the code was interrupted rather than a return address.
+    interrupted: bool,
+}
+impl Backtrace{
+    ///Backtrace of the caller.
+    #[inline(never)]
+    pub fn capture() -> Backtrace{
+        let mut backtrace = Backtrace{frames: [0; MAX_FRAMES], len: 0, interrupted: false};
+        backtrace.walk(frame_pointer());
+        backtrace
+    }
+    ///Backtrace of the code an exception interrupted at `instruction_pointer`.
+    ///
+    ///Must be called from the exception handler itself, whose frame holds the interrupted code's `rbp`.
+    #[inline(always)]
+    pub fn interrupted(instruction_pointer: u64) -> Backtrace{
+        let handler_frame = frame_pointer();
+        let frame_pointer = if handler_frame != 0 && handler_frame.is_multiple_of(8) {unsafe{*(handler_frame as *const u64)}} else {0};
+        Backtrace::from_registers(instruction_pointer, frame_pointer)
+    }
+    ///Backtrace of stopped code from its `rip` and `rbp`.
+    pub fn from_registers(instruction_pointer: u64, frame_pointer: u64) -> Backtrace{
+        let mut backtrace = Backtrace{frames: [0; MAX_FRAMES], len: 0, interrupted: true};
+        backtrace.frames[0] = instruction_pointer;
+        backtrace.len = 1;
+        backtrace.walk(frame_pointer);
+        backtrace
+    }
+    ///Follow the frame pointers up from a frame, until the chain leaves the stack or the backtrace is full.
+    fn walk(&mut self, mut frame_pointer: u64){
+        if UNWINDING.swap(true, Ordering::Acquire){
+            return;
+        }
+        while self.len < MAX_FRAMES && frame_pointer != 0 && frame_pointer.is_multiple_of(8){
+            let (caller_frame, return_address) = match read_frame(frame_pointer){
+                Some((_, 0)) | None => break,
+                Some(frame) => frame,
+            };
+            self.frames[self.len] = return_address;
+            self.len += 1;
+            //the stack grows down, so callers' frames are above
+            if caller_frame <= frame_pointer || caller_frame - frame_pointer > MAX_FRAME_SIZE{
+                break;
+            }
+            frame_pointer = caller_frame;
+        }
+        UNWINDING.store(false, Ordering::Release);
+    }
+    pub fn frames(&self) -> &[u64]{
+        &self.frames[..self.len]
+    }
+}
+///The caller's frame pointer and the return address saved in a frame, if the page tables map it.
+fn read_frame(frame_pointer: u64) -> Option<(u64, u64)>{
+    //aligned words do not cross pages, so each is checked on its own
+    let read = |address: u64| -> Option<u64>{
+        let address = VirtAddr::try_new(address).ok()?;
+        memory::walk_page_tables(address, |_, _| {})?;
+        Some(unsafe{ptr::read_volatile(address.as_ptr::<u64>())})
+    };
+    Some((read(frame_pointer)?, read(frame_pointer.checked_add(8)?)?))
+}
+impl fmt::Display for Backtrace{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        writeln!(f, "Backtrace:")?;
+        for (i, &address) in self.frames().iter().enumerate(){
+            write!(f, "{:>3}: {:#018x}", i, address)?;
+            //a return address points after the call, which may be the start of the next function
+            let call_site = if i == 0 && self.interrupted {address} else {address - 1};
+            match lookup(call_site){
+                Some(symbol) => writeln!(f, " {}+{:#x}", symbol.name, address - symbol.address)?,
+                None => writeln!(f, " ???")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+//----------TEST CASES------------
+#[test_case]
+fn backtrace_reaches_caller(){
+    #[inline(never)]
+    fn capture_here() -> Backtrace{
+        Backtrace::capture()
+    }
+    let backtrace = capture_here();
+    assert!(backtrace.frames().len() >= 2);
+    //the first frame returns into `capture_here`, if the symbols were embedded
+    if let Some(symbol) = lookup(capture_here as *const () as u64){
+        assert_eq!(lookup(backtrace.frames()[0] - 1), Some(symbol));
+        assert!(symbol.name.ends_with("capture_here"));
+    }
+}
+#[test_case]
+fn broken_chain_ends_backtrace(){
+    //a frame pointer to an unmapped page, and one that is not canonical
+    for frame_pointer in [0xdead_bee8, 0x0000_8000_0000_0000]{
+        let backtrace = Backtrace::from_registers(0x1234, frame_pointer);
+        assert_eq!(backtrace.frames(), &[0x1234]);
+    }
+}
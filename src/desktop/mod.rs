@@ -0,0 +1,814 @@
+//! The window server of the Modesto desktop.
+//!
+//! While the desktop is shown, the server owns the framebuffer. It keeps overlapping windows in z-order and
+//! draws them, with their title bars and close boxes, and the menu bar into an off-screen canvas, of which
+//! only the changed parts are copied to the screen. Mouse and keyboard input is turned into `WindowEvent`s
+//! for the window in front; applications are tasks on the `Executor` awaiting their `WindowEvents`.
+
+pub mod notepad;
+
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::{self, Stream, StreamExt};
+use futures_util::task::AtomicWaker;
+use crate::sync::Mutex;
+use x86_64::instructions::interrupts;
+use crate::font::{self, PsfFont};
+use crate::framebuffer::{self, Framebuffer, FramebufferError, Rgb};
+use crate::graphics::{Canvas, Mode, Pattern, Point, Rect, Region};
+use crate::task::keyboard::{self, KeyEventStream, KeyboardEvent};
+use crate::task::mouse::{MouseButtons, MouseEvent, MouseEventStream};
+use crate::{fb_console, vga_buffer};
+
+///Bits per pixel of the graphics mode the desktop sets.
+const DEPTH: u8 = 32;
+pub const MENU_BAR_HEIGHT: i32 = 20;
+///Height of a window title bar, including the line below it.
+pub const TITLE_BAR_HEIGHT: i32 = 19;
+const CLOSE_BOX_SIZE: i32 = 11;
+const MENU_ITEM_HEIGHT: i32 = 18;
+///Space left and right of menu titles and items.
+const MENU_PADDING: i32 = 8;
+const DESKTOP_PATTERN: Pattern = Pattern::GRAY;
+///The desktop's own menu, always the first one; the server handles its items.
+const DESKTOP_MENU: Menu = Menu{title: "Desktop", items: &["Close Window", "Text Mode"]};
+///Events a window queues before further ones are dropped.
+const EVENT_QUEUE_SIZE: usize = 64;
+
+///The arrow pointer, one row per entry with the leftmost pixel in the top bit: black pixels, and the pixels
+///around them drawn in white. Its hot spot is at (1, 1).
+const ARROW: [u16; 16] = [
+    0x0000, 0x4000, 0x6000, 0x7000, 0x7800, 0x7c00, 0x7e00, 0x7f00,
+    0x7f80, 0x7c00, 0x6c00, 0x4600, 0x0600, 0x0300, 0x0300, 0x0000,
+];
+const ARROW_MASK: [u16; 16] = [
+    0xc000, 0xe000, 0xf000, 0xf800, 0xfc00, 0xfe00, 0xff00, 0xff80,
+    0xffc0, 0xffe0, 0xfe00, 0xef00, 0xcf00, 0x0780, 0x0780, 0x0380,
+];
+const ARROW_HOT_SPOT: Point = Point::new(1, 1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WindowId(u32);
+impl WindowId{
+    fn new() -> WindowId{
+        static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+        WindowId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+///Input and state changes sent to a window. Points are relative to the top left corner of its content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowEvent{
+    MouseDown(Point),
+    MouseUp(Point),
+    ///The mouse moved with the button held since a `MouseDown` in the content.
+    MouseDrag(Point),
+    ///A key event while the window is in front.
+    Key(KeyboardEvent),
+    ///The window came to the front and gets the keyboard input.
+    Activated,
+    Deactivated,
+    ///An item of a menu added with `add_menu` was chosen while the window was in front: the menu title
+    ///and the item.
+    Menu(&'static str, &'static str),
+    ///The window was closed by its close box, `WindowHandle::close` or the desktop stopping. It is the last event.
+    Closed,
+}
+
+///A menu of the menu bar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Menu{
+    pub title: &'static str,
+    pub items: &'static [&'static str],
+}
+
+///Queue and waker of a single `WindowEvents` stream.
+struct EventQueue{
+    queue: ArrayQueue<WindowEvent>,
+    waker: AtomicWaker,
+}
+impl EventQueue{
+    fn push(&self, event: WindowEvent){
+        if self.queue.push(event).is_err(){
+            log::warn!("window event queue full. dropping event.");
+        } else {
+            self.waker.wake();
+        }
+    }
+}
+
+///Stream of the events of one window. It ends after `WindowEvent::Closed`.
+pub struct WindowEvents{
+    queue: Arc<EventQueue>,
+    closed: bool,
+}
+impl Stream for WindowEvents{
+    type Item = WindowEvent;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<WindowEvent>>{
+        if self.closed{
+            return Poll::Ready(None);
+        }
+        let event = match self.queue.queue.pop(){
+            Ok(event) => event,
+            Err(crossbeam_queue::PopError) => {
+                self.queue.waker.register(cx.waker());
+                match self.queue.queue.pop(){
+                    Ok(event) => {
+                        self.queue.waker.take();
+                        event
+                    },
+                    Err(crossbeam_queue::PopError) => return Poll::Pending,
+                }
+            },
+        };
+        self.closed = event == WindowEvent::Closed;
+        Poll::Ready(Some(event))
+    }
+}
+
+///A window as kept by the server.
+struct Window{
+    id: WindowId,
+    title: String,
+    ///Outline of the window including its title bar, without the shadow.
+    frame: Rect,
+    content: Canvas,
+    events: Arc<EventQueue>,
+}
+impl Window{
+    fn title_bar(&self) -> Rect{
+        Rect::new(self.frame.left, self.frame.top, self.frame.right, self.frame.top + TITLE_BAR_HEIGHT)
+    }
+    fn close_box(&self) -> Rect{
+        let top = self.frame.top + (TITLE_BAR_HEIGHT - CLOSE_BOX_SIZE) / 2;
+        Rect::with_size(self.frame.left + 8, top, CLOSE_BOX_SIZE, CLOSE_BOX_SIZE)
+    }
+    ///Screen area of the content canvas.
+    fn content_rect(&self) -> Rect{
+        Rect::new(self.frame.left + 1, self.frame.top + TITLE_BAR_HEIGHT, self.frame.right - 1, self.frame.bottom - 1)
+    }
+    ///Everything the window covers on screen, including the shadow.
+    fn bounds(&self) -> Rect{
+        Rect::new(self.frame.left, self.frame.top, self.frame.right + 1, self.frame.bottom + 1)
+    }
+    fn to_content(&self, point: Point) -> Point{
+        let content = self.content_rect();
+        Point::new(point.x - content.left, point.y - content.top)
+    }
+}
+
+///What the mouse is doing since the button went down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tracking{
+    None,
+    ///Pressed in the content of a window, which gets the drag and release events.
+    Content(WindowId),
+    ///Dragging a window by its title bar; `outline` is where it would go, `grab` the pointer position relative
+    ///to the window's top left corner.
+    Drag{id: WindowId, grab: Point, outline: Rect},
+    ///Pressed in a close box; the window is closed if the button is released inside it.
+    CloseBox{id: WindowId, inside: bool},
+    ///A menu is pulled down, with the item under the pointer.
+    Menu{menu: usize, item: Option<usize>},
+}
+
+///The desktop: windows, menus and the pointer, drawn into an off-screen canvas of the screen.
+pub struct WindowServer{
+    screen: Canvas,
+    font: &'static PsfFont,
+    ///Windows from back to front; the last one is active and gets keyboard input.
+    windows: Vec<Window>,
+    menus: Vec<Menu>,
+    pointer: Point,
+    buttons: MouseButtons,
+    tracking: Tracking,
+    ///Parts of the screen to draw again on the next update.
+    dirty: Region,
+    ///Where the pointer was last drawn.
+    drawn_pointer: Option<Point>,
+    ///Set when "Text Mode" was chosen; the event loop stops the desktop.
+    stop_requested: bool,
+}
+impl WindowServer{
+    fn new(width: usize, height: usize) -> WindowServer{
+        let screen = Canvas::new(width, height);
+        let dirty = Region::from_rect(screen.bounds());
+        WindowServer{
+            pointer: Point::new(width as i32 / 2, height as i32 / 2),
+            screen,
+            font: &font::DEFAULT_FONT,
+            windows: Vec::new(),
+            menus: Vec::from([DESKTOP_MENU]),
+            buttons: MouseButtons::NONE,
+            tracking: Tracking::None,
+            dirty,
+            drawn_pointer: None,
+            stop_requested: false,
+        }
+    }
+    fn window(&self, id: WindowId) -> Option<&Window>{
+        self.windows.iter().find(|window| window.id == id)
+    }
+    fn front(&self) -> Option<&Window>{
+        self.windows.last()
+    }
+    ///Topmost window under a point.
+    fn window_at(&self, point: Point) -> Option<WindowId>{
+        self.windows.iter().rev().find(|window| window.frame.contains(point)).map(|window| window.id)
+    }
+    fn text_width(&self, text: &str) -> i32{
+        (text.chars().count() * self.font.width()) as i32
+    }
+    ///Tell the window in front it lost the keyboard input, and redraw its title bar.
+    fn deactivate_front(&mut self){
+        if let Some(front) = self.windows.last(){
+            front.events.push(WindowEvent::Deactivated);
+            self.dirty.union_rect(front.title_bar());
+        }
+    }
+    ///Open a window with a content area of the given size at (x, y), in front of the others.
+    fn open_window(&mut self, title: &str, x: i32, y: i32, width: usize, height: usize) -> (WindowHandle, WindowEvents){
+        let id = WindowId::new();
+        let events = Arc::new(EventQueue{queue: ArrayQueue::new(EVENT_QUEUE_SIZE), waker: AtomicWaker::new()});
+        let frame = Rect::with_size(x, y.max(MENU_BAR_HEIGHT), width as i32 + 2, height as i32 + TITLE_BAR_HEIGHT + 1);
+        let mut content = Canvas::new(width, height);
+        content.clear(Pattern::WHITE);
+        self.deactivate_front();
+        self.windows.push(Window{id, title: title.to_string(), frame, content, events: events.clone()});
+        self.dirty.union_rect(frame.inset(-1, -1));
+        events.push(WindowEvent::Activated);
+        (WindowHandle{id}, WindowEvents{queue: events, closed: false})
+    }
+    ///Bring a window to the front, making it the active one.
+    fn select_window(&mut self, id: WindowId){
+        let index = match self.windows.iter().position(|window| window.id == id){
+            Some(index) => index,
+            None => return,
+        };
+        if index == self.windows.len() - 1{
+            return;
+        }
+        self.deactivate_front();
+        let window = self.windows.remove(index);
+        window.events.push(WindowEvent::Activated);
+        self.dirty.union_rect(window.bounds());
+        self.windows.push(window);
+    }
+    fn close_window(&mut self, id: WindowId){
+        let index = match self.windows.iter().position(|window| window.id == id){
+            Some(index) => index,
+            None => return,
+        };
+        let window = self.windows.remove(index);
+        window.events.push(WindowEvent::Closed);
+        self.dirty.union_rect(window.bounds());
+        if index == self.windows.len(){
+            if let Some(front) = self.windows.last(){
+                front.events.push(WindowEvent::Activated);
+                self.dirty.union_rect(front.title_bar());
+            }
+        }
+    }
+    fn add_menu(&mut self, menu: Menu){
+        match self.menus.iter_mut().find(|existing| existing.title == menu.title){
+            Some(existing) => *existing = menu,
+            None => self.menus.push(menu),
+        }
+        self.dirty.union_rect(Rect::new(0, 0, self.screen.bounds().right, MENU_BAR_HEIGHT));
+    }
+    fn menu_title_rect(&self, index: usize) -> Rect{
+        let left = MENU_PADDING + self.menus[..index].iter()
+            .map(|menu| self.text_width(menu.title) + 2 * MENU_PADDING)
+            .sum::<i32>();
+        Rect::new(left, 0, left + self.text_width(self.menus[index].title) + 2 * MENU_PADDING, MENU_BAR_HEIGHT - 1)
+    }
+    ///The pulled down items of a menu, without the shadow.
+    fn menu_rect(&self, index: usize) -> Rect{
+        let menu = self.menus[index];
+        let width = menu.items.iter().map(|item| self.text_width(item)).max().unwrap_or(0) + 4 * MENU_PADDING;
+        let left = self.menu_title_rect(index).left;
+        Rect::with_size(left, MENU_BAR_HEIGHT - 1, width, menu.items.len() as i32 * MENU_ITEM_HEIGHT + 2)
+    }
+    fn menu_item_rect(&self, menu: usize, item: usize) -> Rect{
+        let rect = self.menu_rect(menu);
+        Rect::with_size(rect.left + 1, rect.top + 1 + item as i32 * MENU_ITEM_HEIGHT, rect.width() - 2, MENU_ITEM_HEIGHT)
+    }
+    fn menu_title_at(&self, point: Point) -> Option<usize>{
+        (0..self.menus.len()).find(|&index| self.menu_title_rect(index).contains(point))
+    }
+    fn menu_item_at(&self, menu: usize, point: Point) -> Option<usize>{
+        (0..self.menus[menu].items.len()).find(|&item| self.menu_item_rect(menu, item).contains(point))
+    }
+    fn dirty_menu(&mut self, menu: usize){
+        let (title, items) = (self.menu_title_rect(menu), self.menu_rect(menu));
+        self.dirty.union_rect(title);
+        self.dirty.union_rect(Rect::new(items.left, items.top, items.right + 1, items.bottom + 1));
+    }
+    ///Mark the outline drawn while dragging a window as changed.
+    fn dirty_outline(&mut self, outline: Rect){
+        self.dirty.union_rect(Rect::new(outline.left, outline.top, outline.right, outline.top + 1));
+        self.dirty.union_rect(Rect::new(outline.left, outline.bottom - 1, outline.right, outline.bottom));
+        self.dirty.union_rect(Rect::new(outline.left, outline.top, outline.left + 1, outline.bottom));
+        self.dirty.union_rect(Rect::new(outline.right - 1, outline.top, outline.right, outline.bottom));
+    }
+
+    ///Move the pointer and handle button changes.
+    fn handle_mouse(&mut self, event: MouseEvent){
+        let bounds = self.screen.bounds();
+        let moved = Point::new(
+            (self.pointer.x + event.dx as i32).clamp(0, bounds.right - 1),
+            (self.pointer.y + event.dy as i32).clamp(0, bounds.bottom - 1),
+        );
+        let was_down = self.buttons.contains(MouseButtons::LEFT);
+        let is_down = event.buttons.contains(MouseButtons::LEFT);
+        self.buttons = event.buttons;
+        let has_moved = moved != self.pointer;
+        self.pointer = moved;
+        if is_down && !was_down{
+            self.press(moved);
+        } else if was_down && !is_down{
+            if has_moved{
+                self.drag(moved);
+            }
+            self.release(moved);
+        } else if is_down && has_moved{
+            self.drag(moved);
+        }
+    }
+    fn press(&mut self, point: Point){
+        if point.y < MENU_BAR_HEIGHT{
+            if let Some(menu) = self.menu_title_at(point){
+                self.tracking = Tracking::Menu{menu, item: None};
+                self.dirty_menu(menu);
+            }
+            return;
+        }
+        let id = match self.window_at(point){
+            Some(id) => id,
+            None => return,
+        };
+        self.select_window(id);
+        let window = self.windows.last().expect("the selected window is in front");
+        let (close_box, title_bar, frame) = (window.close_box(), window.title_bar(), window.frame);
+        self.tracking = if close_box.contains(point){
+            self.dirty.union_rect(close_box);
+            Tracking::CloseBox{id, inside: true}
+        } else if title_bar.contains(point){
+            let grab = Point::new(point.x - frame.left, point.y - frame.top);
+            self.dirty_outline(frame);
+            Tracking::Drag{id, grab, outline: frame}
+        } else {
+            window.events.push(WindowEvent::MouseDown(window.to_content(point)));
+            Tracking::Content(id)
+        };
+    }
+    fn drag(&mut self, point: Point){
+        match self.tracking{
+            Tracking::None => {},
+            Tracking::Content(id) => {
+                if let Some(window) = self.window(id){
+                    window.events.push(WindowEvent::MouseDrag(window.to_content(point)));
+                }
+            },
+            Tracking::Drag{id, grab, outline} => {
+                //keep the title bar below the menu bar and a part of it on screen, as far as the screen is big enough
+                let bounds = self.screen.bounds();
+                let (min_left, min_top) = (32 - outline.width(), MENU_BAR_HEIGHT);
+                let left = (point.x - grab.x).clamp(min_left, min_left.max(bounds.right - 32));
+                let top = (point.y - grab.y).clamp(min_top, min_top.max(bounds.bottom - TITLE_BAR_HEIGHT));
+                let moved = Rect::with_size(left, top, outline.width(), outline.height());
+                if moved != outline{
+                    self.dirty_outline(outline);
+                    self.dirty_outline(moved);
+                    self.tracking = Tracking::Drag{id, grab, outline: moved};
+                }
+            },
+            Tracking::CloseBox{id, inside} => {
+                if let Some(close_box) = self.window(id).map(Window::close_box){
+                    if close_box.contains(point) != inside{
+                        self.dirty.union_rect(close_box);
+                        self.tracking = Tracking::CloseBox{id, inside: !inside};
+                    }
+                }
+            },
+            Tracking::Menu{menu, item} => {
+                if let Some(other) = self.menu_title_at(point).filter(|&other| other != menu){
+                    self.dirty_menu(menu);
+                    self.dirty_menu(other);
+                    self.tracking = Tracking::Menu{menu: other, item: None};
+                    return;
+                }
+                let hovered = self.menu_item_at(menu, point);
+                if hovered != item{
+                    for changed in [item, hovered].into_iter().flatten(){
+                        let rect = self.menu_item_rect(menu, changed);
+                        self.dirty.union_rect(rect);
+                    }
+                    self.tracking = Tracking::Menu{menu, item: hovered};
+                }
+            },
+        }
+    }
+    fn release(&mut self, point: Point){
+        match core::mem::replace(&mut self.tracking, Tracking::None){
+            Tracking::None => {},
+            Tracking::Content(id) => {
+                if let Some(window) = self.window(id){
+                    window.events.push(WindowEvent::MouseUp(window.to_content(point)));
+                }
+            },
+            Tracking::Drag{id, outline, ..} => {
+                self.dirty_outline(outline);
+                if let Some(window) = self.windows.iter_mut().find(|window| window.id == id){
+                    let old_bounds = window.bounds();
+                    window.frame = outline;
+                    let new_bounds = window.bounds();
+                    self.dirty.union_rect(old_bounds);
+                    self.dirty.union_rect(new_bounds);
+                }
+            },
+            Tracking::CloseBox{id, inside} => {
+                if inside{
+                    self.close_window(id);
+                }
+            },
+            Tracking::Menu{menu, item} => {
+                self.dirty_menu(menu);
+                if let Some(item) = item{
+                    self.choose_menu_item(menu, item);
+                }
+            },
+        }
+    }
+    fn choose_menu_item(&mut self, menu: usize, item: usize){
+        let (title, label) = (self.menus[menu].title, self.menus[menu].items[item]);
+        if menu == 0{
+            match label{
+                "Close Window" => {
+                    if let Some(id) = self.front().map(|window| window.id){
+                        self.close_window(id);
+                    }
+                },
+                "Text Mode" => self.stop_requested = true,
+                _ => {},
+            }
+        } else if let Some(front) = self.front(){
+            front.events.push(WindowEvent::Menu(title, label));
+        }
+    }
+    ///Send a key event to the window in front.
+    fn handle_key(&mut self, event: KeyboardEvent){
+        if let Some(front) = self.front(){
+            front.events.push(WindowEvent::Key(event));
+        }
+    }
+
+    fn draw_window(screen: &mut Canvas, font: &PsfFont, window: &Window, active: bool){
+        let frame = window.frame;
+        screen.fill_rect(Rect::new(frame.left + 1, frame.bottom, frame.right + 1, frame.bottom + 1), Pattern::BLACK, Mode::Copy);
+        screen.fill_rect(Rect::new(frame.right, frame.top + 1, frame.right + 1, frame.bottom), Pattern::BLACK, Mode::Copy);
+        screen.fill_rect(frame, Pattern::WHITE, Mode::Copy);
+        screen.frame_rect(frame, Pattern::BLACK, Mode::Copy);
+        let title_bar = window.title_bar();
+        screen.fill_rect(Rect::new(title_bar.left, title_bar.bottom - 1, title_bar.right, title_bar.bottom), Pattern::BLACK, Mode::Copy);
+        if active{
+            //the stripes of the active window, interrupted by the close box and the title
+            for y in (title_bar.top + 3..title_bar.bottom - 3).step_by(2){
+                screen.fill_rect(Rect::new(title_bar.left + 2, y, title_bar.right - 2, y + 1), Pattern::BLACK, Mode::Copy);
+            }
+            let close_box = window.close_box();
+            screen.fill_rect(close_box.inset(-1, 0), Pattern::WHITE, Mode::Copy);
+            screen.frame_rect(close_box, Pattern::BLACK, Mode::Copy);
+        }
+        let width = (window.title.chars().count() * font.width()) as i32;
+        let left = title_bar.left + (title_bar.width() - width) / 2;
+        let top = title_bar.top + (TITLE_BAR_HEIGHT - 1 - font.height() as i32) / 2;
+        screen.fill_rect(Rect::new(left - MENU_PADDING, title_bar.top + 1, left + width + MENU_PADDING, title_bar.bottom - 1), Pattern::WHITE, Mode::Copy);
+        screen.draw_text(Point::new(left, top), &window.title, font, Mode::Or);
+        let content = window.content_rect();
+        screen.copy_bits(&window.content, window.content.bounds(), Point::new(content.left, content.top), Mode::Copy);
+    }
+    fn draw_menu_bar(&mut self){
+        let bar = Rect::new(0, 0, self.screen.bounds().right, MENU_BAR_HEIGHT);
+        self.screen.fill_rect(bar, Pattern::WHITE, Mode::Copy);
+        self.screen.fill_rect(Rect::new(bar.left, bar.bottom - 1, bar.right, bar.bottom), Pattern::BLACK, Mode::Copy);
+        let top = (MENU_BAR_HEIGHT - 1 - self.font.height() as i32) / 2;
+        for index in 0..self.menus.len(){
+            let rect = self.menu_title_rect(index);
+            let title = self.menus[index].title;
+            self.screen.draw_text(Point::new(rect.left + MENU_PADDING, top), title, self.font, Mode::Or);
+        }
+        if let Tracking::Menu{menu, item} = self.tracking{
+            let title = self.menu_title_rect(menu);
+            self.screen.invert_rect(title);
+            let rect = self.menu_rect(menu);
+            self.screen.fill_rect(Rect::new(rect.left + 1, rect.top + 1, rect.right + 1, rect.bottom + 1), Pattern::BLACK, Mode::Copy);
+            self.screen.fill_rect(rect, Pattern::WHITE, Mode::Copy);
+            self.screen.frame_rect(rect, Pattern::BLACK, Mode::Copy);
+            let items = self.menus[menu].items;
+            for (index, label) in items.iter().enumerate(){
+                let item_rect = self.menu_item_rect(menu, index);
+                let text_top = item_rect.top + (MENU_ITEM_HEIGHT - self.font.height() as i32) / 2;
+                self.screen.draw_text(Point::new(item_rect.left + 2 * MENU_PADDING, text_top), label, self.font, Mode::Or);
+                if item == Some(index){
+                    self.screen.invert_rect(item_rect);
+                }
+            }
+        }
+    }
+    ///Draw the changed parts of the screen into the screen canvas and return them.
+    fn paint(&mut self) -> Region{
+        if let Some(drawn) = self.drawn_pointer{
+            if drawn != self.pointer{
+                self.dirty.union_rect(pointer_rect(drawn));
+            }
+        }
+        let dirty = core::mem::take(&mut self.dirty);
+        if dirty.is_empty(){
+            return dirty;
+        }
+        self.screen.set_clip(dirty.clone());
+        self.screen.fill_rect(self.screen.bounds(), DESKTOP_PATTERN, Mode::Copy);
+        let front = self.windows.len().saturating_sub(1);
+        for (index, window) in self.windows.iter().enumerate(){
+            WindowServer::draw_window(&mut self.screen, self.font, window, index == front);
+        }
+        match self.tracking{
+            Tracking::Drag{outline, ..} => self.screen.frame_rect(outline, Pattern::GRAY, Mode::Xor),
+            Tracking::CloseBox{id, inside: true} => {
+                if let Some(close_box) = self.window(id).map(Window::close_box){
+                    self.screen.invert_rect(close_box.inset(1, 1));
+                }
+            },
+            _ => {},
+        }
+        self.draw_menu_bar();
+        self.screen.reset_clip();
+        dirty
+    }
+    ///Bring the screen up to date: draw what changed, copy it to the framebuffer and draw the pointer.
+    fn update(&mut self){
+        let pointer_moved = self.drawn_pointer != Some(self.pointer);
+        let dirty = self.paint();
+        if dirty.is_empty() && !pointer_moved{
+            return;
+        }
+        interrupts::without_interrupts(||{
+            if let Some(framebuffer) = framebuffer::FRAMEBUFFER.lock().as_mut(){
+                for rect in dirty.rects(){
+                    self.screen.present(*rect, framebuffer, Rgb::BLACK, Rgb::WHITE);
+                }
+                draw_pointer(framebuffer, self.pointer);
+            }
+        });
+        self.drawn_pointer = Some(self.pointer);
+    }
+}
+
+///Screen area covered by the pointer when its hot spot is at `point`.
+fn pointer_rect(point: Point) -> Rect{
+    Rect::with_size(point.x - ARROW_HOT_SPOT.x, point.y - ARROW_HOT_SPOT.y, 16, 16)
+}
+fn draw_pointer(framebuffer: &mut Framebuffer, point: Point){
+    let rect = pointer_rect(point);
+    let (width, height) = (framebuffer.width(), framebuffer.height());
+    for row in 0..16{
+        for col in 0..16{
+            let (x, y) = (rect.left + col, rect.top + row);
+            if x < 0 || y < 0 || x as usize >= width || y as usize >= height{
+                continue;
+            }
+            let bit = 0x8000 >> col;
+            if ARROW[row as usize] & bit != 0{
+                framebuffer.put_pixel(x as usize, y as usize, Rgb::BLACK);
+            } else if ARROW_MASK[row as usize] & bit != 0{
+                framebuffer.put_pixel(x as usize, y as usize, Rgb::WHITE);
+            }
+        }
+    }
+}
+
+///The desktop, while it is shown.
+pub static DESKTOP: Mutex<Option<WindowServer>> = Mutex::new(None);
+///Number of times the desktop was started.
+static SESSION: AtomicU64 = AtomicU64::new(0);
+///Tasks waiting in `next_session`.
+static SESSION_WAKERS: Mutex<Vec<Waker>> = Mutex::new(Vec::new());
+
+///Run `f` on the window server if the desktop is shown, then bring the screen up to date.
+fn with_server<R>(f: impl FnOnce(&mut WindowServer) -> R) -> Option<R>{
+    let mut desktop = DESKTOP.lock();
+    let server = desktop.as_mut()?;
+    let result = f(server);
+    server.update();
+    Some(result)
+}
+
+///Show the desktop in a graphics mode of the given size. The text consoles keep running in the background but
+///get no keyboard input until the desktop is stopped.
+pub fn start(width: usize, height: usize) -> Result<(), FramebufferError>{
+    if is_running(){
+        return Ok(());
+    }
+    fb_console::disable();
+    framebuffer::set_mode(width, height, DEPTH)?;
+    interrupts::without_interrupts(||{
+        if let Some(framebuffer) = framebuffer::FRAMEBUFFER.lock().as_mut(){
+            //only the changed parts are copied to the screen, so both pages would have to be kept up to date
+            framebuffer.set_double_buffered(false);
+        }
+    });
+    let mut server = WindowServer::new(width, height);
+    server.update();
+    *DESKTOP.lock() = Some(server);
+    keyboard::suspend_console_input(true);
+    SESSION.fetch_add(1, Ordering::Relaxed);
+    for waker in SESSION_WAKERS.lock().drain(..){
+        waker.wake();
+    }
+    Ok(())
+}
+///Close every window and go back to the text consoles.
+pub fn stop(){
+    let server = match DESKTOP.lock().take(){
+        Some(server) => server,
+        None => return,
+    };
+    for window in &server.windows{
+        window.events.push(WindowEvent::Closed);
+    }
+    framebuffer::text_mode();
+    keyboard::suspend_console_input(false);
+    vga_buffer::redraw();
+}
+pub fn is_running() -> bool{
+    DESKTOP.lock().is_some()
+}
+
+///Wait until the desktop is shown in a session started after session number `previous`, and return that
+///session's number. Start with 0 to wait for the first one.
+pub fn next_session(previous: u64) -> impl Future<Output = u64>{
+    NextSession{previous}
+}
+struct NextSession{
+    previous: u64,
+}
+impl Future for NextSession{
+    type Output = u64;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<u64>{
+        let ready = |previous|{
+            let session = SESSION.load(Ordering::Relaxed);
+            Some(session).filter(|&session| session > previous && is_running())
+        };
+        if let Some(session) = ready(self.previous){
+            return Poll::Ready(session);
+        }
+        SESSION_WAKERS.lock().push(cx.waker().clone());
+        match ready(self.previous){
+            Some(session) => Poll::Ready(session),
+            None => Poll::Pending,
+        }
+    }
+}
+
+///A window opened with `open_window`, for drawing into it. The window's events come from its `WindowEvents`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowHandle{
+    id: WindowId,
+}
+impl WindowHandle{
+    pub fn id(&self) -> WindowId{
+        self.id
+    }
+    ///Draw into the content of the window and show the result. Returns `None` if the window is closed.
+    pub fn draw<R>(&self, f: impl FnOnce(&mut Canvas) -> R) -> Option<R>{
+        with_server(|server|{
+            let window = server.windows.iter_mut().find(|window| window.id == self.id)?;
+            let result = f(&mut window.content);
+            let content = window.content_rect();
+            server.dirty.union_rect(content);
+            Some(result)
+        }).flatten()
+    }
+    pub fn close(&self){
+        with_server(|server| server.close_window(self.id));
+    }
+}
+
+///Open a window in front of the others, with a content area of the given size and its top left corner at
+///(x, y). Returns `None` unless the desktop is shown.
+pub fn open_window(title: &str, x: i32, y: i32, width: usize, height: usize) -> Option<(WindowHandle, WindowEvents)>{
+    with_server(|server| server.open_window(title, x, y, width, height))
+}
+///Add a menu to the menu bar, or replace the one with the same title. Chosen items are sent to the window in front.
+pub fn add_menu(title: &'static str, items: &'static [&'static str]){
+    with_server(|server| server.add_menu(Menu{title, items}));
+}
+
+///Input to the window server.
+enum Input{
+    Mouse(MouseEvent),
+    Key(KeyboardEvent),
+}
+
+///Feed mouse and keyboard input to the window server while the desktop is shown. Input is dropped otherwise.
+pub async fn event_loop(){
+    let mouse = MouseEventStream::new().map(Input::Mouse);
+    let keys = KeyEventStream::new().map(Input::Key);
+    let mut input = stream::select(mouse, keys);
+    while let Some(input) = input.next().await{
+        let stop_requested = with_server(|server|{
+            match input{
+                Input::Mouse(event) => server.handle_mouse(event),
+                Input::Key(event) => server.handle_key(event),
+            }
+            server.stop_requested
+        });
+        if stop_requested == Some(true){
+            stop();
+        }
+    }
+}
+
+//----------TEST CASES------------
+#[cfg(test)]
+fn click(server: &mut WindowServer, from: Point, to: Point){
+    let buttons = MouseButtons::LEFT;
+    let dx = (from.x - server.pointer.x) as i16;
+    let dy = (from.y - server.pointer.y) as i16;
+    server.handle_mouse(MouseEvent{dx, dy, wheel: 0, buttons: MouseButtons::NONE});
+    server.handle_mouse(MouseEvent{dx: 0, dy: 0, wheel: 0, buttons});
+    let (dx, dy) = ((to.x - from.x) as i16, (to.y - from.y) as i16);
+    server.handle_mouse(MouseEvent{dx, dy, wheel: 0, buttons});
+    server.handle_mouse(MouseEvent{dx: 0, dy: 0, wheel: 0, buttons: MouseButtons::NONE});
+    server.paint();
+}
+#[test_case]
+fn windows_are_raised_dragged_and_closed(){
+    let mut server = WindowServer::new(320, 240);
+    let (back, mut back_events) = server.open_window("back", 10, 30, 100, 60);
+    let (front, mut front_events) = server.open_window("front", 50, 50, 100, 60);
+    server.paint();
+    let pending = |events: &mut WindowEvents|{
+        let mut received = Vec::new();
+        while let Ok(event) = events.queue.queue.pop(){
+            received.push(event);
+        }
+        received
+    };
+    assert_eq!(pending(&mut back_events), [WindowEvent::Activated, WindowEvent::Deactivated]);
+    assert_eq!(pending(&mut front_events), [WindowEvent::Activated]);
+    //a click into the content of the window behind raises it and is delivered relative to the content
+    click(&mut server, Point::new(20, 60), Point::new(20, 60));
+    assert_eq!(server.front().map(|window| window.id), Some(back.id()));
+    let down = WindowEvent::MouseDown(Point::new(9, 11));
+    assert_eq!(pending(&mut back_events), [WindowEvent::Activated, down, WindowEvent::MouseUp(Point::new(9, 11))]);
+    //dragging the title bar moves the window, drawn with its stripes at the new place
+    click(&mut server, Point::new(80, 35), Point::new(180, 135));
+    let window = server.window(back.id()).unwrap();
+    assert_eq!(window.frame.left, 110);
+    assert_eq!(window.frame.top, 130);
+    let stripe_y = window.frame.top + 3;
+    assert!(server.screen.pixel(Point::new(window.frame.left + 2, stripe_y)));
+    assert!(!server.screen.pixel(Point::new(window.frame.left + 2, stripe_y + 1)));
+    //the close box closes the window and activates the one below
+    let close_box = window.close_box();
+    let inside = Point::new(close_box.left + 2, close_box.top + 2);
+    click(&mut server, inside, inside);
+    assert!(server.window(back.id()).is_none());
+    assert_eq!(pending(&mut back_events).last(), Some(&WindowEvent::Closed));
+    assert_eq!(pending(&mut front_events).last(), Some(&WindowEvent::Activated));
+    assert_eq!(server.front().map(|window| window.id), Some(front.id()));
+}
+#[test_case]
+fn windows_can_be_dragged_on_tiny_screens(){
+    let mut server = WindowServer::new(640, 32);
+    let (window, _events) = server.open_window("tiny", 10, MENU_BAR_HEIGHT, 100, 60);
+    click(&mut server, Point::new(40, 25), Point::new(300, 31));
+    let window = server.window(window.id()).unwrap();
+    assert_eq!((window.frame.left, window.frame.top), (270, MENU_BAR_HEIGHT));
+}
+#[test_case]
+fn menu_items_go_to_front_window(){
+    let mut server = WindowServer::new(320, 240);
+    server.add_menu(Menu{title: "Edit", items: &["Undo", "Clear"]});
+    let (_, events) = server.open_window("note", 10, 30, 100, 60);
+    let title = server.menu_title_rect(1);
+    let item = server.menu_item_rect(1, 1);
+    click(&mut server, Point::new(title.left + 1, 5), Point::new(item.left + 1, item.top + 1));
+    let mut received = Vec::new();
+    while let Ok(event) = events.queue.queue.pop(){
+        received.push(event);
+    }
+    assert_eq!(received, [WindowEvent::Activated, WindowEvent::Menu("Edit", "Clear")]);
+    //the desktop menu is handled by the server
+    let item = server.menu_item_rect(0, 1);
+    click(&mut server, Point::new(10, 5), Point::new(item.left + 1, item.top + 1));
+    assert!(server.stop_requested);
+}
@@ -0,0 +1,63 @@
+//! A note pad window, the desktop's first application: typed text is wrapped at the window edge.
+
+use alloc::string::String;
+use futures_util::stream::StreamExt;
+use crate::font::{self, PsfFont};
+use crate::graphics::{Canvas, Mode, Pattern, Point, Rect};
+use super::{WindowEvent, add_menu, next_session, open_window};
+
+const WIDTH: usize = 320;
+const HEIGHT: usize = 200;
+const MARGIN: i32 = 4;
+
+///Draw the text wrapped into the canvas, with a caret after it.
+fn draw(canvas: &mut Canvas, text: &str, font: &PsfFont, caret: bool){
+    canvas.clear(Pattern::WHITE);
+    let (char_width, line_height) = (font.width() as i32, font.height() as i32);
+    let columns = ((canvas.bounds().width() - 2 * MARGIN) / char_width).max(1);
+    let mut position = Point::new(MARGIN, MARGIN);
+    for c in text.chars(){
+        if c == '\n' || position.x + char_width > MARGIN + columns * char_width{
+            position = Point::new(MARGIN, position.y + line_height);
+        }
+        if c != '\n'{
+            let mut buffer = [0; 4];
+            position.x = canvas.draw_text(position, c.encode_utf8(&mut buffer), font, Mode::Or);
+        }
+    }
+    if caret{
+        canvas.fill_rect(Rect::with_size(position.x, position.y, 1, line_height), Pattern::BLACK, Mode::Copy);
+    }
+}
+
+///Open a note pad every time the desktop is shown, until its window is closed.
+pub async fn run(){
+    let font = &*font::DEFAULT_FONT;
+    let mut session = 0;
+    loop{
+        session = next_session(session).await;
+        add_menu("Edit", &["Clear"]);
+        let (window, mut events) = match open_window("Note Pad", 40, 60, WIDTH, HEIGHT){
+            Some(window) => window,
+            None => continue,
+        };
+        let mut text = String::new();
+        let mut active = false;
+        while let Some(event) = events.next().await{
+            match event{
+                WindowEvent::Activated => active = true,
+                WindowEvent::Deactivated => active = false,
+                WindowEvent::Menu("Edit", "Clear") => text.clear(),
+                WindowEvent::Key(key) => match key.character(){
+                    Some('\x08') => {
+                        text.pop();
+                    },
+                    Some(c) if c == '\n' || !c.is_control() => text.push(c),
+                    _ => continue,
+                },
+                _ => continue,
+            }
+            window.draw(|canvas| draw(canvas, &text, font, active));
+        }
+    }
+}
@@ -0,0 +1,43 @@
+//! Output for panics and fatal exceptions.
+//!
+//! The normal print paths take the console, framebuffer and serial locks, which the code that panicked or
+//! faulted may be holding. `emergency_print!` takes none of them: it switches the screen back to text mode, writes
+//! straight to the VGA text buffer and to the log port. Whatever it interrupted is never resumed.
+
+use core::fmt;
+use x86_64::instructions::interrupts;
+use crate::{framebuffer, serial, vga_buffer};
+
+struct EmergencyWriter;
+impl fmt::Write for EmergencyWriter{
+    fn write_str(&mut self, s: &str) -> fmt::Result{
+        vga_buffer::emergency_write(s);
+        serial::emergency_write(s);
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments){
+    use core::fmt::Write;
+    interrupts::disable();
+    framebuffer::emergency_text_mode();
+    EmergencyWriter.write_fmt(args).ok();
+}
+
+/// Prints to the screen and the log port without taking any lock.
+#[macro_export]
+macro_rules! emergency_print {
+    ($($arg:tt)*) => {
+        $crate::emergency::_print(format_args!($($arg)*));
+    };
+}
+
+/// Prints to the screen and the log port without taking any lock, appending a newline.
+#[macro_export]
+macro_rules! emergency_println {
+    () => ($crate::emergency_print!("\n"));
+    ($fmt:expr) => ($crate::emergency_print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::emergency_print!(
+        concat!($fmt, "\n"), $($arg)*));
+}
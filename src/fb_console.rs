@@ -0,0 +1,250 @@
+//! Text console drawn with a bitmap font on the framebuffer, for when a graphics mode is set.
+//!
+//! While it is enabled, the active virtual console mirrors its screen here, so `print!` and the shells keep
+//! working in graphics mode. Output is still interpreted by `vga_buffer::BufferedWriter` alone; this console
+//! only holds the cells it shows and draws the changed ones. Cells hold whole characters, so everything the
+//! font has a glyph for is shown, not just code page 437.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::sync::Mutex;
+use x86_64::instructions::interrupts;
+use crate::ansi::TextAttributes;
+use crate::font::{self, PsfFont};
+use crate::framebuffer::{self, Framebuffer, Rgb};
+use crate::vga_buffer::{self, Color};
+
+///The 16 colour VGA palette, so colours look the same as in text mode.
+pub const PALETTE: [Rgb; 16] = [
+    Rgb::new(0x00, 0x00, 0x00), Rgb::new(0x00, 0x00, 0xaa), Rgb::new(0x00, 0xaa, 0x00), Rgb::new(0x00, 0xaa, 0xaa),
+    Rgb::new(0xaa, 0x00, 0x00), Rgb::new(0xaa, 0x00, 0xaa), Rgb::new(0xaa, 0x55, 0x00), Rgb::new(0xaa, 0xaa, 0xaa),
+    Rgb::new(0x55, 0x55, 0x55), Rgb::new(0x55, 0x55, 0xff), Rgb::new(0x55, 0xff, 0x55), Rgb::new(0x55, 0xff, 0xff),
+    Rgb::new(0xff, 0x55, 0x55), Rgb::new(0xff, 0x55, 0xff), Rgb::new(0xff, 0xff, 0x55), Rgb::new(0xff, 0xff, 0xff),
+];
+///Colours of the cells before anything is shown, the same as in text mode.
+const DEFAULT_ATTRIBUTES: TextAttributes = TextAttributes::new(Color::White as u8, Color::Black as u8);
+///Scanlines at the bottom of a cell covered by the cursor.
+const CURSOR_HEIGHT: usize = 2;
+
+///A character cell of the console.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell{
+    pub c: char,
+    pub attributes: TextAttributes,
+}
+impl Cell{
+    const fn blank(attributes: TextAttributes) -> Cell{
+        Cell{c: ' ', attributes}
+    }
+}
+
+///A grid of character cells, drawn to the framebuffer by `flush`.
+///
+///Cells are set by the screen positions of the text console, so they line up with the text screen.
+///Changes are only recorded until `flush`, so the grid works without a framebuffer.
+pub struct FramebufferConsole{
+    font: &'static PsfFont,
+    columns: usize,
+    rows: usize,
+    cells: Vec<Cell>,
+    ///Cells changed since the last flush.
+    dirty: Vec<bool>,
+    ///Rows scrolled since the last flush, as first row, number of rows and lines they moved up by.
+    scrolled: Option<(usize, usize, usize)>,
+    ///Cell the cursor is shown on, if it is shown.
+    cursor: Option<(usize, usize)>,
+    ///Cell the cursor was drawn on by the last flush, moved along when its row scrolls.
+    drawn_cursor: Option<(usize, usize)>,
+}
+impl FramebufferConsole{
+    ///A blank console of `columns` by `rows` cells, without a cursor.
+    pub fn new(columns: usize, rows: usize, font: &'static PsfFont) -> FramebufferConsole{
+        let (columns, rows) = (columns.max(1), rows.max(1));
+        FramebufferConsole{
+            font,
+            columns,
+            rows,
+            cells: vec![Cell::blank(DEFAULT_ATTRIBUTES); columns * rows],
+            dirty: vec![true; columns * rows],
+            scrolled: None,
+            cursor: None,
+            drawn_cursor: None,
+        }
+    }
+    pub fn columns(&self) -> usize{
+        self.columns
+    }
+    pub fn rows(&self) -> usize{
+        self.rows
+    }
+    pub fn cell(&self, row: usize, col: usize) -> Cell{
+        self.cells[row * self.columns + col]
+    }
+    ///Cell the cursor is shown on, if it is shown.
+    pub fn cursor(&self) -> Option<(usize, usize)>{
+        self.cursor
+    }
+    ///Set a cell. Cells off the console are ignored.
+    pub fn set_cell(&mut self, row: usize, col: usize, c: char, attributes: TextAttributes){
+        if row < self.rows && col < self.columns{
+            let index = row * self.columns + col;
+            self.cells[index] = Cell{c, attributes};
+            self.dirty[index] = true;
+        }
+    }
+    ///Show the cursor on a cell, or hide it. A cursor off the console is hidden.
+    pub fn set_cursor(&mut self, cursor: Option<(usize, usize)>){
+        self.cursor = cursor.filter(|&(row, col)| row < self.rows && col < self.columns);
+    }
+    ///Blank every cell.
+    pub fn clear(&mut self){
+        self.cells.iter_mut().for_each(|cell| *cell = Cell::blank(DEFAULT_ATTRIBUTES));
+        self.dirty.iter_mut().for_each(|dirty| *dirty = true);
+    }
+    ///Move the rows `top..top + height` up by one, blanking the bottom one with `attributes`, like
+    ///`vga_buffer::Writer::scroll_region`. The rows off the console are cut off.
+    pub fn scroll_up(&mut self, top: usize, height: usize, attributes: TextAttributes){
+        let height = height.min(self.rows.saturating_sub(top));
+        if height == 0{
+            return;
+        }
+        let (first, end) = (top * self.columns, (top + height) * self.columns);
+        self.cells.copy_within(first + self.columns..end, first);
+        self.dirty.copy_within(first + self.columns..end, first);
+        self.cells[end - self.columns..end].iter_mut().for_each(|cell| *cell = Cell::blank(attributes));
+        self.dirty[end - self.columns..end].iter_mut().for_each(|dirty| *dirty = true);
+        self.drawn_cursor = match self.drawn_cursor{
+            Some((row, col)) if (top..top + height).contains(&row) => {
+                Some((row, col)).filter(|_| row > top).map(|(row, col)| (row - 1, col))
+            },
+            drawn_cursor => drawn_cursor,
+        };
+        self.scrolled = match self.scrolled{
+            None => Some((top, height, 1)),
+            Some((scrolled_top, scrolled_height, lines)) if (scrolled_top, scrolled_height) == (top, height) => {
+                Some((top, height, lines + 1))
+            },
+            //the pixels cannot follow scrolling of different rows, so draw all of them again
+            Some(_) => {
+                self.dirty.iter_mut().for_each(|dirty| *dirty = true);
+                None
+            },
+        };
+    }
+    ///Draw the cells changed since the last flush, and the cursor.
+    pub fn flush(&mut self, framebuffer: &mut Framebuffer){
+        let (width, height) = (self.font.width(), self.font.height());
+        if let Some((row, col)) = self.drawn_cursor.take(){
+            self.dirty[row * self.columns + col] = true;
+        }
+        if let Some((top, rows, lines)) = self.scrolled.take(){
+            framebuffer.scroll_up(top * height, rows * height, lines.min(rows) * height, Rgb::BLACK);
+        }
+        for index in 0..self.cells.len(){
+            if self.dirty[index]{
+                let (row, col) = (index / self.columns, index % self.columns);
+                let cell = self.cells[index];
+                let foreground = PALETTE[cell.attributes.effective_foreground() as usize & 0xf];
+                let background = PALETTE[cell.attributes.background as usize & 0xf];
+                framebuffer.draw_char(col * width, row * height, cell.c, self.font, foreground, background);
+                self.dirty[index] = false;
+            }
+        }
+        if let Some((row, col)) = self.cursor{
+            let foreground = PALETTE[self.cell(row, col).attributes.effective_foreground() as usize & 0xf];
+            let cursor_height = CURSOR_HEIGHT.min(height);
+            framebuffer.fill_rect(col * width, (row + 1) * height - cursor_height, width, cursor_height, foreground);
+            self.drawn_cursor = Some((row, col));
+        }
+    }
+}
+
+///The framebuffer console, while it is enabled.
+pub static FB_CONSOLE: Mutex<Option<FramebufferConsole>> = Mutex::new(None);
+
+///Show the virtual consoles on the framebuffer, filling the screen with cells of the default font.
+///Does nothing unless a graphics mode is set with `framebuffer::set_mode`.
+///
+///Like everything here, this takes `FB_CONSOLE` before `framebuffer::FRAMEBUFFER`.
+pub fn enable(){
+    let enabled = interrupts::without_interrupts(||{
+        let mut console = FB_CONSOLE.lock();
+        let mut framebuffer = framebuffer::FRAMEBUFFER.lock();
+        let framebuffer = match framebuffer.as_mut(){
+            Some(framebuffer) => framebuffer,
+            None => return false,
+        };
+        framebuffer.set_double_buffered(false);
+        framebuffer.clear(Rgb::BLACK);
+        let font = &*font::DEFAULT_FONT;
+        let (columns, rows) = (framebuffer.width() / font.width(), framebuffer.height() / font.height());
+        *console = Some(FramebufferConsole::new(columns, rows, font));
+        true
+    });
+    if enabled{
+        vga_buffer::redraw();
+    }
+}
+///Stop drawing the consoles on the framebuffer, e.g. before going back to text mode.
+pub fn disable(){
+    interrupts::without_interrupts(||{
+        if FB_CONSOLE.lock().take().is_some(){
+            if let Some(framebuffer) = framebuffer::FRAMEBUFFER.lock().as_mut(){
+                framebuffer.set_double_buffered(true);
+            }
+        }
+    })
+}
+pub fn is_enabled() -> bool{
+    interrupts::without_interrupts(|| FB_CONSOLE.lock().is_some())
+}
+///Run `f` on the framebuffer console if it is enabled. What it changes is drawn by the next `flush`.
+pub fn with_console<R>(f: impl FnOnce(&mut FramebufferConsole) -> R) -> Option<R>{
+    interrupts::without_interrupts(|| FB_CONSOLE.lock().as_mut().map(f))
+}
+///Draw what changed on the framebuffer console, if it is enabled.
+pub fn flush(){
+    interrupts::without_interrupts(||{
+        if let Some(console) = FB_CONSOLE.lock().as_mut(){
+            if let Some(framebuffer) = framebuffer::FRAMEBUFFER.lock().as_mut(){
+                console.flush(framebuffer);
+            }
+        }
+    })
+}
+
+//----------TEST CASES------------
+#[test_case]
+fn cells_scroll_within_rows(){
+    let mut console = FramebufferConsole::new(3, 4, &font::DEFAULT_FONT);
+    let red = TextAttributes::new(Color::Red as u8, Color::Black as u8);
+    for row in 0..4{
+        console.set_cell(row, 0, char::from(b'a' + row as u8), red);
+    }
+    console.set_cell(4, 0, 'x', red);
+    console.scroll_up(1, 2, DEFAULT_ATTRIBUTES);
+    assert_eq!(console.cell(0, 0).c, 'a');
+    assert_eq!(console.cell(1, 0), Cell{c: 'c', attributes: red});
+    assert_eq!(console.cell(2, 0), Cell::blank(DEFAULT_ATTRIBUTES));
+    assert_eq!(console.cell(3, 0).c, 'd');
+    console.set_cursor(Some((3, 3)));
+    assert_eq!(console.cursor(), None);
+}
+#[test_case]
+fn console_output_keeps_unicode(){
+    use core::fmt::Write;
+    interrupts::without_interrupts(||{
+        *FB_CONSOLE.lock() = Some(FramebufferConsole::new(vga_buffer::BUFFER_WIDTH, vga_buffer::BUFFER_HEIGHT,
+                                                          &font::DEFAULT_FONT));
+        let mut writer = vga_buffer::WRITER.lock();
+        write!(writer, "\nЖ\x1b[31my").unwrap();
+        let (row, col) = writer.cursor_position();
+        drop(writer);
+        let console = FB_CONSOLE.lock().take().unwrap();
+        assert_eq!(console.cell(row, col - 2).c, 'Ж');
+        assert_eq!(console.cell(row, col - 1).c, 'y');
+        assert_eq!(console.cell(row, col - 1).attributes.foreground, Color::Red as u8);
+        assert_eq!(console.cursor(), Some((row, col)));
+        vga_buffer::WRITER.lock().write_str("\x1b[0m\n").unwrap();
+    });
+}
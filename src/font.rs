@@ -0,0 +1,219 @@
+//! PC Screen Font (PSF 1 and 2) bitmap fonts, as used by the Linux console.
+//!
+//! Fonts are described in fs/fonts/README.txt. Glyph rows are stored MSB first, each padded to whole bytes.
+
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_HEADER_SIZE: usize = 4;
+const PSF1_MODE_512: u8 = 0x01;
+const PSF1_MODE_HAS_TABLE: u8 = 0x02 | 0x04;
+const PSF1_SEPARATOR: u16 = 0xffff;
+const PSF1_SEQUENCE_START: u16 = 0xfffe;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+const PSF2_HEADER_SIZE: usize = 32;
+const PSF2_HAS_UNICODE_TABLE: u32 = 1;
+const PSF2_SEPARATOR: u8 = 0xff;
+const PSF2_SEQUENCE_START: u8 = 0xfe;
+
+///The font built into the kernel.
+pub static DEFAULT_FONT_DATA: &[u8] = include_bytes!("../fs/fonts/default8x16.psf");
+
+lazy_static!{
+    ///The built-in font, parsed on first use (needs the heap).
+    pub static ref DEFAULT_FONT: PsfFont = PsfFont::parse(DEFAULT_FONT_DATA).expect("built-in font is invalid");
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontError{
+    ///Neither a PSF 1 nor a PSF 2 header.
+    BadMagic,
+    ///The data ends before the glyphs or the Unicode table do.
+    Truncated,
+}
+
+///A parsed PSF font.
+#[derive(Debug)]
+pub struct PsfFont{
+    data: &'static [u8],
+    glyphs_start: usize,
+    glyph_count: usize,
+    bytes_per_glyph: usize,
+    width: usize,
+    height: usize,
+    ///Character to glyph index, sorted by character. Empty for fonts without a Unicode table.
+    unicode: Vec<(char, usize)>,
+}
+///Offset of the end of `glyph_count` glyphs of `bytes_per_glyph` bytes starting at `glyphs_start`, unless a
+///header claims more than fits in memory.
+fn glyphs_end(glyphs_start: usize, glyph_count: usize, bytes_per_glyph: usize) -> Result<usize, FontError>{
+    glyph_count.checked_mul(bytes_per_glyph)
+        .and_then(|size| size.checked_add(glyphs_start))
+        .ok_or(FontError::Truncated)
+}
+impl PsfFont{
+    pub fn parse(data: &'static [u8]) -> Result<PsfFont, FontError>{
+        let mut font = if data.starts_with(&PSF2_MAGIC){
+            PsfFont::parse_psf2_header(data)?
+        } else if data.starts_with(&PSF1_MAGIC){
+            PsfFont::parse_psf1_header(data)?
+        } else {
+            return Err(FontError::BadMagic);
+        };
+        let glyphs_end = glyphs_end(font.glyphs_start, font.glyph_count, font.bytes_per_glyph)?;
+        if data.len() < glyphs_end{
+            return Err(FontError::Truncated);
+        }
+        font.unicode.sort_unstable();
+        font.unicode.dedup_by_key(|(c, _)| *c);
+        Ok(font)
+    }
+    fn parse_psf1_header(data: &'static [u8]) -> Result<PsfFont, FontError>{
+        if data.len() < PSF1_HEADER_SIZE{
+            return Err(FontError::Truncated);
+        }
+        let (mode, height) = (data[2], data[3] as usize);
+        let glyph_count = if mode & PSF1_MODE_512 != 0 {512} else {256};
+        let mut font = PsfFont{
+            data,
+            glyphs_start: PSF1_HEADER_SIZE,
+            glyph_count,
+            bytes_per_glyph: height,
+            width: 8,
+            height,
+            unicode: Vec::new(),
+        };
+        if mode & PSF1_MODE_HAS_TABLE != 0{
+            let table = data.get(glyphs_end(PSF1_HEADER_SIZE, glyph_count, height)?..).ok_or(FontError::Truncated)?;
+            let mut entries = table.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]]));
+            for glyph in 0..glyph_count{
+                loop{
+                    match entries.next(){
+                        Some(PSF1_SEPARATOR) => break,
+                        //only single characters are used; combining sequences are skipped
+                        Some(PSF1_SEQUENCE_START) => {
+                            while !matches!(entries.next(), Some(PSF1_SEPARATOR) | None){}
+                            break;
+                        },
+                        Some(code) => {
+                            if let Some(c) = char::from_u32(code as u32){
+                                font.unicode.push((c, glyph));
+                            }
+                        },
+                        None => return Err(FontError::Truncated),
+                    }
+                }
+            }
+        }
+        Ok(font)
+    }
+    fn parse_psf2_header(data: &'static [u8]) -> Result<PsfFont, FontError>{
+        let field = |index: usize| -> Result<u32, FontError>{
+            let bytes = data.get(index * 4..index * 4 + 4).ok_or(FontError::Truncated)?;
+            Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        };
+        let header_size = (field(2)? as usize).max(PSF2_HEADER_SIZE);
+        let flags = field(3)?;
+        let glyph_count = field(4)? as usize;
+        let bytes_per_glyph = field(5)? as usize;
+        let mut font = PsfFont{
+            data,
+            glyphs_start: header_size,
+            glyph_count,
+            bytes_per_glyph,
+            height: field(6)? as usize,
+            width: field(7)? as usize,
+            unicode: Vec::new(),
+        };
+        if flags & PSF2_HAS_UNICODE_TABLE != 0{
+            let table_start = glyphs_end(header_size, glyph_count, bytes_per_glyph)?;
+            let mut table = data.get(table_start..).ok_or(FontError::Truncated)?;
+            for glyph in 0..glyph_count{
+                let end = table.iter().position(|&byte| byte == PSF2_SEPARATOR).ok_or(FontError::Truncated)?;
+                //only single characters are used; combining sequences come after the start marker
+                let singles = &table[..end];
+                let singles = match singles.iter().position(|&byte| byte == PSF2_SEQUENCE_START){
+                    Some(start) => &singles[..start],
+                    None => singles,
+                };
+                if let Ok(text) = core::str::from_utf8(singles){
+                    font.unicode.extend(text.chars().map(|c| (c, glyph)));
+                }
+                table = &table[end + 1..];
+            }
+        }
+        Ok(font)
+    }
+    pub fn width(&self) -> usize{
+        self.width
+    }
+    pub fn height(&self) -> usize{
+        self.height
+    }
+    ///Bytes per glyph row.
+    pub fn row_bytes(&self) -> usize{
+        self.width.div_ceil(8)
+    }
+    ///Glyph index of a character, if the font has it. Fonts without a Unicode table are indexed by code point.
+    pub fn glyph_index(&self, c: char) -> Option<usize>{
+        if self.unicode.is_empty(){
+            return Some(c as usize).filter(|&index| index < self.glyph_count);
+        }
+        self.unicode.binary_search_by_key(&c, |&(c, _)| c)
+            .ok()
+            .map(|position| self.unicode[position].1)
+    }
+    ///Bitmap of the glyph for a character, falling back to U+FFFD, '?' and finally glyph 0.
+    pub fn glyph(&self, c: char) -> &'static [u8]{
+        let index = self.glyph_index(c)
+            .or_else(|| self.glyph_index(char::REPLACEMENT_CHARACTER))
+            .or_else(|| self.glyph_index('?'))
+            .unwrap_or(0);
+        let start = self.glyphs_start + index * self.bytes_per_glyph;
+        &self.data[start..start + self.bytes_per_glyph]
+    }
+    ///Whether the pixel at (x, y) of a glyph bitmap is set.
+    pub fn pixel(&self, glyph: &[u8], x: usize, y: usize) -> bool{
+        glyph[y * self.row_bytes() + x / 8] & (0x80 >> (x % 8)) != 0
+    }
+}
+
+//----------TEST CASES------------
+#[test_case]
+fn default_font_maps_unicode(){
+    let font = &*DEFAULT_FONT;
+    assert_eq!((font.width(), font.height()), (8, 16));
+    //code page 437 order, plus Unicode beyond it
+    assert_eq!(font.glyph_index('A'), Some(0x41));
+    assert_eq!(font.glyph_index('╬'), Some(0xce));
+    assert!(font.glyph_index('Ж').is_some());
+    //missing characters fall back to the replacement glyph
+    assert_eq!(font.glyph('\u{4e00}'), font.glyph(char::REPLACEMENT_CHARACTER));
+    let full_block = font.glyph('█');
+    assert!(font.pixel(full_block, 0, 0) && font.pixel(full_block, 7, 15));
+}
+#[test_case]
+fn psf1_without_table_is_indexed_by_code(){
+    static FONT: [u8; 4 + 256 * 2] = {
+        let mut data = [0; 4 + 256 * 2];
+        data[0] = 0x36;
+        data[1] = 0x04;
+        data[3] = 2;
+        data[4 + 0x41 * 2] = 0x80;
+        data
+    };
+    let font = PsfFont::parse(&FONT).unwrap();
+    assert_eq!((font.width(), font.height()), (8, 2));
+    assert!(font.pixel(font.glyph('A'), 0, 0));
+    assert_eq!(PsfFont::parse(&FONT[..100]).unwrap_err(), FontError::Truncated);
+}
+#[test_case]
+fn psf2_with_huge_glyph_count_is_truncated(){
+    //a PSF 2 header claiming 2^32 - 1 glyphs of 2^32 - 1 bytes, and no glyphs
+    static FONT: [u8; 32] = [0x72, 0xb5, 0x4a, 0x86, 0, 0, 0, 0, 32, 0, 0, 0, 0, 0, 0, 0,
+                             0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 16, 0, 0, 0, 8, 0, 0, 0];
+    assert_eq!(glyphs_end(32, usize::MAX, 2), Err(FontError::Truncated));
+    assert_eq!(PsfFont::parse(&FONT).unwrap_err(), FontError::Truncated);
+}
@@ -0,0 +1,346 @@
+//! Linear framebuffer graphics on QEMU's Bochs display adapter (BGA, the `-vga std` device).
+//!
+//! The adapter is programmed through the VBE DISPI index and data ports. Its memory is found through
+//! PCI BAR 0 and mapped at `FRAMEBUFFER_START`. The virtual screen holds two pages; drawing goes to the
+//! hidden one and `Framebuffer::flip` shows it, unless double buffering is switched off.
+
+use x86_64::instructions::port::Port;
+use x86_64::instructions::interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::sync::Mutex;
+use crate::{memory, pci};
+use crate::font::PsfFont;
+
+const DISPI_INDEX_PORT: u16 = 0x1ce;
+const DISPI_DATA_PORT: u16 = 0x1cf;
+
+const DISPI_INDEX_ID: u16 = 0;
+const DISPI_INDEX_XRES: u16 = 1;
+const DISPI_INDEX_YRES: u16 = 2;
+const DISPI_INDEX_BPP: u16 = 3;
+const DISPI_INDEX_ENABLE: u16 = 4;
+const DISPI_INDEX_VIRT_WIDTH: u16 = 6;
+const DISPI_INDEX_VIRT_HEIGHT: u16 = 7;
+const DISPI_INDEX_Y_OFFSET: u16 = 9;
+
+///Oldest interface version with a virtual screen and display offsets.
+const DISPI_ID_VIRTUAL_SCREEN: u16 = 0xb0c2;
+///Newest interface version this driver knows.
+const DISPI_ID_LATEST: u16 = 0xb0c5;
+const DISPI_ENABLED: u16 = 0x01;
+const DISPI_LFB_ENABLED: u16 = 0x40;
+const DISPI_NO_CLEAR_MEMORY: u16 = 0x80;
+
+const BGA_VENDOR_ID: u16 = 0x1234;
+const BGA_DEVICE_ID: u16 = 0x1111;
+
+///Virtual address the video memory is mapped to.
+pub const FRAMEBUFFER_START: u64 = 0x5555_0000_0000;
+///Text mode keeps its characters and font in the first 256 KiB of video memory. The pages start after it,
+///so switching back to text mode shows the console again.
+const TEXT_MODE_MEMORY: usize = 256 * 1024;
+
+///A 24 bit colour.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rgb{
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+impl Rgb{
+    pub const BLACK: Rgb = Rgb::new(0, 0, 0);
+    pub const WHITE: Rgb = Rgb::new(0xff, 0xff, 0xff);
+    pub const fn new(r: u8, g: u8, b: u8) -> Rgb{
+        Rgb{r, g, b}
+    }
+    ///The colour as a pixel value of the given depth, e.g. 0x00rrggbb for 24 and 32 bits.
+    pub fn encode(self, depth: u8) -> u32{
+        let (r, g, b) = (self.r as u32, self.g as u32, self.b as u32);
+        match depth{
+            15 => (r >> 3) << 10 | (g >> 3) << 5 | b >> 3,
+            16 => (r >> 3) << 11 | (g >> 2) << 5 | b >> 3,
+            _ => r << 16 | g << 8 | b,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramebufferError{
+    ///No Bochs display adapter answered on the DISPI ports.
+    NotPresent,
+    ///The adapter is not on the PCI bus, so the address of its memory is unknown.
+    NoPciDevice,
+    ///Only 15, 16, 24 and 32 bits per pixel are supported.
+    UnsupportedDepth(u8),
+    ///The adapter did not accept the mode, e.g. because two pages do not fit into its memory.
+    ModeRejected,
+    ///`crate::init` has not set up the memory manager yet.
+    NoMemoryManager,
+    ///The video memory could not be mapped.
+    MapFailed,
+}
+
+///The DISPI register interface of the adapter.
+struct Dispi{
+    index: Port<u16>,
+    data: Port<u16>,
+}
+impl Dispi{
+    const fn new() -> Dispi{
+        Dispi{index: Port::new(DISPI_INDEX_PORT), data: Port::new(DISPI_DATA_PORT)}
+    }
+    fn read(&mut self, register: u16) -> u16{
+        unsafe{
+            self.index.write(register);
+            self.data.read()
+        }
+    }
+    fn write(&mut self, register: u16, value: u16){
+        unsafe{
+            self.index.write(register);
+            self.data.write(value);
+        }
+    }
+}
+
+///A graphics mode set on the adapter, with two pages of video memory to draw to.
+pub struct Framebuffer{
+    dispi: Dispi,
+    base: VirtAddr,
+    width: usize,
+    height: usize,
+    depth: u8,
+    ///Bytes per line.
+    pitch: usize,
+    ///Line of the virtual screen the first page starts on.
+    first_line: usize,
+    ///Page that is not shown.
+    back_page: usize,
+    ///Whether drawing goes to the back page rather than the page shown.
+    double_buffered: bool,
+}
+
+///The framebuffer, while a graphics mode is set.
+pub static FRAMEBUFFER: Mutex<Option<Framebuffer>> = Mutex::new(None);
+
+impl Framebuffer{
+    pub fn width(&self) -> usize{
+        self.width
+    }
+    pub fn height(&self) -> usize{
+        self.height
+    }
+    ///Bits per pixel.
+    pub fn depth(&self) -> u8{
+        self.depth
+    }
+    fn bytes_per_pixel(&self) -> usize{
+        (self.depth as usize).div_ceil(8)
+    }
+    ///Switch double buffering on or off. While it is off, everything documented as drawing to the back page
+    ///draws to the page shown instead, so output appears as it is drawn, as for the framebuffer console.
+    pub fn set_double_buffered(&mut self, double_buffered: bool){
+        self.double_buffered = double_buffered;
+    }
+    ///Address of a pixel on the page drawn to.
+    fn pixel_ptr(&self, x: usize, y: usize) -> *mut u8{
+        let page = if self.double_buffered {self.back_page} else {self.back_page ^ 1};
+        let line = self.first_line + page * self.height + y;
+        (self.base + (line * self.pitch + x * self.bytes_per_pixel()) as u64).as_mut_ptr()
+    }
+    fn write_pixel(&mut self, x: usize, y: usize, value: u32){
+        let pixel = self.pixel_ptr(x, y);
+        unsafe{
+            match self.bytes_per_pixel(){
+                2 => (pixel as *mut u16).write_volatile(value as u16),
+                3 => {
+                    pixel.write_volatile(value as u8);
+                    pixel.add(1).write_volatile((value >> 8) as u8);
+                    pixel.add(2).write_volatile((value >> 16) as u8);
+                },
+                _ => (pixel as *mut u32).write_volatile(value),
+            }
+        }
+    }
+    ///Set a pixel on the back page. Pixels off screen are ignored.
+    pub fn put_pixel(&mut self, x: usize, y: usize, color: Rgb){
+        if x < self.width && y < self.height{
+            self.write_pixel(x, y, color.encode(self.depth));
+        }
+    }
+    ///Fill a rectangle of the back page, clipped to the screen.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Rgb){
+        let value = color.encode(self.depth);
+        let x_end = (x.saturating_add(width)).min(self.width);
+        let y_end = (y.saturating_add(height)).min(self.height);
+        for row in y..y_end{
+            for col in x..x_end{
+                self.write_pixel(col, row, value);
+            }
+        }
+    }
+    ///Fill the whole back page.
+    pub fn clear(&mut self, color: Rgb){
+        self.fill_rect(0, 0, self.width, self.height, color);
+    }
+    ///Copy an image of `width` by `height` pixels, stored row by row, to the back page at (x, y).
+    ///The parts off screen are cut off.
+    pub fn blit(&mut self, x: usize, y: usize, width: usize, height: usize, pixels: &[Rgb]){
+        let depth = self.depth;
+        for row in 0..height.min(self.height.saturating_sub(y)){
+            for col in 0..width.min(self.width.saturating_sub(x)){
+                if let Some(color) = pixels.get(row * width + col){
+                    self.write_pixel(x + col, y + row, color.encode(depth));
+                }
+            }
+        }
+    }
+    ///Draw a character of `font` with its top left corner at (x, y) of the back page.
+    pub fn draw_char(&mut self, x: usize, y: usize, c: char, font: &PsfFont, foreground: Rgb, background: Rgb){
+        let glyph = font.glyph(c);
+        let (foreground, background) = (foreground.encode(self.depth), background.encode(self.depth));
+        for row in 0..font.height().min(self.height.saturating_sub(y)){
+            for col in 0..font.width().min(self.width.saturating_sub(x)){
+                let value = if font.pixel(glyph, col, row) {foreground} else {background};
+                self.write_pixel(x + col, y + row, value);
+            }
+        }
+    }
+    ///Move the pixel rows `y..y + height` of the back page up by `lines`, filling the rows uncovered at the
+    ///bottom of them with `color`. The rows off screen are cut off.
+    pub fn scroll_up(&mut self, y: usize, height: usize, lines: usize, color: Rgb){
+        let height = height.min(self.height.saturating_sub(y));
+        let lines = lines.min(height);
+        if height == 0{
+            return;
+        }
+        let top = self.pixel_ptr(0, y);
+        unsafe{
+            core::ptr::copy(top.add(lines * self.pitch), top, (height - lines) * self.pitch);
+        }
+        self.fill_rect(0, y + height - lines, self.width, lines, color);
+    }
+    ///Show the back page. The page shown until now becomes the back page; it still holds the frame
+    ///before, so the next frame has to be drawn completely.
+    pub fn flip(&mut self){
+        let shown_line = self.first_line + self.back_page * self.height;
+        self.dispi.write(DISPI_INDEX_Y_OFFSET, shown_line as u16);
+        self.back_page ^= 1;
+    }
+}
+
+///Switch to a graphics mode with the given resolution and bits per pixel, and map the video memory.
+pub fn set_mode(width: usize, height: usize, depth: u8) -> Result<(), FramebufferError>{
+    if ![15, 16, 24, 32].contains(&depth){
+        return Err(FramebufferError::UnsupportedDepth(depth));
+    }
+    //the resolution registers have 16 bits
+    if width == 0 || height == 0 || width > u16::MAX as usize || height > u16::MAX as usize{
+        return Err(FramebufferError::ModeRejected);
+    }
+    let pitch = width.checked_mul((depth as usize).div_ceil(8)).ok_or(FramebufferError::ModeRejected)?;
+    let first_line = TEXT_MODE_MEMORY.div_ceil(pitch);
+    let virtual_height = height.checked_mul(2)
+        .and_then(|pages| pages.checked_add(first_line))
+        .filter(|&lines| lines <= u16::MAX as usize)
+        .ok_or(FramebufferError::ModeRejected)?;
+    let mut dispi = Dispi::new();
+    let id = dispi.read(DISPI_INDEX_ID);
+    if !(DISPI_ID_VIRTUAL_SCREEN..=DISPI_ID_LATEST).contains(&id){
+        return Err(FramebufferError::NotPresent);
+    }
+    let phys = pci::find_device(BGA_VENDOR_ID, BGA_DEVICE_ID)
+        .and_then(|device| device.memory_bar(0))
+        .ok_or(FramebufferError::NoPciDevice)?;
+
+    dispi.write(DISPI_INDEX_ENABLE, 0);
+    dispi.write(DISPI_INDEX_XRES, width as u16);
+    dispi.write(DISPI_INDEX_YRES, height as u16);
+    dispi.write(DISPI_INDEX_BPP, depth as u16);
+    dispi.write(DISPI_INDEX_VIRT_WIDTH, width as u16);
+    dispi.write(DISPI_INDEX_VIRT_HEIGHT, virtual_height as u16);
+    dispi.write(DISPI_INDEX_ENABLE, DISPI_ENABLED | DISPI_LFB_ENABLED);
+    let accepted = dispi.read(DISPI_INDEX_XRES) as usize == width
+        && dispi.read(DISPI_INDEX_YRES) as usize == height
+        && dispi.read(DISPI_INDEX_BPP) as u8 == depth
+        && dispi.read(DISPI_INDEX_VIRT_WIDTH) as usize == width
+        && dispi.read(DISPI_INDEX_VIRT_HEIGHT) as usize >= virtual_height;
+    if !accepted{
+        dispi.write(DISPI_INDEX_ENABLE, 0);
+        return Err(FramebufferError::ModeRejected);
+    }
+
+    let base = VirtAddr::new(FRAMEBUFFER_START);
+    let mapped = match memory::MEMORY.lock().as_mut(){
+        Some(memory) => memory.map_device_memory(PhysAddr::new(phys), base, (virtual_height * pitch) as u64)
+            .map_err(|_| FramebufferError::MapFailed),
+        None => Err(FramebufferError::NoMemoryManager),
+    };
+    if let Err(error) = mapped{
+        dispi.write(DISPI_INDEX_ENABLE, 0);
+        return Err(error);
+    }
+
+    //show the first page and draw to the second
+    dispi.write(DISPI_INDEX_Y_OFFSET, first_line as u16);
+    //the framebuffer console draws from interrupt handlers printing
+    interrupts::without_interrupts(||{
+        *FRAMEBUFFER.lock() = Some(Framebuffer{
+            dispi, base, width, height, depth, pitch, first_line, back_page: 1, double_buffered: true,
+        });
+    });
+    Ok(())
+}
+
+///Leave the graphics mode and show VGA text mode again.
+pub fn text_mode(){
+    interrupts::without_interrupts(||{
+        if let Some(mut framebuffer) = FRAMEBUFFER.lock().take(){
+            framebuffer.dispi.write(DISPI_INDEX_ENABLE, 0);
+        }
+    })
+}
+///Show VGA text mode again without taking the framebuffer lock, for panics and fatal exceptions.
+///`FRAMEBUFFER` is left as it is, so nothing should draw afterwards.
+pub(crate) fn emergency_text_mode(){
+    suspend_mode();
+}
+///Show VGA text mode without taking the framebuffer lock, for code that stops the kernel and lets it go on later.
+///Returns what `resume_mode` needs to bring back the graphics mode.
+pub(crate) fn suspend_mode() -> u16{
+    let mut dispi = Dispi::new();
+    let enable = dispi.read(DISPI_INDEX_ENABLE);
+    dispi.write(DISPI_INDEX_ENABLE, 0);
+    enable
+}
+///Bring back the mode `suspend_mode` left, keeping the picture in video memory.
+pub(crate) fn resume_mode(enable: u16){
+    if enable & DISPI_ENABLED != 0{
+        Dispi::new().write(DISPI_INDEX_ENABLE, enable | DISPI_NO_CLEAR_MEMORY);
+    }
+}
+///Width, height and bits per pixel of the graphics mode, or `None` in text mode.
+pub fn current_mode() -> Option<(usize, usize, u8)>{
+    interrupts::without_interrupts(||{
+        FRAMEBUFFER.lock().as_ref().map(|framebuffer| (framebuffer.width, framebuffer.height, framebuffer.depth))
+    })
+}
+
+//----------TEST CASES------------
+#[test_case]
+//the digits are grouped by colour channel
+#[allow(clippy::unusual_byte_groupings)]
+fn colours_encode_per_depth(){
+    let orange = Rgb::new(0xff, 0x80, 0x10);
+    assert_eq!(orange.encode(32), 0x00ff8010);
+    assert_eq!(orange.encode(24), 0x00ff8010);
+    assert_eq!(orange.encode(16), 0b11111_100000_00010);
+    assert_eq!(orange.encode(15), 0b11111_10000_00010);
+}
+#[test_case]
+fn impossible_modes_are_rejected(){
+    assert_eq!(set_mode(0, 480, 32), Err(FramebufferError::ModeRejected));
+    assert_eq!(set_mode(640, 0, 32), Err(FramebufferError::ModeRejected));
+    assert_eq!(set_mode(usize::MAX, 480, 32), Err(FramebufferError::ModeRejected));
+    assert_eq!(set_mode(640, 40000, 32), Err(FramebufferError::ModeRejected));
+}
@@ -0,0 +1,554 @@
+//! A stub for the GDB remote serial protocol, answering on the port with the debugger role.
+//!
+//! With a port assigned (`serial debugger COM2`), breakpoints stop the kernel and wait for GDB, and GDB can
+//! interrupt the running kernel by sending a packet or Ctrl-C. The stub supports reading and writing registers and
+//! memory, software breakpoints and single steps. It runs inside the breakpoint and debug exceptions with
+//! interrupts disabled, and only touches the UART through `serial::unlocked_uart`, so it can stop any code.
+//! Connect with `target remote` on the host end of the port, e.g. QEMU's `-serial tcp::1234,server,nowait`.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::interrupts::{TrapFrame, TRAP_FLAG};
+use crate::memory;
+use crate::serial::{self, ComPort, Role, Uart};
+use crate::sync::Mutex;
+
+///Longest packet the stub accepts or sends, without framing.
+const PACKET_SIZE: usize = 4096;
+const MAX_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xcc;
+const CTRL_C: u8 = 0x03;
+
+///Signals reported to GDB when the kernel stops.
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+///Registers in GDB's amd64 order: 17 of 8 bytes up to rip, then 7 of 4 bytes from eflags.
+const REGISTER_COUNT: usize = 24;
+const WIDE_REGISTERS: usize = 17;
+
+///Set by the serial interrupt when GDB wants the kernel stopped.
+static BREAK_REQUESTED: AtomicBool = AtomicBool::new(false);
+///Whether the byte that asked for the break was the `$` opening a packet, so the stub starts inside it.
+static PACKET_STARTED: AtomicBool = AtomicBool::new(false);
+
+static STUB: Mutex<Stub> = Mutex::new(Stub::new());
+
+///Look at a byte received on the debugger port while the kernel runs; true if the kernel should stop for GDB.
+pub(crate) fn receive_byte(byte: u8) -> bool{
+    match byte{
+        CTRL_C => {},
+        b'$' => PACKET_STARTED.store(true, Ordering::Relaxed),
+        //acknowledgements and noise
+        _ => return false,
+    }
+    BREAK_REQUESTED.store(true, Ordering::Relaxed);
+    true
+}
+
+///Handle a breakpoint or debug exception. Returns false if it was not meant for the debugger, e.g. as no port has
+///the debugger role.
+pub(crate) fn handle_trap(frame: &mut TrapFrame) -> bool{
+    //locked means the trap hit the stub itself
+    let mut stub = match STUB.try_lock(){
+        Some(stub) => stub,
+        None => return false,
+    };
+    let port = match stub.port.or_else(|| serial::try_port_for(Role::Debugger).flatten()){
+        Some(port) => port,
+        None => return false,
+    };
+    let uart = match serial::unlocked_uart(port){
+        Some(uart) => uart,
+        None => return false,
+    };
+    let signal = match frame.vector{
+        3 => {
+            //after one of the stub's breakpoints, the instruction under it is run next
+            if stub.breakpoints.iter().flatten().any(|breakpoint| breakpoint.address == frame.rip.wrapping_sub(1)){
+                frame.rip -= 1;
+            }
+            SIGTRAP
+        },
+        _ if BREAK_REQUESTED.swap(false, Ordering::Relaxed) => {
+            frame.rflags &= !TRAP_FLAG;
+            SIGINT
+        },
+        _ if stub.stepping => {
+            frame.rflags &= !TRAP_FLAG;
+            SIGTRAP
+        },
+        _ => return false,
+    };
+    stub.port = Some(port);
+    stub.stepping = false;
+    stub.session(uart, frame, signal);
+    true
+}
+
+///A breakpoint the stub wrote into memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Breakpoint{
+    address: u64,
+    ///The byte the `int3` replaced.
+    original: u8,
+}
+
+///What to do after a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resume{
+    ///Stay stopped and wait for the next command.
+    No,
+    Continue,
+    Step,
+    ///Remove the breakpoints and let the kernel run without the debugger.
+    Detach,
+}
+
+///Packet contents being put together, in a fixed buffer as the heap may be locked by the stopped code.
+struct Reply{
+    buffer: [u8; PACKET_SIZE],
+    len: usize,
+}
+impl Reply{
+    const fn new() -> Reply{
+        Reply{buffer: [0; PACKET_SIZE], len: 0}
+    }
+    fn clear(&mut self){
+        self.len = 0;
+    }
+    fn push(&mut self, byte: u8){
+        if self.len < PACKET_SIZE{
+            self.buffer[self.len] = byte;
+            self.len += 1;
+        }
+    }
+    fn push_hex(&mut self, byte: u8){
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+        self.push(DIGITS[(byte >> 4) as usize]);
+        self.push(DIGITS[(byte & 0xf) as usize]);
+    }
+    ///Push the lowest `bytes` bytes of a value, least significant first as GDB expects for x86.
+    fn push_le(&mut self, value: u64, bytes: usize){
+        for &byte in &value.to_le_bytes()[..bytes]{
+            self.push_hex(byte);
+        }
+    }
+    fn as_bytes(&self) -> &[u8]{
+        &self.buffer[..self.len]
+    }
+}
+impl fmt::Write for Reply{
+    fn write_str(&mut self, s: &str) -> fmt::Result{
+        for byte in s.bytes(){
+            self.push(byte);
+        }
+        Ok(())
+    }
+}
+
+fn hex_value(digit: u8) -> Option<u8>{
+    match digit{
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+fn parse_hex(text: &[u8]) -> Option<u64>{
+    if text.is_empty() || text.len() > 16{
+        return None;
+    }
+    text.iter().try_fold(0, |value, &digit| Some(value << 4 | hex_value(digit)? as u64))
+}
+///Decode hex pairs into bytes, least significant first.
+fn parse_le(text: &[u8]) -> Option<u64>{
+    if !text.len().is_multiple_of(2) || text.len() > 16{
+        return None;
+    }
+    text.chunks(2).rev().try_fold(0, |value, pair| Some(value << 8 | (hex_value(pair[0])? << 4 | hex_value(pair[1])?) as u64))
+}
+///Split "address,length" into its numbers.
+fn parse_range(text: &[u8]) -> Option<(u64, u64)>{
+    let comma = text.iter().position(|&byte| byte == b',')?;
+    Some((parse_hex(&text[..comma])?, parse_hex(&text[comma + 1..])?))
+}
+
+///A register by GDB number, with its size in bytes; the segment registers besides cs and ss read as 0.
+fn register(frame: &TrapFrame, number: usize) -> Option<(u64, usize)>{
+    let value = match number{
+        0 => frame.rax,
+        1 => frame.rbx,
+        2 => frame.rcx,
+        3 => frame.rdx,
+        4 => frame.rsi,
+        5 => frame.rdi,
+        6 => frame.rbp,
+        7 => frame.rsp,
+        8 => frame.r8,
+        9 => frame.r9,
+        10 => frame.r10,
+        11 => frame.r11,
+        12 => frame.r12,
+        13 => frame.r13,
+        14 => frame.r14,
+        15 => frame.r15,
+        16 => frame.rip,
+        17 => frame.rflags,
+        18 => frame.cs,
+        19 => frame.ss,
+        20..=23 => 0,
+        _ => return None,
+    };
+    Some((value, if number < WIDE_REGISTERS {8} else {4}))
+}
+///Change a register by GDB number. Segment registers stay as they are.
+fn set_register(frame: &mut TrapFrame, number: usize, value: u64) -> bool{
+    let register = match number{
+        0 => &mut frame.rax,
+        1 => &mut frame.rbx,
+        2 => &mut frame.rcx,
+        3 => &mut frame.rdx,
+        4 => &mut frame.rsi,
+        5 => &mut frame.rdi,
+        6 => &mut frame.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut frame.r8,
+        9 => &mut frame.r9,
+        10 => &mut frame.r10,
+        11 => &mut frame.r11,
+        12 => &mut frame.r12,
+        13 => &mut frame.r13,
+        14 => &mut frame.r14,
+        15 => &mut frame.r15,
+        16 => &mut frame.rip,
+        17 => &mut frame.rflags,
+        18..=23 => return true,
+        _ => return false,
+    };
+    *register = value;
+    true
+}
+
+///State of the debugger, kept between stops.
+struct Stub{
+    ///Port GDB is attached on, from its first stop until it detaches.
+    port: Option<ComPort>,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    ///Whether the kernel was resumed for a single step.
+    stepping: bool,
+    reply: Reply,
+}
+impl Stub{
+    const fn new() -> Stub{
+        Stub{port: None, breakpoints: [None; MAX_BREAKPOINTS], stepping: false, reply: Reply::new()}
+    }
+    ///Report the stop to GDB, then handle its commands until it resumes the kernel.
+    fn session(&mut self, mut uart: Uart, frame: &mut TrapFrame, signal: u8){
+        //GDB waits for a stop reply after Ctrl-C; breaking in with a packet answers that packet first
+        if !PACKET_STARTED.load(Ordering::Relaxed){
+            self.reply.clear();
+            self.stop_reply(signal);
+            self.send_reply(&mut uart);
+        }
+        let mut packet = Reply::new();
+        loop{
+            receive_packet(&mut uart, &mut packet);
+            self.reply.clear();
+            match self.handle_packet(packet.as_bytes(), frame, signal){
+                Resume::No => self.send_reply(&mut uart),
+                Resume::Continue => {
+                    frame.rflags &= !TRAP_FLAG;
+                    return;
+                },
+                Resume::Step => {
+                    frame.rflags |= TRAP_FLAG;
+                    self.stepping = true;
+                    return;
+                },
+                Resume::Detach => {
+                    self.send_reply(&mut uart);
+                    self.remove_breakpoints();
+                    frame.rflags &= !TRAP_FLAG;
+                    self.port = None;
+                    return;
+                },
+            }
+        }
+    }
+    fn stop_reply(&mut self, signal: u8){
+        self.reply.push(b'S');
+        self.reply.push_hex(signal);
+    }
+    ///Carry out a command, leaving the answer in `reply`.
+    fn handle_packet(&mut self, packet: &[u8], frame: &mut TrapFrame, signal: u8) -> Resume{
+        let (&command, arguments) = match packet.split_first(){
+            Some(split) => split,
+            None => return Resume::No,
+        };
+        match command{
+            b'?' => self.stop_reply(signal),
+            b'g' => {
+                for number in 0..REGISTER_COUNT{
+                    if let Some((value, bytes)) = register(frame, number){
+                        self.reply.push_le(value, bytes);
+                    }
+                }
+            },
+            b'G' => {
+                let mut rest = arguments;
+                for number in 0..REGISTER_COUNT{
+                    let bytes = if number < WIDE_REGISTERS {8} else {4};
+                    if rest.len() < bytes * 2{
+                        break;
+                    }
+                    let (digits, tail) = rest.split_at(bytes * 2);
+                    match parse_le(digits){
+                        Some(value) => set_register(frame, number, value),
+                        None => return self.error(1),
+                    };
+                    rest = tail;
+                }
+                self.ok();
+            },
+            b'p' => match parse_hex(arguments).and_then(|number| register(frame, number as usize)){
+                Some((value, bytes)) => self.reply.push_le(value, bytes),
+                None => return self.error(1),
+            },
+            b'P' => {
+                let equals = arguments.iter().position(|&byte| byte == b'=');
+                let parsed = equals.and_then(|equals| Some((parse_hex(&arguments[..equals])?, parse_le(&arguments[equals + 1..])?)));
+                match parsed{
+                    Some((number, value)) if set_register(frame, number as usize, value) => self.ok(),
+                    _ => return self.error(1),
+                }
+            },
+            b'm' => match parse_range(arguments){
+                Some((address, length)) => {
+                    let length = length.min(PACKET_SIZE as u64 / 2);
+                    for offset in 0..length{
+                        match memory::peek(address.wrapping_add(offset)){
+                            Some(byte) => self.reply.push_hex(byte),
+                            None => break,
+                        }
+                    }
+                    if length != 0 && self.reply.len == 0{
+                        return self.error(14);
+                    }
+                },
+                None => return self.error(1),
+            },
+            b'M' => {
+                let colon = arguments.iter().position(|&byte| byte == b':');
+                let parsed = colon.and_then(|colon| Some((parse_range(&arguments[..colon])?, &arguments[colon + 1..])));
+                match parsed{
+                    Some(((address, length), data)) if length.checked_mul(2) == Some(data.len() as u64) => {
+                        for (offset, pair) in data.chunks(2).enumerate(){
+                            let byte = match parse_le(pair){
+                                Some(byte) => byte as u8,
+                                None => return self.error(1),
+                            };
+                            if !memory::poke(address.wrapping_add(offset as u64), byte){
+                                return self.error(14);
+                            }
+                        }
+                        self.ok();
+                    },
+                    _ => return self.error(1),
+                }
+            },
+            b'Z' | b'z' => {
+                //only software breakpoints, "Z0,address,kind"
+                match arguments.strip_prefix(b"0,").and_then(parse_range){
+                    Some((address, _)) => {
+                        let done = if command == b'Z' {self.insert_breakpoint(address)} else {self.remove_breakpoint(address)};
+                        if done {self.ok()} else {return self.error(14)}
+                    },
+                    None => return Resume::No,
+                }
+            },
+            b'c' | b's' => {
+                if let Some(address) = parse_hex(arguments){
+                    frame.rip = address;
+                }
+                return if command == b'c' {Resume::Continue} else {Resume::Step};
+            },
+            b'D' => {
+                self.ok();
+                return Resume::Detach;
+            },
+            //there is nothing to kill, so the kernel just goes on
+            b'k' => {
+                self.remove_breakpoints();
+                self.port = None;
+                return Resume::Continue;
+            },
+            b'H' => self.ok(),
+            b'q' => {
+                if arguments.starts_with(b"Supported"){
+                    write!(self.reply, "PacketSize={:x};swbreak+", PACKET_SIZE).ok();
+                } else if arguments == b"Attached"{
+                    self.reply.push(b'1');
+                } else if arguments == b"C"{
+                    self.reply.push(b'Q');
+                    self.reply.push(b'C');
+                    self.reply.push(b'1');
+                }
+            },
+            //empty replies tell GDB the command is not supported
+            _ => {},
+        }
+        Resume::No
+    }
+    fn ok(&mut self){
+        self.reply.clear();
+        self.reply.push(b'O');
+        self.reply.push(b'K');
+    }
+    fn error(&mut self, code: u8) -> Resume{
+        self.reply.clear();
+        self.reply.push(b'E');
+        self.reply.push_hex(code);
+        Resume::No
+    }
+    fn insert_breakpoint(&mut self, address: u64) -> bool{
+        if self.breakpoints.iter().flatten().any(|breakpoint| breakpoint.address == address){
+            return true;
+        }
+        let slot = match self.breakpoints.iter().position(|breakpoint| breakpoint.is_none()){
+            Some(slot) => slot,
+            None => return false,
+        };
+        let original = match memory::peek(address){
+            Some(original) => original,
+            None => return false,
+        };
+        if !memory::poke(address, INT3){
+            return false;
+        }
+        self.breakpoints[slot] = Some(Breakpoint{address, original});
+        true
+    }
+    fn remove_breakpoint(&mut self, address: u64) -> bool{
+        for slot in self.breakpoints.iter_mut(){
+            if let Some(breakpoint) = *slot{
+                if breakpoint.address == address{
+                    *slot = None;
+                    return memory::poke(address, breakpoint.original);
+                }
+            }
+        }
+        true
+    }
+    fn remove_breakpoints(&mut self){
+        for slot in self.breakpoints.iter_mut(){
+            if let Some(breakpoint) = slot.take(){
+                memory::poke(breakpoint.address, breakpoint.original);
+            }
+        }
+    }
+    ///Send `reply` as a packet until GDB acknowledges it.
+    fn send_reply(&mut self, uart: &mut Uart){
+        loop{
+            uart.send(b'$');
+            let mut checksum = 0u8;
+            for &byte in self.reply.as_bytes(){
+                uart.send(byte);
+                checksum = checksum.wrapping_add(byte);
+            }
+            uart.send(b'#');
+            let mut digits = Reply::new();
+            digits.push_hex(checksum);
+            uart.send(digits.buffer[0]);
+            uart.send(digits.buffer[1]);
+            loop{
+                match receive(uart){
+                    b'+' => return,
+                    b'-' => break,
+                    //GDB moved on without acknowledging
+                    b'$' => {
+                        PACKET_STARTED.store(true, Ordering::Relaxed);
+                        return;
+                    },
+                    _ => {},
+                }
+            }
+        }
+    }
+}
+
+///Wait for a packet with a valid checksum, acknowledging it, and leave its contents in `packet`.
+fn receive_packet(uart: &mut Uart, packet: &mut Reply){
+    let mut started = PACKET_STARTED.swap(false, Ordering::Relaxed);
+    loop{
+        while !started{
+            started = receive(uart) == b'$';
+        }
+        started = false;
+        packet.clear();
+        let mut checksum = 0u8;
+        let mut byte = receive(uart);
+        while byte != b'#'{
+            if byte == b'$'{
+                //a new packet started over
+                packet.clear();
+                checksum = 0;
+            } else {
+                packet.push(byte);
+                checksum = checksum.wrapping_add(byte);
+            }
+            byte = receive(uart);
+        }
+        let expected = (receive(uart), receive(uart));
+        match (hex_value(expected.0), hex_value(expected.1)){
+            (Some(high), Some(low)) if high << 4 | low == checksum => {
+                uart.send(b'+');
+                return;
+            },
+            _ => uart.send(b'-'),
+        }
+    }
+}
+///Wait for a byte from GDB.
+fn receive(uart: &mut Uart) -> u8{
+    loop{
+        if let Some(byte) = uart.try_receive(){
+            return byte;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+//----------TEST CASES------------
+#[test_case]
+fn registers_and_memory_packets(){
+    let mut stub = Stub::new();
+    let mut frame = TrapFrame{
+        rax: 0x1122334455667788, rbx: 0, rcx: 0, rdx: 0, rsi: 0, rdi: 0, rbp: 0, r8: 0, r9: 0, r10: 0, r11: 0,
+        r12: 0, r13: 0, r14: 0, r15: 0, vector: 3, rip: 0x20_0000, cs: 8, rflags: 0x202, rsp: 0, ss: 0,
+    };
+    assert_eq!(stub.handle_packet(b"p0", &mut frame, SIGTRAP), Resume::No);
+    assert_eq!(stub.reply.as_bytes(), b"8877665544332211");
+    stub.reply.clear();
+    stub.handle_packet(b"P10=0010200000000000", &mut frame, SIGTRAP);
+    assert_eq!((stub.reply.as_bytes(), frame.rip), (&b"OK"[..], 0x201000));
+    stub.reply.clear();
+    stub.handle_packet(b"g", &mut frame, SIGTRAP);
+    assert_eq!(stub.reply.len, (WIDE_REGISTERS * 8 + (REGISTER_COUNT - WIDE_REGISTERS) * 4) * 2);
+
+    let data = [0xde_u8, 0xad, 0xbe, 0xef];
+    let address = &data as *const u8 as u64;
+    let mut packet = Reply::new();
+    write!(packet, "m{:x},4", address).unwrap();
+    stub.reply.clear();
+    stub.handle_packet(packet.as_bytes(), &mut frame, SIGTRAP);
+    assert_eq!(stub.reply.as_bytes(), b"deadbeef");
+    stub.reply.clear();
+    stub.handle_packet(b"m0,4", &mut frame, SIGTRAP);
+    assert_eq!(stub.reply.as_bytes(), b"E0e");
+    //a length whose count of hex digits overflows a u64
+    stub.reply.clear();
+    stub.handle_packet(b"M0,8000000000000000:", &mut frame, SIGTRAP);
+    assert_eq!(stub.reply.as_bytes(), b"E01");
+    assert_eq!(stub.handle_packet(b"s", &mut frame, SIGTRAP), Resume::Step);
+}
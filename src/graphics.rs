@@ -0,0 +1,479 @@
+//! QuickDraw-style 2D drawing into off-screen one bit per pixel canvases, for the desktop.
+//!
+//! Shapes are filled with 8x8 patterns through a transfer mode and clipped to the canvas clip region.
+//! Rectangles include their top and left edge but not their bottom and right one, as in QuickDraw.
+//! A finished canvas is shown on anything implementing `Surface`, such as the framebuffer.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::font::PsfFont;
+use crate::framebuffer::{Framebuffer, Rgb};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Point{
+    pub x: i32,
+    pub y: i32,
+}
+impl Point{
+    pub const fn new(x: i32, y: i32) -> Point{
+        Point{x, y}
+    }
+}
+
+///A rectangle of pixels, from `left`, `top` up to but not including `right`, `bottom`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rect{
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+}
+impl Rect{
+    pub const fn new(left: i32, top: i32, right: i32, bottom: i32) -> Rect{
+        Rect{left, top, right, bottom}
+    }
+    ///The rectangle of the given size with its top left corner at (x, y).
+    pub const fn with_size(x: i32, y: i32, width: i32, height: i32) -> Rect{
+        Rect{left: x, top: y, right: x + width, bottom: y + height}
+    }
+    pub fn width(&self) -> i32{
+        self.right - self.left
+    }
+    pub fn height(&self) -> i32{
+        self.bottom - self.top
+    }
+    pub fn is_empty(&self) -> bool{
+        self.right <= self.left || self.bottom <= self.top
+    }
+    pub fn contains(&self, point: Point) -> bool{
+        point.x >= self.left && point.x < self.right && point.y >= self.top && point.y < self.bottom
+    }
+    ///The part covered by both rectangles, which may be empty.
+    pub fn intersect(&self, other: Rect) -> Rect{
+        Rect::new(self.left.max(other.left), self.top.max(other.top), self.right.min(other.right), self.bottom.min(other.bottom))
+    }
+    pub fn offset(&self, dx: i32, dy: i32) -> Rect{
+        Rect::new(self.left + dx, self.top + dy, self.right + dx, self.bottom + dy)
+    }
+    ///The rectangle moved inwards by `dx` on the left and right and `dy` on the top and bottom.
+    pub fn inset(&self, dx: i32, dy: i32) -> Rect{
+        Rect::new(self.left + dx, self.top + dy, self.right - dx, self.bottom - dy)
+    }
+    ///The parts of this rectangle outside `other`: the bands above and below it and the pieces left and right of it.
+    fn subtract(&self, other: Rect) -> [Rect; 4]{
+        let overlap = self.intersect(other);
+        if overlap.is_empty(){
+            return [*self, Rect::default(), Rect::default(), Rect::default()];
+        }
+        [
+            Rect::new(self.left, self.top, self.right, overlap.top),
+            Rect::new(self.left, overlap.bottom, self.right, self.bottom),
+            Rect::new(self.left, overlap.top, overlap.left, overlap.bottom),
+            Rect::new(overlap.right, overlap.top, self.right, overlap.bottom),
+        ]
+    }
+}
+
+///An area of any shape, kept as non-overlapping rectangles.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Region{
+    rects: Vec<Rect>,
+}
+impl Region{
+    pub fn new() -> Region{
+        Region{rects: Vec::new()}
+    }
+    pub fn from_rect(rect: Rect) -> Region{
+        let mut region = Region::new();
+        region.union_rect(rect);
+        region
+    }
+    ///The rectangles making up the region; they do not overlap.
+    pub fn rects(&self) -> &[Rect]{
+        &self.rects
+    }
+    pub fn is_empty(&self) -> bool{
+        self.rects.is_empty()
+    }
+    pub fn contains(&self, point: Point) -> bool{
+        self.rects.iter().any(|rect| rect.contains(point))
+    }
+    ///The smallest rectangle around the region.
+    pub fn bounds(&self) -> Rect{
+        self.rects.iter().copied().reduce(|a, b| {
+            Rect::new(a.left.min(b.left), a.top.min(b.top), a.right.max(b.right), a.bottom.max(b.bottom))
+        }).unwrap_or_default()
+    }
+    ///Add a rectangle to the region.
+    pub fn union_rect(&mut self, rect: Rect){
+        let mut pieces = vec![rect];
+        for existing in &self.rects{
+            pieces = pieces.iter().flat_map(|piece| piece.subtract(*existing)).filter(|piece| !piece.is_empty()).collect();
+        }
+        self.rects.extend(pieces);
+    }
+    pub fn union(&mut self, other: &Region){
+        for rect in &other.rects{
+            self.union_rect(*rect);
+        }
+    }
+    ///Remove a rectangle from the region.
+    pub fn subtract_rect(&mut self, rect: Rect){
+        self.rects = self.rects.iter().flat_map(|existing| existing.subtract(rect)).filter(|piece| !piece.is_empty()).collect();
+    }
+    ///Keep only the part of the region inside `rect`.
+    pub fn intersect_rect(&mut self, rect: Rect){
+        self.rects = self.rects.iter().map(|existing| existing.intersect(rect)).filter(|piece| !piece.is_empty()).collect();
+    }
+    ///Keep only the part of the region inside `other`.
+    pub fn intersect(&mut self, other: &Region){
+        self.rects = self.rects.iter()
+            .flat_map(|a| other.rects.iter().map(move |b| a.intersect(*b)))
+            .filter(|piece| !piece.is_empty())
+            .collect();
+    }
+    pub fn offset(&mut self, dx: i32, dy: i32){
+        self.rects.iter_mut().for_each(|rect| *rect = rect.offset(dx, dy));
+    }
+}
+
+///An 8x8 pixel pattern, one byte per row with the leftmost pixel in the top bit. Set bits are black.
+///Patterns are aligned to the canvas, so neighbouring shapes filled with the same one line up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pattern(pub [u8; 8]);
+impl Pattern{
+    pub const WHITE: Pattern = Pattern([0x00; 8]);
+    pub const BLACK: Pattern = Pattern([0xff; 8]);
+    pub const GRAY: Pattern = Pattern([0xaa, 0x55, 0xaa, 0x55, 0xaa, 0x55, 0xaa, 0x55]);
+    pub const LIGHT_GRAY: Pattern = Pattern([0x88, 0x22, 0x88, 0x22, 0x88, 0x22, 0x88, 0x22]);
+    pub const DARK_GRAY: Pattern = Pattern([0x77, 0xdd, 0x77, 0xdd, 0x77, 0xdd, 0x77, 0xdd]);
+    fn row(&self, y: i32) -> u8{
+        self.0[(y & 7) as usize]
+    }
+}
+
+///How a pattern is combined with the pixels drawn over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode{
+    ///Replace the pixels with the pattern.
+    Copy,
+    ///Set the pixels that are black in the pattern.
+    Or,
+    ///Invert the pixels that are black in the pattern.
+    Xor,
+    ///Clear the pixels that are black in the pattern.
+    Bic,
+}
+impl Mode{
+    ///Combine the pixels of `destination` selected by `mask` with `source`.
+    fn apply(self, destination: u8, source: u8, mask: u8) -> u8{
+        let result = match self{
+            Mode::Copy => source,
+            Mode::Or => destination | source,
+            Mode::Xor => destination ^ source,
+            Mode::Bic => destination & !source,
+        };
+        destination & !mask | result & mask
+    }
+}
+
+///Something a canvas can be shown on.
+pub trait Surface{
+    ///Width and height in pixels.
+    fn size(&self) -> (usize, usize);
+    fn put_pixel(&mut self, x: usize, y: usize, color: Rgb);
+}
+impl Surface for Framebuffer{
+    fn size(&self) -> (usize, usize){
+        (self.width(), self.height())
+    }
+    fn put_pixel(&mut self, x: usize, y: usize, color: Rgb){
+        Framebuffer::put_pixel(self, x, y, color);
+    }
+}
+
+///An off-screen black and white image to draw on. Rows are packed eight pixels to the byte, leftmost pixel in
+///the top bit, and set bits are black.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Canvas{
+    width: i32,
+    height: i32,
+    row_bytes: usize,
+    bits: Vec<u8>,
+    ///Drawing is limited to this region; it is always inside the canvas.
+    clip: Region,
+}
+impl Canvas{
+    ///A white canvas of the given size, clipped to its bounds.
+    pub fn new(width: usize, height: usize) -> Canvas{
+        let row_bytes = width.div_ceil(8);
+        Canvas{
+            width: width as i32,
+            height: height as i32,
+            row_bytes,
+            bits: vec![0; row_bytes * height],
+            clip: Region::from_rect(Rect::with_size(0, 0, width as i32, height as i32)),
+        }
+    }
+    pub fn bounds(&self) -> Rect{
+        Rect::with_size(0, 0, self.width, self.height)
+    }
+    pub fn row_bytes(&self) -> usize{
+        self.row_bytes
+    }
+    ///The pixels, `row_bytes` per row.
+    pub fn bytes(&self) -> &[u8]{
+        &self.bits
+    }
+    pub fn clip(&self) -> &Region{
+        &self.clip
+    }
+    ///Limit drawing to `clip`, cut to the canvas.
+    pub fn set_clip(&mut self, mut clip: Region){
+        clip.intersect_rect(self.bounds());
+        self.clip = clip;
+    }
+    ///Allow drawing on the whole canvas again.
+    pub fn reset_clip(&mut self){
+        self.clip = Region::from_rect(self.bounds());
+    }
+    ///Whether the pixel at `point` is black. Pixels off the canvas are white.
+    pub fn pixel(&self, point: Point) -> bool{
+        if !self.bounds().contains(point){
+            return false;
+        }
+        self.bits[point.y as usize * self.row_bytes + point.x as usize / 8] & (0x80 >> (point.x % 8)) != 0
+    }
+    ///Draw the pixels `x0..x1` of row `y` with a pattern, ignoring the clip region.
+    fn raw_span(&mut self, y: i32, x0: i32, x1: i32, pattern: Pattern, mode: Mode){
+        let source = pattern.row(y);
+        let row = &mut self.bits[y as usize * self.row_bytes..(y as usize + 1) * self.row_bytes];
+        let mut x = x0;
+        while x < x1{
+            let byte_end = ((x & !7) + 8).min(x1);
+            let mask = (0xff >> (x & 7)) & !(0xffu16 >> (byte_end - (x & !7))) as u8;
+            let byte = &mut row[x as usize / 8];
+            *byte = mode.apply(*byte, source, mask);
+            x = byte_end;
+        }
+    }
+    ///Draw the pixels `x0..x1` of row `y` that are inside the clip region.
+    fn span(&mut self, y: i32, x0: i32, x1: i32, pattern: Pattern, mode: Mode){
+        for index in 0..self.clip.rects.len(){
+            let clip = self.clip.rects[index];
+            if y >= clip.top && y < clip.bottom{
+                let (from, to) = (x0.max(clip.left), x1.min(clip.right));
+                if from < to{
+                    self.raw_span(y, from, to, pattern, mode);
+                }
+            }
+        }
+    }
+    ///Fill the whole canvas, ignoring the clip region.
+    pub fn clear(&mut self, pattern: Pattern){
+        for y in 0..self.height{
+            self.raw_span(y, 0, self.width, pattern, Mode::Copy);
+        }
+    }
+    ///Draw a line from `from` to `to`, both ends included, using Bresenham's algorithm.
+    pub fn line(&mut self, from: Point, to: Point, pattern: Pattern, mode: Mode){
+        let (dx, dy) = ((to.x - from.x).abs(), -(to.y - from.y).abs());
+        let (step_x, step_y) = ((to.x - from.x).signum(), (to.y - from.y).signum());
+        let (mut x, mut y) = (from.x, from.y);
+        let mut error = dx + dy;
+        loop{
+            self.span(y, x, x + 1, pattern, mode);
+            if x == to.x && y == to.y{
+                break;
+            }
+            let doubled = 2 * error;
+            if doubled >= dy{
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx{
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+    pub fn fill_rect(&mut self, rect: Rect, pattern: Pattern, mode: Mode){
+        for y in rect.top..rect.bottom{
+            self.span(y, rect.left, rect.right, pattern, mode);
+        }
+    }
+    ///Draw the one pixel wide outline just inside `rect`.
+    pub fn frame_rect(&mut self, rect: Rect, pattern: Pattern, mode: Mode){
+        self.frame_round_rect(rect, 0, 0, pattern, mode);
+    }
+    ///Invert the pixels of `rect`, e.g. to highlight a selection.
+    pub fn invert_rect(&mut self, rect: Rect){
+        self.fill_rect(rect, Pattern::BLACK, Mode::Xor);
+    }
+    ///Fill a rectangle with corners rounded to quarter ovals `oval_width` wide and `oval_height` high.
+    pub fn fill_round_rect(&mut self, rect: Rect, oval_width: i32, oval_height: i32, pattern: Pattern, mode: Mode){
+        for y in rect.top..rect.bottom{
+            if let Some((x0, x1)) = round_rect_span(rect, oval_width, oval_height, y){
+                self.span(y, x0, x1, pattern, mode);
+            }
+        }
+    }
+    ///Draw the one pixel wide outline just inside a rounded rectangle.
+    pub fn frame_round_rect(&mut self, rect: Rect, oval_width: i32, oval_height: i32, pattern: Pattern, mode: Mode){
+        let inner = rect.inset(1, 1);
+        for y in rect.top..rect.bottom{
+            let (x0, x1) = match round_rect_span(rect, oval_width, oval_height, y){
+                Some(span) => span,
+                None => continue,
+            };
+            //the outline is the shape minus the shape one pixel smaller
+            match round_rect_span(inner, oval_width - 2, oval_height - 2, y){
+                Some((inner_x0, inner_x1)) if y >= inner.top && y < inner.bottom => {
+                    self.span(y, x0, inner_x0.max(x0 + 1), pattern, mode);
+                    self.span(y, inner_x1.min(x1 - 1).max(inner_x0.max(x0 + 1)), x1, pattern, mode);
+                },
+                _ => self.span(y, x0, x1, pattern, mode),
+            }
+        }
+    }
+    ///Fill the oval inside `rect`.
+    pub fn fill_oval(&mut self, rect: Rect, pattern: Pattern, mode: Mode){
+        self.fill_round_rect(rect, rect.width(), rect.height(), pattern, mode);
+    }
+    ///Draw the one pixel wide outline just inside the oval in `rect`.
+    pub fn frame_oval(&mut self, rect: Rect, pattern: Pattern, mode: Mode){
+        self.frame_round_rect(rect, rect.width(), rect.height(), pattern, mode);
+    }
+    pub fn fill_region(&mut self, region: &Region, pattern: Pattern, mode: Mode){
+        for rect in &region.rects{
+            self.fill_rect(*rect, pattern, mode);
+        }
+    }
+    ///Draw text with its top left corner at `origin`, combining the set pixels of each glyph through `mode`.
+    ///Returns the x coordinate following the last character.
+    pub fn draw_text(&mut self, origin: Point, text: &str, font: &PsfFont, mode: Mode) -> i32{
+        let mut x = origin.x;
+        for c in text.chars(){
+            let glyph = font.glyph(c);
+            for row in 0..font.height(){
+                for col in 0..font.width(){
+                    if font.pixel(glyph, col, row){
+                        let (pixel_x, pixel_y) = (x + col as i32, origin.y + row as i32);
+                        self.span(pixel_y, pixel_x, pixel_x + 1, Pattern::BLACK, mode);
+                    }
+                }
+            }
+            x += font.width() as i32;
+        }
+        x
+    }
+    ///Copy the pixels of `source_rect` of another canvas to `destination` of this one. Black source pixels
+    ///act like a black pattern pixel in `mode`, so `Mode::Copy` copies and `Mode::Or` lays the source over.
+    pub fn copy_bits(&mut self, source: &Canvas, source_rect: Rect, destination: Point, mode: Mode){
+        let source_rect = source_rect.intersect(source.bounds());
+        let (dx, dy) = (destination.x - source_rect.left, destination.y - source_rect.top);
+        let target = source_rect.offset(dx, dy);
+        for index in 0..self.clip.rects.len(){
+            let area = self.clip.rects[index].intersect(target);
+            for y in area.top..area.bottom{
+                for x in area.left..area.right{
+                    let black = source.pixel(Point::new(x - dx, y - dy));
+                    let byte = &mut self.bits[y as usize * self.row_bytes + x as usize / 8];
+                    *byte = mode.apply(*byte, if black {0xff} else {0}, 0x80 >> (x % 8));
+                }
+            }
+        }
+    }
+    ///Show `rect` of the canvas at the same place on `surface`, black pixels in `foreground` and white ones
+    ///in `background`.
+    pub fn present(&self, rect: Rect, surface: &mut impl Surface, foreground: Rgb, background: Rgb){
+        let (width, height) = surface.size();
+        let rect = rect.intersect(self.bounds()).intersect(Rect::with_size(0, 0, width as i32, height as i32));
+        for y in rect.top..rect.bottom{
+            for x in rect.left..rect.right{
+                let color = if self.pixel(Point::new(x, y)) {foreground} else {background};
+                surface.put_pixel(x as usize, y as usize, color);
+            }
+        }
+    }
+}
+
+///Integer square root, rounded down.
+fn isqrt(value: u64) -> u64{
+    let mut root = 0;
+    let mut bit = 1 << 62;
+    let mut rest = value;
+    while bit > value{
+        bit >>= 2;
+    }
+    while bit != 0{
+        if rest >= root + bit{
+            rest -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    root
+}
+
+///Pixels `x0..x1` covered on row `y` by a rectangle with its corners rounded to quarter ovals, or `None` if
+///the row is outside it.
+fn round_rect_span(rect: Rect, oval_width: i32, oval_height: i32, y: i32) -> Option<(i32, i32)>{
+    if rect.is_empty() || y < rect.top || y >= rect.bottom{
+        return None;
+    }
+    let oval_width = oval_width.clamp(0, rect.width());
+    let oval_height = oval_height.clamp(0, rect.height());
+    //row within the oval whose quarters make up the corners, if the row is in a corner
+    let corner_row = if y - rect.top < oval_height / 2{
+        y - rect.top
+    } else if rect.bottom - 1 - y < oval_height / 2{
+        oval_height - (rect.bottom - y)
+    } else {
+        return Some((rect.left, rect.right));
+    };
+    //pixel (x, row) of the oval is inside if its centre is; in doubled coordinates to stay on whole numbers
+    let (width, height) = (oval_width as i64, oval_height as i64);
+    let dy = 2 * corner_row as i64 + 1 - height;
+    let half_width = isqrt((width * width * (height * height - dy * dy) / (height * height)) as u64) as i64;
+    let inset = ((width - 1 - half_width + 1) / 2) as i32;
+    Some((rect.left + inset, rect.right - inset))
+}
+
+//----------TEST CASES------------
+#[test_case]
+fn lines_and_xor_rects(){
+    let mut canvas = Canvas::new(8, 4);
+    canvas.line(Point::new(0, 0), Point::new(7, 3), Pattern::BLACK, Mode::Copy);
+    assert_eq!(canvas.bytes(), &[0b1100_0000, 0b0011_0000, 0b0000_1100, 0b0000_0011]);
+    canvas.fill_rect(Rect::new(1, 1, 7, 3), Pattern::BLACK, Mode::Xor);
+    assert_eq!(canvas.bytes(), &[0b1100_0000, 0b0100_1110, 0b0111_0010, 0b0000_0011]);
+    canvas.clear(Pattern::GRAY);
+    assert_eq!(canvas.bytes(), &[0xaa, 0x55, 0xaa, 0x55]);
+}
+#[test_case]
+fn ovals_and_round_rects(){
+    let mut canvas = Canvas::new(8, 8);
+    canvas.fill_oval(canvas.bounds(), Pattern::BLACK, Mode::Copy);
+    assert_eq!(canvas.bytes(), &[0x3c, 0x7e, 0xff, 0xff, 0xff, 0xff, 0x7e, 0x3c]);
+    canvas.clear(Pattern::WHITE);
+    canvas.frame_oval(canvas.bounds(), Pattern::BLACK, Mode::Copy);
+    assert_eq!(canvas.bytes(), &[0x3c, 0x42, 0x81, 0x81, 0x81, 0x81, 0x42, 0x3c]);
+    canvas.clear(Pattern::WHITE);
+    canvas.frame_round_rect(canvas.bounds(), 4, 4, Pattern::BLACK, Mode::Copy);
+    assert_eq!(canvas.bytes(), &[0x7e, 0x81, 0x81, 0x81, 0x81, 0x81, 0x81, 0x7e]);
+}
+#[test_case]
+fn regions_clip_drawing(){
+    let mut region = Region::from_rect(Rect::new(0, 0, 8, 4));
+    region.subtract_rect(Rect::new(2, 1, 6, 3));
+    region.union_rect(Rect::new(3, 2, 5, 3));
+    assert!(region.contains(Point::new(3, 2)) && !region.contains(Point::new(3, 1)));
+    assert_eq!(region.bounds(), Rect::new(0, 0, 8, 4));
+    let mut canvas = Canvas::new(8, 4);
+    canvas.set_clip(region);
+    canvas.fill_rect(Rect::new(-5, -5, 20, 20), Pattern::BLACK, Mode::Xor);
+    assert_eq!(canvas.bytes(), &[0xff, 0b1100_0011, 0b1101_1011, 0xff]);
+}
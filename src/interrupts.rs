@@ -1,28 +1,42 @@
 use core::arch::asm;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
-use crate::{print, println, gdt, hlt_loop};
-use spin;
+use crate::{print, emergency_print, emergency_println, gdt, hlt_loop};
+use crate::backtrace::Backtrace;
+use crate::sync::Mutex;
 use pic8259::ChainedPics;
 use lazy_static::lazy_static;
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 use x86_64::instructions::port::Port;
 use crate::vga_buffer;
-use crate::key_conversion::{KEYMAP_DE};
+use crate::{gdb, monitor, profiler, trace, watchdog};
+use crate::serial::{ComPort, Role};
+use crate::ps2::Ps2Port;
+use x86_64::VirtAddr;
 
 pub static mut MILLISECONDS_ELAPSED: u64 = 0;
 pub static PIT_MS_PER_INTERRUPT: u32 = 1;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+///Milliseconds counted by the timer interrupt since it was started.
+pub fn milliseconds_elapsed() -> u64{
+    unsafe{MILLISECONDS_ELAPSED}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 ///Index in the PIC for various devices.
 pub enum InterruptIndex{
     Timer = PIC_1_OFFSET,
     Keyboard,
+    ///COM2 and COM4 (IRQ 3).
+    Com2 = PIC_1_OFFSET + 3,
+    ///COM1 and COM3 (IRQ 4).
+    Com1,
+    Mouse = PIC_1_OFFSET + 12,
 }
 // Helper functions for the Interrupt index.
 impl InterruptIndex{
     fn as_u8(&self)-> u8{
-        self as u8
+        *self as u8
     }
     fn as_usize(&self)->usize{
         usize::from(self.as_u8())
@@ -32,7 +46,10 @@ lazy_static!{
     ///The global IDT.
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        unsafe{
+            idt.breakpoint.set_handler_addr(VirtAddr::new(breakpoint_entry as *const () as u64));
+            idt.debug.set_handler_addr(VirtAddr::new(debug_entry as *const () as u64));
+        }
         unsafe{
             idt.double_fault.set_handler_fn(double_fault_handler)
             .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
@@ -40,33 +57,197 @@ lazy_static!{
         idt.page_fault.set_handler_fn(page_fault_handler);
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Com2.as_usize()].set_handler_fn(com2_interrupt_handler);
+        idt[InterruptIndex::Com1.as_usize()].set_handler_fn(com1_interrupt_handler);
+        idt[InterruptIndex::Mouse.as_usize()].set_handler_fn(mouse_interrupt_handler);
         idt
     };
 }
 //Handle PIC Timer interrupts.
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame){
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame){
+    trace::IRQ_ENTER.hit(InterruptIndex::Timer.as_u8() as u64, 0);
+    if profiler::tick(){
+        profiler::record(&Backtrace::interrupted(stack_frame.instruction_pointer.as_u64()));
+    }
     unsafe{
         MILLISECONDS_ELAPSED += PIT_MS_PER_INTERRUPT as u64;
         PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+    if let Some(problem) = watchdog::tick(){
+        watchdog::report(problem, Some(&Backtrace::interrupted(stack_frame.instruction_pointer.as_u64())));
+    }
+    trace::IRQ_EXIT.hit(InterruptIndex::Timer.as_u8() as u64, 0);
 }
-///Handle keyboard interrupts.
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame){
-    use x86_64::instructions::port::Port;
-    let mut port = Port::new(0x60);
-    let scancode : u8 = unsafe{port.read()};
-    crate::task::keyboard::add_scancode(scancode);
+///Handle keyboard interrupts. SysRq stops the interrupted code in the monitor after its next instruction.
+extern "x86-interrupt" fn keyboard_interrupt_handler(mut stack_frame: InterruptStackFrame){
+    trace::IRQ_ENTER.hit(InterruptIndex::Keyboard.as_u8() as u64, 0);
+    if let Some(scancode) = crate::ps2::read_data_if_ready(Ps2Port::First){
+        if scancode == monitor::HOTKEY_SCANCODE{
+            monitor::request();
+            unsafe{stack_frame.as_mut().update(|frame| frame.cpu_flags |= TRAP_FLAG)};
+        } else {
+            crate::task::keyboard::add_scancode(scancode);
+        }
+    }
     unsafe{
         PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
     }
+    trace::IRQ_EXIT.hit(InterruptIndex::Keyboard.as_u8() as u64, 0);
+}
+///Handle mouse interrupts (IRQ 12).
+extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame){
+    trace::IRQ_ENTER.hit(InterruptIndex::Mouse.as_u8() as u64, 0);
+    if let Some(byte) = crate::ps2::read_data_if_ready(Ps2Port::Second){
+        crate::task::mouse::add_mouse_byte(byte);
+    }
+    unsafe{
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::Mouse.as_u8());
+    }
+    trace::IRQ_EXIT.hit(InterruptIndex::Mouse.as_u8() as u64, 0);
+}
+///Queue the bytes received on the given ports, which share an IRQ line. Bytes for the debugger stub go to it
+///instead, and when it wants to break in, the interrupted code stops after its next instruction.
+fn receive_serial(ports: [ComPort; 2], stack_frame: &mut InterruptStackFrame){
+    let debugger = crate::serial::port_for(Role::Debugger);
+    for port in ports{
+        while let Some(byte) = crate::serial::read_byte_if_ready(port){
+            if Some(port) != debugger{
+                crate::task::serial::add_byte(port, byte);
+            } else if gdb::receive_byte(byte){
+                unsafe{stack_frame.as_mut().update(|frame| frame.cpu_flags |= TRAP_FLAG)};
+            }
+        }
+    }
+}
+///Handle COM1 and COM3 interrupts, which are only enabled for received data.
+extern "x86-interrupt" fn com1_interrupt_handler(mut stack_frame: InterruptStackFrame){
+    trace::IRQ_ENTER.hit(InterruptIndex::Com1.as_u8() as u64, 0);
+    receive_serial([ComPort::Com1, ComPort::Com3], &mut stack_frame);
+    unsafe{
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::Com1.as_u8());
+    }
+    trace::IRQ_EXIT.hit(InterruptIndex::Com1.as_u8() as u64, 0);
+}
+///Handle COM2 and COM4 interrupts, which are only enabled for received data.
+extern "x86-interrupt" fn com2_interrupt_handler(mut stack_frame: InterruptStackFrame){
+    trace::IRQ_ENTER.hit(InterruptIndex::Com2.as_u8() as u64, 0);
+    receive_serial([ComPort::Com2, ComPort::Com4], &mut stack_frame);
+    unsafe{
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::Com2.as_u8());
+    }
+    trace::IRQ_EXIT.hit(InterruptIndex::Com2.as_u8() as u64, 0);
 }
 ///Initialize the Interrupt Descriptor Table.
 pub fn init_idt(){
     IDT.load();
 }
+
+///Trap flag in RFLAGS, which raises a debug exception after the next instruction.
+pub const TRAP_FLAG: u64 = 1 << 8;
+
+///Registers of the code a breakpoint or debug exception interrupted, saved by `trap_entry`. The code resumes with
+///whatever the handler leaves in here.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct TrapFrame{
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    ///Exception vector.
+    pub vector: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+extern "C"{
+    fn breakpoint_entry();
+    fn debug_entry();
+}
+//The x86-interrupt handlers only see the interrupt stack frame, while a debugger needs all registers: these
+//entries push the vector and the general purpose registers below the frame, forming a `TrapFrame`.
+core::arch::global_asm!(
+    ".global breakpoint_entry",
+    "breakpoint_entry:",
+    "    push 3",
+    "    jmp trap_entry",
+    ".global debug_entry",
+    "debug_entry:",
+    "    push 1",
+    "trap_entry:",
+    "    push r15",
+    "    push r14",
+    "    push r13",
+    "    push r12",
+    "    push r11",
+    "    push r10",
+    "    push r9",
+    "    push r8",
+    "    push rbp",
+    "    push rdi",
+    "    push rsi",
+    "    push rdx",
+    "    push rcx",
+    "    push rbx",
+    "    push rax",
+    "    mov rdi, rsp",
+    "    cld",
+    //the CPU aligned the stack before pushing the 5 words of the interrupt frame, 16 more were pushed here
+    "    sub rsp, 8",
+    "    call {handler}",
+    "    add rsp, 8",
+    "    pop rax",
+    "    pop rbx",
+    "    pop rcx",
+    "    pop rdx",
+    "    pop rsi",
+    "    pop rdi",
+    "    pop rbp",
+    "    pop r8",
+    "    pop r9",
+    "    pop r10",
+    "    pop r11",
+    "    pop r12",
+    "    pop r13",
+    "    pop r14",
+    "    pop r15",
+    "    add rsp, 8",
+    "    iretq",
+    handler = sym trap_handler,
+);
+extern "C" fn trap_handler(frame: &mut TrapFrame){
+    match frame.vector{
+        3 => breakpoint_handler(frame),
+        _ => debug_handler(frame),
+    }
+    //the time spent stopped in a debugger is not the stopped code's fault
+    watchdog::reset();
+}
 ///Handler for breakpoint exceptions.
-extern "x86-interrupt" fn breakpoint_handler(stack_frame:InterruptStackFrame){
-    println!("Exception: Breakpoint \n {:#?}", stack_frame);
+fn breakpoint_handler(frame: &mut TrapFrame){
+    if !gdb::handle_trap(frame) && !monitor::handle_trap(frame){
+        log::info!("Exception: Breakpoint\n{:#x?}", frame);
+    }
+}
+///Handler for debug exceptions, raised by single steps.
+fn debug_handler(frame: &mut TrapFrame){
+    if !gdb::handle_trap(frame) && !monitor::handle_trap(frame){
+        frame.rflags &= !TRAP_FLAG;
+    }
 }
 ///Double fault handler.
 extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) -> !{
@@ -76,10 +257,12 @@ use x86_64::structures::idt::PageFaultErrorCode;
 
 extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode){
     use x86_64::registers::control::Cr2;
-    println!("PAGE FAULT");
-    println!("Accessed address: {:?}", Cr2::read());
-    println!("Error code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
+    trace::PAGE_FAULT.hit(Cr2::read().as_u64(), error_code.bits());
+    emergency_println!("PAGE FAULT");
+    emergency_println!("Accessed address: {:?}", Cr2::read());
+    emergency_println!("Error code: {:?}", error_code);
+    emergency_println!("{:#?}", stack_frame);
+    emergency_print!("{}", Backtrace::interrupted(stack_frame.instruction_pointer.as_u64()));
     hlt_loop();
 }
 
@@ -87,7 +270,23 @@ pub const PIC_1_OFFSET:u8 = 32;
 pub const PIC_2_OFFSET:u8 = PIC_1_OFFSET + 8;
 
 ///Mutex struct representing the 8259 PICs 1 and 2.
-pub static PICS:spin::Mutex<ChainedPics> = spin::Mutex::new(unsafe{ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)});
+pub static PICS: Mutex<ChainedPics> = Mutex::new(unsafe{ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)});
+
+///Unmask the IRQ line of the given device in the PICs, including the cascade line for the secondary PIC.
+pub fn unmask_irq(index: InterruptIndex){
+    let irq = index.as_u8() - PIC_1_OFFSET;
+    let mut pics = PICS.lock();
+    unsafe{
+        let [mut mask1, mut mask2] = pics.read_masks();
+        if irq < 8{
+            mask1 &= !(1 << irq);
+        } else {
+            mask1 &= !(1 << 2);
+            mask2 &= !(1 << (irq - 8));
+        }
+        pics.write_masks(mask1, mask2);
+    }
+}
 
 ///Initialise the PIT to send an interrupt at the given frequency in Hz (times per second).
 pub fn init_pit(frequency_in_hz: u32){
@@ -1,46 +1,563 @@
-//This is a giant hack but hopefully it works
+//! Keyboard layouts and the global layout registry.
+//!
+//! Layouts are applied when a `KeyCode` is turned into a character, so AltGr, dead keys and the
+//! shifted number row come out right for the selected layout. Keys a layout does not override
+//! fall through to its base `pc_keyboard` layout.
+//!
+//! Layouts are described by keymap files (see fs/keymaps/README.txt for the format). The ones in fs/keymaps are
+//! built in; others are loaded at runtime with the shell's `loadkeys` command.
 
 use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
 use lazy_static::lazy_static;
-use crate::println;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, KeyEvent, KeyState, KeyboardLayout, Modifiers,
+                  ScancodeSet, ScancodeSet1};
+use crate::sync::Mutex;
 
-#[derive(Clone)]
+///Modifier level a key mapping applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum KeyLevel{
+    Normal,
+    Shift,
+    AltGr,
+}
+
+///What a key produces on a given level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyOutput{
+    ///Produce the character directly.
+    Char(char),
+    ///Dead key; combined with the next character through the layout's dead key table.
+    Dead(char),
+}
+
+///`pc_keyboard` layout used for keys a `KeyConversionLayout` does not map itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BaseLayout{
+    Us104,
+    Uk105,
+}
+impl BaseLayout{
+    fn map_keycode(&self, keycode: KeyCode, modifiers: &Modifiers) -> DecodedKey{
+        match self{
+            BaseLayout::Us104 => layouts::Us104Key::map_keycode(keycode, modifiers, HandleControl::Ignore),
+            BaseLayout::Uk105 => layouts::Uk105Key::map_keycode(keycode, modifiers, HandleControl::Ignore),
+        }
+    }
+}
+
+///A keyboard layout: per-key overrides on top of a base layout, plus dead key tables.
+#[derive(Debug, Clone)]
 pub struct KeyConversionLayout{
-    name: &'static str,
-    code: &'static str,
-    mapping: BTreeMap<char, char>
+    name: String,
+    code: String,
+    base: BaseLayout,
+    mapping: BTreeMap<(KeyCode, KeyLevel), KeyOutput>,
+    dead_keys: BTreeMap<char, BTreeMap<char, char>>,
 }
 impl KeyConversionLayout{
-    pub fn convert_char(&self, old: char) -> char{
-        match self.mapping.get(&old) {
-            Some(c) => *c,
-            None => old
+    ///Create a layout without any overrides.
+    pub fn new(name: &str, code: &str, base: BaseLayout) -> KeyConversionLayout{
+        KeyConversionLayout{
+            name: String::from(name),
+            code: String::from(code),
+            base,
+            mapping: BTreeMap::new(),
+            dead_keys: BTreeMap::new(),
+        }
+    }
+    ///Map a key on the given level.
+    pub fn with_key(mut self, keycode: KeyCode, level: KeyLevel, output: KeyOutput) -> Self{
+        self.mapping.insert((keycode, level), output);
+        self
+    }
+    ///Add (base, composed) pairs to the table of the given dead key.
+    pub fn with_dead_key(mut self, dead: char, pairs: &[(char, char)]) -> Self{
+        let table = self.dead_keys.entry(dead).or_default();
+        for &(base, composed) in pairs{
+            table.insert(base, composed);
+        }
+        self
+    }
+    ///Human readable name of the layout.
+    pub fn name(&self) -> &str{
+        &self.name
+    }
+    ///Short code the layout is registered under, e.g. "de".
+    pub fn code(&self) -> &str{
+        &self.code
+    }
+    ///Map a key code to its output under the given modifiers. None for keys without a character.
+    pub fn map_keycode(&self, keycode: KeyCode, modifiers: &Modifiers) -> Option<KeyOutput>{
+        let level = if modifiers.alt_gr {
+            KeyLevel::AltGr
+        } else if modifiers.is_shifted() {
+            KeyLevel::Shift
+        } else {
+            KeyLevel::Normal
+        };
+        //caps lock uppercases letters on keys the layout maps itself, leaving their other levels alone; shift
+        //undoes it for keys whose shift level is the uppercase letter
+        if modifiers.capslock && level != KeyLevel::AltGr{
+            if let Some(&KeyOutput::Char(normal)) = self.mapping.get(&(keycode, KeyLevel::Normal)){
+                let upper = single_uppercase(normal);
+                match (level, self.mapping.get(&(keycode, KeyLevel::Shift))){
+                    (KeyLevel::Normal, _) if upper != normal => return Some(KeyOutput::Char(upper)),
+                    (KeyLevel::Shift, Some(&KeyOutput::Char(shifted))) if upper != normal && shifted == upper =>
+                        return Some(KeyOutput::Char(normal)),
+                    _ => {},
+                }
+            }
+        }
+        if let Some(output) = self.mapping.get(&(keycode, level)){
+            return Some(*output);
+        }
+        match self.base.map_keycode(keycode, modifiers){
+            DecodedKey::Unicode(c) => Some(KeyOutput::Char(c)),
+            DecodedKey::RawKey(_) => None,
+        }
+    }
+    ///Combine a dead key with the following character, if the layout knows the combination.
+    pub fn compose(&self, dead: char, c: char) -> Option<char>{
+        self.dead_keys.get(&dead).and_then(|table| table.get(&c)).copied()
+    }
+    ///Parse a layout from the text of a keymap file.
+    pub fn parse(source: &str) -> Result<KeyConversionLayout, ParseError>{
+        let mut layout = KeyConversionLayout::new("", "", BaseLayout::Us104);
+        let (mut has_name, mut has_code) = (false, false);
+        for (index, line) in source.lines().enumerate(){
+            let error = |kind| ParseError{line: index + 1, kind};
+            let line = match line.find('#'){
+                Some(comment) => &line[..comment],
+                None => line,
+            }.trim();
+            let mut tokens = line.split_whitespace();
+            let first = match tokens.next(){
+                Some(token) => token,
+                None => continue,
+            };
+            match first{
+                "name" | "code" | "base" => {
+                    let value = line[first.len()..].trim_start()
+                        .strip_prefix('=')
+                        .ok_or(error(ParseErrorKind::BadLine))?
+                        .trim();
+                    match first{
+                        "name" => {layout.name = String::from(value); has_name = true;},
+                        "code" => {layout.code = String::from(value); has_code = true;},
+                        _ => layout.base = match value{
+                            "us104" => BaseLayout::Us104,
+                            "uk105" => BaseLayout::Uk105,
+                            _ => return Err(error(ParseErrorKind::UnknownBase)),
+                        },
+                    }
+                },
+                "dead" => {
+                    let dead = tokens.next()
+                        .and_then(parse_char)
+                        .ok_or(error(ParseErrorKind::BadCharacter))?;
+                    let table = layout.dead_keys.entry(dead).or_default();
+                    while let Some(base) = tokens.next(){
+                        let composed = tokens.next().ok_or(error(ParseErrorKind::UnpairedDeadKey))?;
+                        match (parse_char(base), parse_char(composed)){
+                            (Some(base), Some(composed)) => {table.insert(base, composed);},
+                            _ => return Err(error(ParseErrorKind::BadCharacter)),
+                        }
+                    }
+                },
+                scancode => {
+                    let keycode = parse_scancode(scancode).ok_or(error(ParseErrorKind::BadScancode))?;
+                    let mut levels = [KeyLevel::Normal, KeyLevel::Shift, KeyLevel::AltGr].into_iter();
+                    let mut has_output = false;
+                    for token in tokens{
+                        let level = levels.next().ok_or(error(ParseErrorKind::TooManyLevels))?;
+                        has_output = true;
+                        if token == "-"{
+                            continue;
+                        }
+                        let output = match token.strip_prefix("d:"){
+                            Some(dead) => parse_char(dead).map(KeyOutput::Dead),
+                            None => parse_char(token).map(KeyOutput::Char),
+                        }.ok_or(error(ParseErrorKind::BadCharacter))?;
+                        layout.mapping.insert((keycode, level), output);
+                    }
+                    if !has_output{
+                        return Err(error(ParseErrorKind::BadLine));
+                    }
+                },
+            }
         }
+        let end = source.lines().count();
+        if !has_name{
+            return Err(ParseError{line: end, kind: ParseErrorKind::MissingHeader("name")});
+        }
+        if !has_code{
+            return Err(ParseError{line: end, kind: ParseErrorKind::MissingHeader("code")});
+        }
+        Ok(layout)
     }
 }
-lazy_static!{
-    pub static ref KEYMAP_DE: KeyConversionLayout = KeyConversionLayout{
-        name: "German (Standard)",
-        code: "de",
-        mapping: BTreeMap::from([
-            ('~', '°'),
-            ('`', '^'),
-            ('@', '"'), //2 key
-            ('#', '§'), //3 key
-            ('^', '&'), //6 key
-            ('&', '/'), //7 key
-            ('*', '('), //8 key
-            ('(', ')'), //9 key
-            (')', '='), //ß key
-            ('-', 'ß'),
-            (0x5f as char, '?'),
-        ])
-    };
+
+///Uppercase of a character, if it is a single character; 'ß' stays as it is rather than becoming "SS".
+fn single_uppercase(c: char) -> char{
+    let mut upper = c.to_uppercase();
+    match (upper.next(), upper.next()){
+        (Some(upper), None) => upper,
+        _ => c,
+    }
 }
+
+///Parse a scancode set 1 make code written as 0xXX, or e0XX for extended keys.
+fn parse_scancode(token: &str) -> Option<KeyCode>{
+    if let Some(hex) = token.strip_prefix("e0"){
+        let code = u8::from_str_radix(hex, 16).ok()?;
+        ScancodeSet1::map_extended_scancode(code).ok()
+    } else {
+        let code = u8::from_str_radix(token.strip_prefix("0x")?, 16).ok()?;
+        ScancodeSet1::map_scancode(code).ok()
+    }
+}
+///Parse a character written literally, as U+XXXX or as "space".
+fn parse_char(token: &str) -> Option<char>{
+    if token == "space"{
+        return Some(' ');
+    }
+    if let Some(hex) = token.strip_prefix("U+"){
+        return u32::from_str_radix(hex, 16).ok().and_then(char::from_u32);
+    }
+    let mut chars = token.chars();
+    match (chars.next(), chars.next()){
+        (Some(c), None) => Some(c),
+        _ => None,
+    }
+}
+
+///Error produced while parsing a keymap file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError{
+    ///1-based line the error occurred on.
+    pub line: usize,
+    pub kind: ParseErrorKind,
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind{
+    ///A required header field is missing.
+    MissingHeader(&'static str),
+    ///The line is neither a header, a key nor a dead key table.
+    BadLine,
+    ///The base layout is not one of us104 or uk105.
+    UnknownBase,
+    ///The scancode is malformed or unknown to scancode set 1.
+    BadScancode,
+    ///A character is neither a single character, U+XXXX nor "space".
+    BadCharacter,
+    ///A key line lists more than normal, shift and AltGr outputs.
+    TooManyLevels,
+    ///A dead key table has a base character without a composed one.
+    UnpairedDeadKey,
+}
+
+///Error returned when selecting a layout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LayoutError{
+    ///No layout is registered under the given code.
+    UnknownLayout,
+}
+
 lazy_static!{
-    pub static ref KEYMAP_US: KeyConversionLayout = KeyConversionLayout{
-        name: "US",
-        code: "us",
-        mapping: BTreeMap::new(),
+    ///All known layouts, keyed by their code.
+    static ref LAYOUTS: Mutex<BTreeMap<String, KeyConversionLayout>> = {
+        let mut layouts = BTreeMap::new();
+        for source in BUILTIN_KEYMAPS{
+            let layout = KeyConversionLayout::parse(source).expect("built-in keymap failed to parse");
+            layouts.insert(String::from(layout.code()), layout);
+        }
+        Mutex::new(layouts)
     };
+    ///Code of the layout used for decoding key presses.
+    static ref CURRENT_LAYOUT: Mutex<String> = Mutex::new(String::from("de"));
+}
+
+///Keymap files from fs/keymaps, built into the kernel as it cannot read them from disk.
+static BUILTIN_KEYMAPS: &[&str] = &[
+    include_str!("../fs/keymaps/us.kmap"),
+    include_str!("../fs/keymaps/uk.kmap"),
+    include_str!("../fs/keymaps/de.kmap"),
+    include_str!("../fs/keymaps/ch.kmap"),
+    include_str!("../fs/keymaps/fr.kmap"),
+    include_str!("../fs/keymaps/dvorak.kmap"),
+];
+
+///Add a layout to the registry, replacing any layout with the same code.
+pub fn register_layout(layout: KeyConversionLayout){
+    LAYOUTS.lock().insert(String::from(layout.code()), layout);
+}
+///Parse a keymap file and register the layout. Returns the code of the loaded layout.
+pub fn load_layout(source: &str) -> Result<String, ParseError>{
+    let layout = KeyConversionLayout::parse(source)?;
+    let code = String::from(layout.code());
+    register_layout(layout);
+    Ok(code)
+}
+///Switch the global layout to the one registered under `code`.
+pub fn set_layout(code: &str) -> Result<(), LayoutError>{
+    if !LAYOUTS.lock().contains_key(code){
+        return Err(LayoutError::UnknownLayout);
+    }
+    *CURRENT_LAYOUT.lock() = String::from(code);
+    Ok(())
+}
+///Code of the currently active layout.
+pub fn current_layout() -> String{
+    CURRENT_LAYOUT.lock().clone()
+}
+///Switch to the next registered layout (by code) and return its code.
+pub fn cycle_layout() -> String{
+    let layouts = LAYOUTS.lock();
+    let mut current = CURRENT_LAYOUT.lock();
+    let next = layouts.keys()
+        .find(|code| code.as_str() > current.as_str())
+        .or_else(|| layouts.keys().next())
+        .cloned()
+        .unwrap_or_else(|| current.clone());
+    *current = next.clone();
+    next
+}
+///(code, name) of every registered layout.
+pub fn available_layouts() -> Vec<(String, String)>{
+    LAYOUTS.lock().values()
+        .map(|layout| (String::from(layout.code()), String::from(layout.name())))
+        .collect()
+}
+///Run `f` with the currently active layout.
+pub fn with_current_layout<R>(f: impl FnOnce(&KeyConversionLayout) -> R) -> R{
+    let code = current_layout();
+    let layouts = LAYOUTS.lock();
+    match layouts.get(&code){
+        Some(layout) => f(layout),
+        None => f(&KeyConversionLayout::new("US", "us", BaseLayout::Us104)),
+    }
+}
+
+///Set of held modifier keys. Left and right variants are not distinguished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ModifierMask(u8);
+impl ModifierMask{
+    pub const NONE: ModifierMask = ModifierMask(0);
+    pub const SHIFT: ModifierMask = ModifierMask(1);
+    pub const CTRL: ModifierMask = ModifierMask(1 << 1);
+    pub const ALT: ModifierMask = ModifierMask(1 << 2);
+    pub const ALT_GR: ModifierMask = ModifierMask(1 << 3);
+    pub const META: ModifierMask = ModifierMask(1 << 4);
+    ///Whether every modifier in `other` is held.
+    pub fn contains(self, other: ModifierMask) -> bool{
+        self.0 & other.0 == other.0
+    }
+}
+impl core::ops::BitOr for ModifierMask{
+    type Output = ModifierMask;
+    fn bitor(self, other: ModifierMask) -> ModifierMask{
+        ModifierMask(self.0 | other.0)
+    }
+}
+
+///State of the lock keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LockState{
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+///Turns key events into decoded keys using the global layout, tracking modifiers and dead keys.
+pub struct KeyDecoder{
+    modifiers: Modifiers,
+    alt: bool,
+    meta: bool,
+    scroll_lock: bool,
+    pending_dead_key: Option<char>,
+    queued: Option<DecodedKey>,
+}
+impl KeyDecoder{
+    pub fn new() -> KeyDecoder{
+        KeyDecoder{
+            modifiers: Modifiers{
+                lshift: false,
+                rshift: false,
+                lctrl: false,
+                rctrl: false,
+                numlock: true,
+                capslock: false,
+                alt_gr: false,
+            },
+            alt: false,
+            meta: false,
+            scroll_lock: false,
+            pending_dead_key: None,
+            queued: None,
+        }
+    }
+    ///Modifier keys currently held.
+    pub fn modifier_mask(&self) -> ModifierMask{
+        let mut mask = ModifierMask::NONE;
+        for (held, modifier) in [
+            (self.modifiers.is_shifted(), ModifierMask::SHIFT),
+            (self.modifiers.is_ctrl(), ModifierMask::CTRL),
+            (self.alt, ModifierMask::ALT),
+            (self.modifiers.alt_gr, ModifierMask::ALT_GR),
+            (self.meta, ModifierMask::META),
+        ]{
+            if held{
+                mask = mask | modifier;
+            }
+        }
+        mask
+    }
+    ///Current state of the lock keys.
+    pub fn lock_state(&self) -> LockState{
+        LockState{
+            caps_lock: self.modifiers.capslock,
+            num_lock: self.modifiers.numlock,
+            scroll_lock: self.scroll_lock,
+        }
+    }
+    ///Process a key event. Returns the decoded key for presses that produce one.
+    pub fn process_keyevent(&mut self, event: KeyEvent) -> Option<DecodedKey>{
+        let down = event.state == KeyState::Down;
+        match event.code{
+            KeyCode::ShiftLeft => {self.modifiers.lshift = down; None},
+            KeyCode::ShiftRight => {self.modifiers.rshift = down; None},
+            KeyCode::AltLeft => {self.alt = down; None},
+            KeyCode::AltRight => {self.modifiers.alt_gr = down; None},
+            KeyCode::ControlLeft => {self.modifiers.lctrl = down; None},
+            KeyCode::ControlRight => {self.modifiers.rctrl = down; None},
+            KeyCode::WindowsLeft | KeyCode::WindowsRight => {self.meta = down; None},
+            KeyCode::CapsLock => {
+                if down {self.modifiers.capslock = !self.modifiers.capslock;}
+                None
+            },
+            KeyCode::NumpadLock => {
+                if down {self.modifiers.numlock = !self.modifiers.numlock;}
+                None
+            },
+            KeyCode::ScrollLock => {
+                if down {self.scroll_lock = !self.scroll_lock;}
+                None
+            },
+            code if down => self.decode(code),
+            _ => None,
+        }
+    }
+    ///Key left over from the last key event, which produced two: a dead key that did not compose with the key
+    ///typed after it gives its accent, then that key.
+    pub fn take_queued(&mut self) -> Option<DecodedKey>{
+        self.queued.take()
+    }
+    fn decode(&mut self, keycode: KeyCode) -> Option<DecodedKey>{
+        let pending_dead_key = self.pending_dead_key.take();
+        let modifiers = &self.modifiers;
+        let (output, composed, spacing) = with_current_layout(|layout|{
+            let output = layout.map_keycode(keycode, modifiers);
+            let composed = match (pending_dead_key, output){
+                (Some(dead), Some(KeyOutput::Char(c))) => layout.compose(dead, c),
+                _ => None,
+            };
+            //a dead key on its own stands for its accent, which the table lists as composed with space
+            let spacing = pending_dead_key.map(|dead| layout.compose(dead, ' ').unwrap_or(dead));
+            (output, composed, spacing)
+        });
+        let key = match output{
+            Some(KeyOutput::Char(c)) => DecodedKey::Unicode(composed.unwrap_or(c)),
+            //typing a dead key twice gives its accent
+            Some(KeyOutput::Dead(dead)) if pending_dead_key == Some(dead) => return spacing.map(DecodedKey::Unicode),
+            Some(KeyOutput::Dead(dead)) => {
+                self.pending_dead_key = Some(dead);
+                return spacing.map(DecodedKey::Unicode);
+            },
+            None => DecodedKey::RawKey(keycode),
+        };
+        match spacing{
+            Some(spacing) if composed.is_none() => {
+                self.queued = Some(key);
+                Some(DecodedKey::Unicode(spacing))
+            },
+            _ => Some(key),
+        }
+    }
+}
+impl Default for KeyDecoder{
+    fn default() -> KeyDecoder{
+        KeyDecoder::new()
+    }
+}
+
+//----------TEST CASES------------
+#[test_case]
+fn de_number_row_and_altgr(){
+    let layout = KeyConversionLayout::parse(include_str!("../fs/keymaps/de.kmap")).unwrap();
+    let mut decoder = KeyDecoder::new();
+    decoder.modifiers.lshift = true;
+    assert_eq!(layout.map_keycode(KeyCode::Key3, &decoder.modifiers), Some(KeyOutput::Char('§')));
+    decoder.modifiers.lshift = false;
+    decoder.modifiers.alt_gr = true;
+    assert_eq!(layout.map_keycode(KeyCode::Q, &decoder.modifiers), Some(KeyOutput::Char('@')));
+    decoder.modifiers.alt_gr = false;
+    decoder.modifiers.capslock = true;
+    assert_eq!(layout.map_keycode(KeyCode::Quote, &decoder.modifiers), Some(KeyOutput::Char('Ä')));
+}
+#[test_case]
+fn de_dead_key_composes(){
+    let layout = KeyConversionLayout::parse(include_str!("../fs/keymaps/de.kmap")).unwrap();
+    assert_eq!(layout.compose('^', 'o'), Some('ô'));
+    assert_eq!(layout.compose('´', 'x'), None);
+}
+#[test_case]
+fn caps_lock_uppercases_normal_level(){
+    let de = KeyConversionLayout::parse(include_str!("../fs/keymaps/de.kmap")).unwrap();
+    let ch = KeyConversionLayout::parse(include_str!("../fs/keymaps/ch.kmap")).unwrap();
+    let mut decoder = KeyDecoder::new();
+    decoder.modifiers.capslock = true;
+    assert_eq!(de.map_keycode(KeyCode::Minus, &decoder.modifiers), Some(KeyOutput::Char('ß')));
+    assert_eq!(ch.map_keycode(KeyCode::BracketSquareLeft, &decoder.modifiers), Some(KeyOutput::Char('Ü')));
+    assert_eq!(ch.map_keycode(KeyCode::SemiColon, &decoder.modifiers), Some(KeyOutput::Char('Ö')));
+    decoder.modifiers.lshift = true;
+    assert_eq!(ch.map_keycode(KeyCode::BracketSquareLeft, &decoder.modifiers), Some(KeyOutput::Char('è')));
+    assert_eq!(de.map_keycode(KeyCode::Quote, &decoder.modifiers), Some(KeyOutput::Char('ä')));
+}
+#[test_case]
+fn dead_key_without_composition(){
+    let previous = current_layout();
+    set_layout("de").unwrap();
+    let mut decoder = KeyDecoder::new();
+    let mut press = |code| decoder.process_keyevent(KeyEvent::new(code, KeyState::Down));
+    //^ then x gives the accent, then x
+    assert_eq!(press(KeyCode::BackTick), None);
+    assert_eq!(press(KeyCode::X), Some(DecodedKey::Unicode('^')));
+    assert_eq!(decoder.take_queued(), Some(DecodedKey::Unicode('x')));
+    let mut press = |code| decoder.process_keyevent(KeyEvent::new(code, KeyState::Down));
+    //^ twice or ^ then space give the accent alone
+    assert_eq!(press(KeyCode::BackTick), None);
+    assert_eq!(press(KeyCode::BackTick), Some(DecodedKey::Unicode('^')));
+    assert_eq!(press(KeyCode::BackTick), None);
+    assert_eq!(press(KeyCode::Spacebar), Some(DecodedKey::Unicode('^')));
+    //another dead key gives the first accent and waits for the second one's base
+    assert_eq!(press(KeyCode::BackTick), None);
+    assert_eq!(press(KeyCode::Equals), Some(DecodedKey::Unicode('^')));
+    assert_eq!(press(KeyCode::E), Some(DecodedKey::Unicode('é')));
+    assert_eq!(decoder.take_queued(), None);
+    set_layout(&previous).unwrap();
+}
+#[test_case]
+fn builtin_keymaps_parse(){
+    for source in BUILTIN_KEYMAPS{
+        assert!(KeyConversionLayout::parse(source).is_ok());
+    }
+}
+#[test_case]
+fn parse_errors_report_line(){
+    let error = KeyConversionLayout::parse("name = Broken\ncode = xx\n0x10 a A @ !\n").unwrap_err();
+    assert_eq!(error, ParseError{line: 3, kind: ParseErrorKind::TooManyLevels});
+    let error = KeyConversionLayout::parse("name = Broken\n0x10 a\n").unwrap_err();
+    assert_eq!(error.kind, ParseErrorKind::MissingHeader("code"));
 }
@@ -6,8 +6,26 @@
 #![feature(abi_x86_interrupt)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
+pub mod sync;
+pub mod ring;
+pub mod emergency;
+pub mod backtrace;
+pub mod gdb;
+pub mod monitor;
+pub mod profiler;
+pub mod watchdog;
+pub mod trace;
 pub mod vga_buffer;
+pub mod ansi;
+pub mod framebuffer;
+pub mod font;
+pub mod fb_console;
+pub mod graphics;
+pub mod desktop;
 pub mod serial;
+pub mod logger;
+pub mod ps2;
+pub mod pci;
 pub mod interrupts;
 pub mod gdt;
 pub mod memory;
@@ -16,6 +34,7 @@ extern crate alloc;
 pub mod task;
 pub mod key_conversion;
 pub mod storage;
+pub mod shell;
 use core::panic::PanicInfo;
 
 #[cfg(test)]
@@ -40,6 +59,16 @@ pub fn exit_qemu(exit_code : QemuExitCode){
         port.write(exit_code as u32);
     }
 }
+///Reboot by pulsing the CPU reset line through the keyboard controller.
+pub fn reboot() -> !{
+    use x86_64::instructions::port::Port;
+    x86_64::instructions::interrupts::disable();
+    unsafe{
+        let mut port: Port<u8> = Port::new(0x64);
+        port.write(0xfe);
+    }
+    hlt_loop();
+}
 pub trait Testable{
     fn run(&self) -> ();
 }
@@ -61,7 +90,8 @@ pub fn test_runner(tests: &[&dyn Testable]){
     exit_qemu(QemuExitCode::Success);
 }
 pub fn test_panic_handler(info : &PanicInfo) -> !{
-    serial_println!("[failed]; Error: {}", info);
+    emergency_println!("[failed]; Error: {}", info);
+    emergency_print!("{}", backtrace::Backtrace::capture());
     exit_qemu(QemuExitCode::Failure);
     hlt_loop();
 }
@@ -71,15 +101,24 @@ pub fn hlt_loop() -> !{
 }
 pub fn init(boot_info: &'static bootloader::BootInfo){
     vga_buffer::init();
+    logger::init();
     gdt::init();
     interrupts::init_idt();
     interrupts::init_pit((1 / interrupts::PIT_MS_PER_INTERRUPT) * 1000);
     unsafe{interrupts::PICS.lock().initialize()};
+    if let Err(error) = ps2::init(){
+        log::error!("PS/2 controller initialisation failed: {:?}", error);
+    }
+    interrupts::unmask_irq(interrupts::InterruptIndex::Mouse);
+    serial::init();
+    interrupts::unmask_irq(interrupts::InterruptIndex::Com1);
+    interrupts::unmask_irq(interrupts::InterruptIndex::Com2);
     x86_64::instructions::interrupts::enable();
     let phys_mem_off = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe{memory::init(phys_mem_off)};
     let mut frame_allocator = unsafe{memory::BootInfoFrameAllocator::init(&boot_info.memory_map)};
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialisation failed");
+    *memory::MEMORY.lock() = Some(memory::MemoryManager::new(mapper, frame_allocator));
 }
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> !{
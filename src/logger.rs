@@ -0,0 +1,378 @@
+//! The kernel log: a backend for the `log` crate's macros.
+//!
+//! Every record goes into a ring buffer that never blocks, so logging is safe from interrupt handlers. Records
+//! are then handed to the sinks, each with its own level: right away when logging with interrupts enabled,
+//! otherwise by the `write_log` task. `dmesg` shows what is still in the buffer, and the `file` sink keeps a longer
+//! history in memory for `logfile`.
+
+use alloc::string::String;
+use core::fmt::{self, Write};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+use log::{Level, LevelFilter, Log, Metadata};
+use crate::ring::SeqRing;
+use crate::sync::Mutex;
+use x86_64::instructions::interrupts;
+use crate::{println, serial_println};
+
+///Records kept in the ring buffer.
+pub const LOG_CAPACITY: usize = 256;
+///Bytes kept of a record's target and message together; longer messages are cut.
+const TEXT_BYTES: usize = 192;
+const TEXT_WORDS: usize = TEXT_BYTES / 8;
+///Words of a record in the buffer: the timestamp, a header with the level, target length and message length in
+///its lowest three bytes, then the target followed by the message.
+const RECORD_WORDS: usize = 2 + TEXT_WORDS;
+///Bytes kept of a record's target.
+const TARGET_BYTES: usize = 40;
+///Most sinks that can be added.
+const MAX_SINKS: usize = 8;
+///Bytes kept by the log file; the oldest lines are dropped to make room.
+pub const LOG_FILE_BYTES: usize = 64 * 1024;
+///Prefix of the targets of this crate's modules, left out of records.
+const CRATE_PREFIX: &str = concat!(env!("CARGO_CRATE_NAME"), "::");
+
+///Formats into a fixed buffer, cutting at a character boundary once `limit` is reached.
+struct TextWriter{
+    bytes: [u8; TEXT_BYTES],
+    len: usize,
+    limit: usize,
+}
+impl Write for TextWriter{
+    fn write_str(&mut self, s: &str) -> fmt::Result{
+        for c in s.chars(){
+            let len = c.len_utf8();
+            if self.len + len > self.limit{
+                self.limit = self.len;
+                break;
+            }
+            c.encode_utf8(&mut self.bytes[self.len..]);
+            self.len += len;
+        }
+        Ok(())
+    }
+}
+
+///A record read from the log.
+#[derive(Clone)]
+pub struct Record{
+    ///Number of the record since boot.
+    pub sequence: u64,
+    ///Milliseconds since boot.
+    pub timestamp: u64,
+    pub level: Level,
+    target_len: usize,
+    message_len: usize,
+    text: [u8; TEXT_BYTES],
+}
+impl Record{
+    ///Module the record comes from, without the crate name.
+    pub fn target(&self) -> &str{
+        core::str::from_utf8(&self.text[..self.target_len]).unwrap_or("?")
+    }
+    pub fn message(&self) -> &str{
+        core::str::from_utf8(&self.text[self.target_len..self.target_len + self.message_len]).unwrap_or("?")
+    }
+}
+impl fmt::Display for Record{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        write!(f, "[{:>5}.{:03}] {:<5} {}: {}",
+               self.timestamp / 1000, self.timestamp % 1000, self.level, self.target(), self.message())
+    }
+}
+
+///Ring buffer of the last `N` records, written without locks.
+pub struct RingBuffer<const N: usize>{
+    ring: SeqRing<N, RECORD_WORDS>,
+}
+impl<const N: usize> RingBuffer<N>{
+    pub const fn new() -> RingBuffer<N>{
+        RingBuffer{ring: SeqRing::new()}
+    }
+    ///Sequence number the next record will get.
+    pub fn head(&self) -> u64{
+        self.ring.head()
+    }
+    ///Sequence number of the oldest record that may still be in the buffer.
+    pub fn oldest(&self) -> u64{
+        self.ring.oldest()
+    }
+    ///Add a record, overwriting the oldest one once the buffer is full. Returns its sequence number.
+    pub fn push(&self, level: Level, target: &str, timestamp: u64, message: fmt::Arguments) -> u64{
+        let mut writer = TextWriter{bytes: [0; TEXT_BYTES], len: 0, limit: TARGET_BYTES};
+        let _ = writer.write_str(target);
+        let target_len = writer.len;
+        writer.limit = TEXT_BYTES;
+        let _ = writer.write_fmt(message);
+        let message_len = writer.len - target_len;
+
+        let mut words = [0; RECORD_WORDS];
+        words[0] = timestamp;
+        words[1] = level as u64 | (target_len as u64) << 8 | (message_len as u64) << 16;
+        for (word, bytes) in words[2..].iter_mut().zip(writer.bytes.chunks_exact(8)){
+            *word = u64::from_le_bytes(bytes.try_into().unwrap());
+        }
+        self.ring.push(|| words)
+    }
+    ///Read a record; `None` if it was overwritten, is still being written or does not exist yet.
+    pub fn get(&self, sequence: u64) -> Option<Record>{
+        let words = self.ring.get(sequence)?;
+        let (timestamp, header) = (words[0], words[1]);
+        let mut text = [0; TEXT_BYTES];
+        for (word, bytes) in words[2..].iter().zip(text.chunks_exact_mut(8)){
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        let level = match header & 0xff{
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            _ => Level::Trace,
+        };
+        let target_len = ((header >> 8) & 0xff) as usize;
+        let message_len = ((header >> 16) & 0xff) as usize;
+        Some(Record{sequence, timestamp, level, target_len, message_len, text})
+    }
+}
+
+impl<const N: usize> Default for RingBuffer<N>{
+    fn default() -> RingBuffer<N>{
+        RingBuffer::new()
+    }
+}
+
+///Where log records are shown.
+pub trait Sink: Sync{
+    fn write(&self, record: &Record);
+}
+///The active virtual console.
+struct VgaSink;
+impl Sink for VgaSink{
+    fn write(&self, record: &Record){
+        println!("{}", record);
+    }
+}
+///The serial port dedicated to the log.
+struct SerialSink;
+impl Sink for SerialSink{
+    fn write(&self, record: &Record){
+        serial_println!("{}", record);
+    }
+}
+
+///A file in memory holding the newest records that fit in `capacity` bytes.
+struct FileSink{
+    text: Mutex<String>,
+    capacity: usize,
+}
+impl FileSink{
+    const fn new(capacity: usize) -> FileSink{
+        FileSink{text: Mutex::new(String::new()), capacity}
+    }
+    fn contents(&self) -> String{
+        interrupts::without_interrupts(|| self.text.lock().clone())
+    }
+}
+impl Sink for FileSink{
+    fn write(&self, record: &Record){
+        interrupts::without_interrupts(||{
+            let mut text = self.text.lock();
+            let _ = writeln!(text, "{}", record);
+            if text.len() > self.capacity{
+                let excess = text.len() - self.capacity;
+                //drop whole lines, at least `excess` bytes
+                let newline = text.as_bytes()[excess - 1..].iter().position(|&byte| byte == b'\n');
+                let end = newline.map_or(text.len(), |newline| excess + newline);
+                text.drain(..end);
+            }
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+struct SinkEntry{
+    name: &'static str,
+    level: LevelFilter,
+    sink: &'static dyn Sink,
+}
+
+///The kernel log.
+pub static LOG: RingBuffer<LOG_CAPACITY> = RingBuffer::new();
+static LOG_FILE: FileSink = FileSink::new(LOG_FILE_BYTES);
+static SINKS: Mutex<[Option<SinkEntry>; MAX_SINKS]> = Mutex::new([
+    Some(SinkEntry{name: "vga", level: LevelFilter::Info, sink: &VgaSink}),
+    Some(SinkEntry{name: "serial", level: LevelFilter::Info, sink: &SerialSink}),
+    Some(SinkEntry{name: "file", level: LevelFilter::Debug, sink: &LOG_FILE}),
+    None, None, None, None, None,
+]);
+///Sequence number of the next record to hand to the sinks.
+static DELIVERED: AtomicU64 = AtomicU64::new(0);
+///Set while the sinks are written to, so records logged by a sink are only delivered afterwards.
+static FLUSHING: AtomicBool = AtomicBool::new(false);
+///Wakes `write_log` when records are logged with interrupts disabled.
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+struct KernelLogger;
+impl Log for KernelLogger{
+    fn enabled(&self, _metadata: &Metadata) -> bool{
+        true
+    }
+    fn log(&self, record: &log::Record){
+        let target = record.target();
+        let target = target.strip_prefix(CRATE_PREFIX).unwrap_or(target);
+        LOG.push(record.level(), target, crate::interrupts::milliseconds_elapsed(), *record.args());
+        if interrupts::are_enabled(){
+            flush();
+        } else {
+            WAKER.wake();
+        }
+    }
+    fn flush(&self){
+        flush();
+    }
+}
+static LOGGER: KernelLogger = KernelLogger;
+
+///Install the kernel log as the backend of the `log` macros, keeping records of level debug and above.
+pub fn init(){
+    log::set_logger(&LOGGER).expect("a logger is already installed");
+    log::set_max_level(LevelFilter::Debug);
+}
+
+///Hand the records not yet delivered to the sinks. Does nothing when called by a sink.
+pub fn flush(){
+    if FLUSHING.swap(true, Ordering::Acquire){
+        return;
+    }
+    let sinks = *SINKS.lock();
+    loop{
+        let sequence = DELIVERED.load(Ordering::Relaxed).max(LOG.oldest());
+        if sequence >= LOG.head(){
+            break;
+        }
+        match LOG.get(sequence){
+            Some(record) => {
+                for entry in sinks.iter().flatten(){
+                    if record.level <= entry.level{
+                        entry.sink.write(&record);
+                    }
+                }
+            },
+            //overwritten while reading; the next one follows
+            None if sequence < LOG.oldest() => {},
+            //still being written by the code an interrupt handler interrupted; it flushes once done
+            None => break,
+        }
+        DELIVERED.store(sequence + 1, Ordering::Relaxed);
+    }
+    FLUSHING.store(false, Ordering::Release);
+}
+
+///Add a sink showing records up to the given level. Returns false if there are too many sinks already.
+pub fn add_sink(name: &'static str, level: LevelFilter, sink: &'static dyn Sink) -> bool{
+    let mut sinks = SINKS.lock();
+    match sinks.iter_mut().find(|entry| entry.is_none()){
+        Some(free) => {
+            *free = Some(SinkEntry{name, level, sink});
+            true
+        },
+        None => false,
+    }
+}
+///Change the level of a sink. Returns false if there is no sink with the name.
+pub fn set_sink_level(name: &str, level: LevelFilter) -> bool{
+    let mut sinks = SINKS.lock();
+    match sinks.iter_mut().flatten().find(|entry| entry.name == name){
+        Some(entry) => {
+            entry.level = level;
+            true
+        },
+        None => false,
+    }
+}
+///Call `f` with the name and level of every sink.
+pub fn for_each_sink(mut f: impl FnMut(&str, LevelFilter)){
+    let sinks = *SINKS.lock();
+    for entry in sinks.iter().flatten(){
+        f(entry.name, entry.level);
+    }
+}
+
+///Write every record still in the buffer to `out`.
+pub fn dump(out: &mut dyn Write) -> fmt::Result{
+    for sequence in LOG.oldest()..LOG.head(){
+        if let Some(record) = LOG.get(sequence){
+            writeln!(out, "{}", record)?;
+        }
+    }
+    Ok(())
+}
+
+///The log file written by the `file` sink.
+pub fn log_file() -> String{
+    LOG_FILE.contents()
+}
+
+///Deliver the records logged with interrupts disabled, e.g. by interrupt handlers.
+pub async fn write_log(){
+    loop{
+        Pending.await;
+        flush();
+    }
+}
+///Future ready when there are records the sinks have not seen.
+struct Pending;
+impl Future for Pending{
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()>{
+        let pending = || DELIVERED.load(Ordering::Relaxed) < LOG.head();
+        if pending(){
+            return Poll::Ready(());
+        }
+        WAKER.register(cx.waker());
+        if pending(){
+            WAKER.take();
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+//----------TEST CASES------------
+#[test_case]
+fn ring_buffer_wraps_and_cuts(){
+    let buffer: RingBuffer<4> = RingBuffer::new();
+    for number in 0..6{
+        buffer.push(Level::Info, "task::keyboard", 1000 + number, format_args!("record {}", number));
+    }
+    assert_eq!((buffer.oldest(), buffer.head()), (2, 6));
+    assert!(buffer.get(1).is_none());
+    let record = buffer.get(5).unwrap();
+    assert_eq!((record.target(), record.message()), ("task::keyboard", "record 5"));
+    assert_eq!(alloc::format!("{}", record), "[    1.005] INFO  task::keyboard: record 5");
+    //long messages are cut at a character boundary
+    let long = "äöü".repeat(100);
+    let sequence = buffer.push(Level::Warn, "x", 0, format_args!("{}", long));
+    let record = buffer.get(sequence).unwrap();
+    assert_eq!(record.level, Level::Warn);
+    assert_eq!(record.message().len(), TEXT_BYTES - 2);
+    assert!(long.starts_with(record.message()));
+}
+#[test_case]
+fn file_sink_drops_oldest_lines(){
+    let buffer: RingBuffer<4> = RingBuffer::new();
+    let file = FileSink::new(100);
+    for number in 0..4{
+        let sequence = buffer.push(Level::Debug, "logger", 0, format_args!("line {}", number));
+        file.write(&buffer.get(sequence).unwrap());
+    }
+    let contents = file.contents();
+    assert!(contents.len() <= 100);
+    assert_eq!(contents.lines().count(), 3);
+    assert!(contents.starts_with("[    0.000] DEBUG logger: line 1\n"));
+    assert!(contents.ends_with("line 3\n"));
+}
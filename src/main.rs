@@ -7,7 +7,7 @@
 
 extern crate alloc;
 
-use playground_os_rust::{allocator, memory, print, println, serial_print, serial_println};
+use playground_os_rust::{allocator, desktop, emergency_print, emergency_println, logger, memory, print, println, serial_print, serial_println, vga_buffer};
 use bootloader::{BootInfo, entry_point};
 use alloc::boxed::Box;
 
@@ -74,30 +74,40 @@ pub fn kernel_main(boot_info: &'static BootInfo) -> !{
     println!("It did not crash!");
     let mut executor = Executor::new();
     executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(keyboard::print_key_presses()));
+    executor.spawn(Task::new(keyboard::dispatch_key_events()));
+    //console 0 is left to the kernel output
+    for console in 1..vga_buffer::CONSOLE_COUNT{
+        executor.spawn(Task::new(keyboard::console_shell(console)));
+    }
+    executor.spawn(Task::new(logger::write_log()));
+    executor.spawn(Task::new(serial::serial_shell()));
+    executor.spawn(Task::new(desktop::event_loop()));
+    executor.spawn(Task::new(desktop::notepad::run()));
     executor.run();
 }
 
 use core::panic::PanicInfo;
+use playground_os_rust::backtrace::Backtrace;
 use x86_64::VirtAddr;
 use playground_os_rust::memory::translate_addr;
 use playground_os_rust::task::simple_executor::SimpleExecutor;
-use playground_os_rust::task::{keyboard, Task};
+use playground_os_rust::task::{keyboard, serial, Task};
 use playground_os_rust::task::executor::Executor;
 
 #[cfg(not(test))]
 #[panic_handler]
 ///Handle a panic.
 fn panic(info: &PanicInfo) -> ! {
-    println!("Panic!; {}", info);
-    serial_println!("Panic!; {}", info);
-    playground_os_rust::hlt_loop();
+    emergency_println!("Panic!; {}", info);
+    emergency_print!("{}", Backtrace::capture());
+    playground_os_rust::monitor::run_after_panic();
 }
 #[cfg(test)]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    serial_println!("[failed]");
-    serial_println!("Error: {}", info);
+    emergency_println!("[failed]");
+    emergency_println!("Error: {}", info);
+    emergency_print!("{}", Backtrace::capture());
     exit_qemu(QemuExitCode::Failure);
     playground_os_rust::hlt_loop();
 }
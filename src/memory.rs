@@ -1,6 +1,14 @@
 use x86_64::{PhysAddr, structures::paging::PageTable, VirtAddr};
-use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PhysFrame, Size4KiB};
+use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::structures::paging::mapper::{MapToError, TranslateResult};
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::Translate;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use crate::sync::Mutex;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+///Where all physical memory is mapped, set by `init`.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
 
 ///Provides the address of the currently active level 4 page table.
 ///
@@ -45,8 +53,64 @@ fn translate_addr_inner(addr:VirtAddr, offset:VirtAddr)->Option<PhysAddr>{
     //translate the virtual address into a physical address
     Some(frame.start_address() + u64::from(addr.page_offset()))
 }
+///Walk the active page table for an address, passing the entry used at each level (4 down to 1) to `visit`, and
+///return the physical address it maps to. Unlike `translate_addr` this understands huge pages.
+///
+///Takes no lock, so debuggers can look at addresses from an exception. Finds nothing before `init`.
+pub fn walk_page_tables(addr: VirtAddr, mut visit: impl FnMut(usize, &PageTableEntry)) -> Option<PhysAddr>{
+    use x86_64::registers::control::Cr3;
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    if offset == 0{
+        return None;
+    }
+    let (level_4_page_frame, _) = Cr3::read();
+    let mut table_address = level_4_page_frame.start_address();
+    let table_indices = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    for (level, &index) in (1..=4).rev().zip(table_indices.iter()){
+        let table_ptr: *const PageTable = VirtAddr::new(offset + table_address.as_u64()).as_ptr();
+        let table = unsafe{&*table_ptr};
+        let entry = &table[index];
+        visit(level, entry);
+        if !entry.flags().contains(PageTableFlags::PRESENT){
+            return None;
+        }
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE){
+            //the bits below the entry's level are the offset into the page
+            let page_mask = (1u64 << (12 + 9 * (level - 1))) - 1;
+            return Some(entry.addr() + (addr.as_u64() & page_mask));
+        }
+        table_address = entry.addr();
+    }
+    None
+}
+///Read a byte of memory, or `None` if its page is not mapped. For debuggers, see `walk_page_tables`.
+pub fn peek(address: u64) -> Option<u8>{
+    let address = VirtAddr::try_new(address).ok()?;
+    walk_page_tables(address, |_, _| {})?;
+    Some(unsafe{core::ptr::read_volatile(address.as_ptr::<u8>())})
+}
+///Write a byte of memory, read-only pages such as code included; false if its page is not mapped. For debuggers,
+///see `walk_page_tables`.
+pub fn poke(address: u64, value: u8) -> bool{
+    use x86_64::registers::control::{Cr0, Cr0Flags};
+    let address = match VirtAddr::try_new(address){
+        Ok(address) => address,
+        Err(_) => return false,
+    };
+    if walk_page_tables(address, |_, _| {}).is_none(){
+        return false;
+    }
+    unsafe{
+        let flags = Cr0::read();
+        Cr0::write(flags - Cr0Flags::WRITE_PROTECT);
+        core::ptr::write_volatile(address.as_mut_ptr::<u8>(), value);
+        Cr0::write(flags);
+    }
+    true
+}
 ///Initialise the OffsetPageTable and return it.
 pub unsafe fn init(physical_memory_offset : VirtAddr) -> OffsetPageTable<'static>{
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     let level_4_page_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_page_table, physical_memory_offset)
 }
@@ -104,4 +168,37 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator{
         self.next += 1;
         frame
     }
-}
\ No newline at end of file
+}
+///Page table and frame allocator, kept after boot for drivers that map memory later on.
+pub struct MemoryManager{
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BootInfoFrameAllocator,
+}
+///The memory manager, set up at the end of `crate::init`.
+pub static MEMORY: Mutex<Option<MemoryManager>> = Mutex::new(None);
+
+impl MemoryManager{
+    pub fn new(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) -> MemoryManager{
+        MemoryManager{mapper, frame_allocator}
+    }
+    ///Map `size` bytes of device memory at physical address `phys` to the virtual address `virt`, uncached.
+    ///Pages that already map the same frames are left alone, so a region can be mapped again with a larger size.
+    pub fn map_device_memory(&mut self, phys: PhysAddr, virt: VirtAddr, size: u64) -> Result<(), MapToError<Size4KiB>>{
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+        let pages = size.div_ceil(4096);
+        for i in 0..pages{
+            let page: Page<Size4KiB> = Page::containing_address(virt + i * 4096);
+            let frame = PhysFrame::containing_address(phys + i * 4096);
+            if let TranslateResult::Mapped{frame: mapped, ..} = self.mapper.translate(page.start_address()){
+                if mapped.start_address() == frame.start_address(){
+                    continue;
+                }
+                return Err(MapToError::PageAlreadyMapped(frame));
+            }
+            unsafe{
+                self.mapper.map_to(page, frame, flags, &mut self.frame_allocator)?.flush();
+            }
+        }
+        Ok(())
+    }
+}
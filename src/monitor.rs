@@ -0,0 +1,347 @@
+//! An interactive monitor for looking at the stopped kernel.
+//!
+//! It is entered on `int3` when no debugger takes it, on SysRq (Alt+Print Screen), which stops whatever code is
+//! running, and after a panic. Everything else stands still meanwhile: the monitor polls the PS/2 keyboard and the
+//! console serial port, and writes straight to the text screen and the log port, taking no locks.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use x86_64::VirtAddr;
+use crate::backtrace::{self, Backtrace};
+use crate::interrupts::{TrapFrame, TRAP_FLAG};
+use crate::key_conversion::KeyDecoder;
+use crate::serial::{self, Role, Uart};
+use crate::task::{self, serial::TerminalDecoder};
+use crate::vga_buffer::{self, Color, ColorCode, ScreenBackup};
+use crate::{framebuffer, memory, ps2};
+
+///Scancode of SysRq, i.e. Alt+Print Screen.
+pub const HOTKEY_SCANCODE: u8 = 0x54;
+const LINE_LENGTH: usize = 76;
+const MAX_ARGS: usize = 8;
+const DUMP_LENGTH: u64 = 64;
+const MAX_DUMP_LENGTH: u64 = 512;
+const BYTES_PER_LINE: u64 = 8;
+const COLOR: ColorCode = ColorCode::new(Color::White, Color::Blue);
+
+///Set by the keyboard interrupt when SysRq was pressed.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+///Set while the monitor runs, so it is not entered again from inside.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+///Whether `int3` enters the monitor; not in the unit tests, which check that the breakpoint handler returns.
+static ON_BREAKPOINT: AtomicBool = AtomicBool::new(!cfg!(test));
+
+///Ask for the monitor; the keyboard interrupt then makes the interrupted code stop after its next instruction.
+pub(crate) fn request(){
+    REQUESTED.store(true, Ordering::Relaxed);
+}
+
+///Enter the monitor for a breakpoint or a requested debug exception. Returns false if it was not meant for it.
+pub(crate) fn handle_trap(frame: &mut TrapFrame) -> bool{
+    if ACTIVE.load(Ordering::Relaxed){
+        return false;
+    }
+    let reason = match frame.vector{
+        3 if ON_BREAKPOINT.load(Ordering::Relaxed) => "breakpoint",
+        1 if REQUESTED.swap(false, Ordering::Relaxed) => {
+            frame.rflags &= !TRAP_FLAG;
+            "SysRq"
+        },
+        _ => return false,
+    };
+    run(Some(frame), reason);
+    true
+}
+
+///Enter the monitor after a panic has been printed. There is nothing to continue, so it never returns.
+pub fn run_after_panic() -> !{
+    if !ACTIVE.load(Ordering::Relaxed){
+        run(None, "panic");
+    }
+    crate::hlt_loop();
+}
+
+fn run(mut frame: Option<&mut TrapFrame>, reason: &str){
+    ACTIVE.store(true, Ordering::Relaxed);
+    let mode = framebuffer::suspend_mode();
+    let screen = ScreenBackup::take();
+    let mut input = Input::new();
+    writeln!(Output, "\nmonitor: stopped by {}, type help for the commands", reason).ok();
+    loop{
+        write!(Output, "mon> ").ok();
+        let line = input.read_line();
+        let mut args = [""; MAX_ARGS];
+        let mut count = 0;
+        for arg in line.split_whitespace().take(MAX_ARGS){
+            args[count] = arg;
+            count += 1;
+        }
+        if count == 0{
+            continue;
+        }
+        match COMMANDS.iter().find(|command| command.name == args[0]){
+            Some(command) => {
+                if (command.run)(&mut Output, frame.as_deref_mut(), &args[1..count]){
+                    break;
+                }
+            },
+            None => {
+                writeln!(Output, "unknown command: {}", args[0]).ok();
+            },
+        }
+    }
+    screen.restore();
+    framebuffer::resume_mode(mode);
+    ACTIVE.store(false, Ordering::Relaxed);
+}
+
+///The text screen and the log port, written to without locks.
+struct Output;
+impl fmt::Write for Output{
+    fn write_str(&mut self, s: &str) -> fmt::Result{
+        vga_buffer::write_unlocked(s, COLOR);
+        serial::emergency_write(s);
+        Ok(())
+    }
+}
+
+///Lines typed on the PS/2 keyboard or the console serial port.
+struct Input{
+    keyboard: Keyboard<layouts::Us104Key, ScancodeSet1>,
+    decoder: KeyDecoder,
+    serial: Option<Uart>,
+    terminal: TerminalDecoder,
+    line: [u8; LINE_LENGTH],
+    len: usize,
+}
+impl Input{
+    fn new() -> Input{
+        Input{
+            keyboard: Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore),
+            decoder: KeyDecoder::new(),
+            serial: serial::try_port_for(Role::Console).flatten().and_then(serial::unlocked_uart),
+            terminal: TerminalDecoder::new(),
+            line: [0; LINE_LENGTH],
+            len: 0,
+        }
+    }
+    fn read_char(&mut self) -> char{
+        loop{
+            if let Some(DecodedKey::Unicode(c)) = self.decoder.take_queued(){
+                return c;
+            }
+            if let Some(scancode) = ps2::poll_keyboard(){
+                if let Ok(Some(event)) = self.keyboard.add_byte(scancode){
+                    if let Some(DecodedKey::Unicode(c)) = self.decoder.process_keyevent(event){
+                        return c;
+                    }
+                }
+            }
+            if let Some(byte) = self.serial.as_mut().and_then(|uart| uart.try_receive()){
+                if let Some(c) = self.terminal.add_byte(byte){
+                    return c;
+                }
+            }
+            core::hint::spin_loop();
+        }
+    }
+    ///Read a line, echoing it. Only printable ASCII is taken.
+    fn read_line(&mut self) -> &str{
+        self.len = 0;
+        loop{
+            match self.read_char(){
+                '\n' => break,
+                '\x08' if self.len > 0 => {
+                    self.len -= 1;
+                    write!(Output, "\x08 \x08").ok();
+                },
+                c if (c == ' ' || c.is_ascii_graphic()) && self.len < LINE_LENGTH => {
+                    self.line[self.len] = c as u8;
+                    self.len += 1;
+                    write!(Output, "{}", c).ok();
+                },
+                _ => {},
+            }
+        }
+        writeln!(Output).ok();
+        core::str::from_utf8(&self.line[..self.len]).unwrap_or("")
+    }
+}
+
+///A monitor command: its name, a one line description and the function running it, which returns true to leave
+///the monitor.
+struct Command{
+    name: &'static str,
+    help: &'static str,
+    run: fn(out: &mut dyn Write, frame: Option<&mut TrapFrame>, args: &[&str]) -> bool,
+}
+
+static COMMANDS: &[Command] = &[
+    Command{name: "help", help: "list the commands; numbers are hex", run: cmd_help},
+    Command{name: "regs", help: "show the registers of the stopped code", run: cmd_regs},
+    Command{name: "bt", help: "show the backtrace of the stopped code", run: cmd_bt},
+    Command{name: "dump", help: "dump ADDR [LEN] - show memory", run: cmd_dump},
+    Command{name: "poke", help: "poke ADDR BYTE... - change memory, code included", run: cmd_poke},
+    Command{name: "walk", help: "walk ADDR - show the page table entries mapping an address", run: cmd_walk},
+    Command{name: "tasks", help: "list the tasks, * marks the one that was running", run: cmd_tasks},
+    Command{name: "continue", help: "leave the monitor and let the kernel go on", run: cmd_continue},
+    Command{name: "reboot", help: "restart the machine", run: cmd_reboot},
+];
+
+///Parse a hex number, with or without "0x".
+fn parse_number(text: &str) -> Option<u64>{
+    let digits = text.strip_prefix("0x").unwrap_or(text);
+    u64::from_str_radix(digits, 16).ok()
+}
+
+fn cmd_help(out: &mut dyn Write, _frame: Option<&mut TrapFrame>, _args: &[&str]) -> bool{
+    for command in COMMANDS{
+        writeln!(out, "{:<9} {}", command.name, command.help).ok();
+    }
+    false
+}
+fn cmd_regs(out: &mut dyn Write, frame: Option<&mut TrapFrame>, _args: &[&str]) -> bool{
+    let frame = match frame{
+        Some(frame) => frame,
+        None => {
+            writeln!(out, "no registers after a panic").ok();
+            return false;
+        },
+    };
+    let registers = [
+        ("rax", frame.rax), ("rbx", frame.rbx), ("rcx", frame.rcx), ("rdx", frame.rdx),
+        ("rsi", frame.rsi), ("rdi", frame.rdi), ("rbp", frame.rbp), ("rsp", frame.rsp),
+        ("r8", frame.r8), ("r9", frame.r9), ("r10", frame.r10), ("r11", frame.r11),
+        ("r12", frame.r12), ("r13", frame.r13), ("r14", frame.r14), ("r15", frame.r15),
+    ];
+    for line in registers.chunks(3){
+        for (name, value) in line{
+            write!(out, "{:>3} {:016x}  ", name, value).ok();
+        }
+        writeln!(out).ok();
+    }
+    writeln!(out, "rflags {:016x}  cs {:04x}  ss {:04x}", frame.rflags, frame.cs, frame.ss).ok();
+    write!(out, "rip {:016x}", frame.rip).ok();
+    match backtrace::lookup(frame.rip){
+        Some(symbol) => writeln!(out, " {}+{:#x}", symbol.name, frame.rip - symbol.address),
+        None => writeln!(out),
+    }.ok();
+    false
+}
+fn cmd_bt(out: &mut dyn Write, frame: Option<&mut TrapFrame>, _args: &[&str]) -> bool{
+    let backtrace = match frame{
+        Some(frame) => Backtrace::from_registers(frame.rip, frame.rbp),
+        None => Backtrace::capture(),
+    };
+    write!(out, "{}", backtrace).ok();
+    false
+}
+fn cmd_dump(out: &mut dyn Write, _frame: Option<&mut TrapFrame>, args: &[&str]) -> bool{
+    let address = match args.first().and_then(|arg| parse_number(arg)){
+        Some(address) => address,
+        None => {
+            writeln!(out, "usage: dump ADDR [LEN]").ok();
+            return false;
+        },
+    };
+    let length = args.get(1).and_then(|arg| parse_number(arg)).unwrap_or(DUMP_LENGTH).min(MAX_DUMP_LENGTH);
+    for line in (0..length).step_by(BYTES_PER_LINE as usize){
+        let start = address.wrapping_add(line);
+        let mut text = [b' '; BYTES_PER_LINE as usize];
+        write!(out, "{:016x} ", start).ok();
+        for offset in 0..BYTES_PER_LINE{
+            if line + offset >= length{
+                write!(out, "   ").ok();
+                continue;
+            }
+            match memory::peek(start.wrapping_add(offset)){
+                Some(byte) => {
+                    write!(out, " {:02x}", byte).ok();
+                    text[offset as usize] = if byte.is_ascii_graphic() {byte} else {b'.'};
+                },
+                None => {
+                    write!(out, " ??").ok();
+                },
+            }
+        }
+        writeln!(out, "  {}", core::str::from_utf8(&text).unwrap_or("")).ok();
+    }
+    false
+}
+fn cmd_poke(out: &mut dyn Write, _frame: Option<&mut TrapFrame>, args: &[&str]) -> bool{
+    let address = match args.first().and_then(|arg| parse_number(arg)){
+        Some(address) if args.len() > 1 => address,
+        _ => {
+            writeln!(out, "usage: poke ADDR BYTE...").ok();
+            return false;
+        },
+    };
+    for (offset, arg) in args[1..].iter().enumerate(){
+        let target = address.wrapping_add(offset as u64);
+        match parse_number(arg){
+            Some(byte) if byte <= 0xff => {
+                if !memory::poke(target, byte as u8){
+                    writeln!(out, "{:#x} is not mapped", target).ok();
+                    return false;
+                }
+            },
+            _ => {
+                writeln!(out, "not a byte: {}", arg).ok();
+                return false;
+            },
+        }
+    }
+    false
+}
+fn cmd_walk(out: &mut dyn Write, _frame: Option<&mut TrapFrame>, args: &[&str]) -> bool{
+    let address = match args.first().and_then(|arg| parse_number(arg)).and_then(|address| VirtAddr::try_new(address).ok()){
+        Some(address) => address,
+        None => {
+            writeln!(out, "usage: walk ADDR, a canonical address").ok();
+            return false;
+        },
+    };
+    let physical = memory::walk_page_tables(address, |level, entry|{
+        writeln!(out, "P{} entry: {:#x} {:?}", level, entry.addr().as_u64(), entry.flags()).ok();
+    });
+    match physical{
+        Some(physical) => writeln!(out, "{:#x} -> {:#x}", address.as_u64(), physical.as_u64()),
+        None => writeln!(out, "{:#x} is not mapped", address.as_u64()),
+    }.ok();
+    false
+}
+fn cmd_tasks(out: &mut dyn Write, _frame: Option<&mut TrapFrame>, _args: &[&str]) -> bool{
+    let running = task::running_task();
+    let listed = task::try_for_each_task(|info|{
+        let name = info.name.trim_end_matches("::{{closure}}");
+        let mark = if Some(info.id) == running {'*'} else {' '};
+        writeln!(out, "{}{:>4} {:>10} polls  {}", mark, info.id, info.polls, name).ok();
+    });
+    if !listed{
+        writeln!(out, "the executor was stopped while changing the task list").ok();
+    }
+    false
+}
+fn cmd_continue(out: &mut dyn Write, frame: Option<&mut TrapFrame>, _args: &[&str]) -> bool{
+    if frame.is_none(){
+        writeln!(out, "there is nothing to continue after a panic").ok();
+        return false;
+    }
+    true
+}
+fn cmd_reboot(_out: &mut dyn Write, _frame: Option<&mut TrapFrame>, _args: &[&str]) -> bool{
+    crate::reboot();
+}
+
+//----------TEST CASES------------
+#[test_case]
+fn dump_shows_memory(){
+    let data = *b"monitor!";
+    let address = alloc::format!("{:x}", &data as *const u8 as u64);
+    let mut text = alloc::string::String::new();
+    assert!(!cmd_dump(&mut text, None, &[&address, "8"]));
+    assert!(text.ends_with(" 6d 6f 6e 69 74 6f 72 21  monitor!\n"));
+    assert_eq!(parse_number("0x1f"), Some(0x1f));
+    assert_eq!(parse_number("zz"), None);
+}
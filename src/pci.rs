@@ -0,0 +1,99 @@
+//! Access to PCI configuration space through the legacy 0xcf8/0xcfc mechanism.
+
+use x86_64::instructions::port::Port;
+use x86_64::instructions::interrupts;
+
+const CONFIG_ADDRESS_PORT: u16 = 0xcf8;
+const CONFIG_DATA_PORT: u16 = 0xcfc;
+///Bit of the configuration address that starts the access.
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+const OFFSET_VENDOR_DEVICE: u8 = 0x00;
+const OFFSET_HEADER_TYPE: u8 = 0x0c;
+const OFFSET_BAR0: u8 = 0x10;
+///Vendor id read from an empty slot.
+const NO_VENDOR: u16 = 0xffff;
+///Header type bit marking devices with more than one function.
+const HEADER_MULTI_FUNCTION: u32 = 1 << 23;
+///Bit of a base address register that marks it as an I/O port range.
+const BAR_IO_SPACE: u32 = 1;
+///Bits of a memory base address register giving its width.
+const BAR_TYPE_MASK: u32 = 0b110;
+///Width of a memory base address register taking the next register as its upper half.
+const BAR_TYPE_64: u32 = 0b100;
+///Number of base address registers in a type 0 header.
+const BAR_COUNT: u8 = 6;
+
+///Bus, device and function number of a PCI function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress{
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+impl PciAddress{
+    fn config_address(&self, offset: u8) -> u32{
+        CONFIG_ENABLE
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset & 0xfc) as u32
+    }
+    ///Read the aligned 32 bit register at `offset` of the configuration space.
+    pub fn read_config(&self, offset: u8) -> u32{
+        let mut address = Port::<u32>::new(CONFIG_ADDRESS_PORT);
+        let mut data = Port::<u32>::new(CONFIG_DATA_PORT);
+        interrupts::without_interrupts(||{
+            unsafe{
+                address.write(self.config_address(offset));
+                data.read()
+            }
+        })
+    }
+    pub fn vendor_id(&self) -> u16{
+        self.read_config(OFFSET_VENDOR_DEVICE) as u16
+    }
+    pub fn device_id(&self) -> u16{
+        (self.read_config(OFFSET_VENDOR_DEVICE) >> 16) as u16
+    }
+    ///Physical address of the memory range decoded by base address register `index`,
+    ///or `None` if it is an I/O port range. A 64-bit address takes its upper half from register `index + 1`.
+    pub fn memory_bar(&self, index: u8) -> Option<u64>{
+        if index >= BAR_COUNT{
+            return None;
+        }
+        let bar = self.read_config(OFFSET_BAR0 + index * 4);
+        if bar & BAR_IO_SPACE != 0{
+            return None;
+        }
+        let low = (bar & !0xf) as u64;
+        if bar & BAR_TYPE_MASK != BAR_TYPE_64{
+            return Some(low);
+        }
+        if index + 1 >= BAR_COUNT{
+            return None;
+        }
+        let high = self.read_config(OFFSET_BAR0 + (index + 1) * 4) as u64;
+        Some(high << 32 | low)
+    }
+}
+
+///Find the first function with the given vendor and device id by scanning every bus.
+pub fn find_device(vendor_id: u16, device_id: u16) -> Option<PciAddress>{
+    for bus in 0..=255u8{
+        for device in 0..32u8{
+            let first = PciAddress{bus, device, function: 0};
+            if first.vendor_id() == NO_VENDOR{
+                continue;
+            }
+            let functions = if first.read_config(OFFSET_HEADER_TYPE) & HEADER_MULTI_FUNCTION != 0 {8} else {1};
+            for function in 0..functions{
+                let address = PciAddress{bus, device, function};
+                if address.vendor_id() == vendor_id && address.device_id() == device_id{
+                    return Some(address);
+                }
+            }
+        }
+    }
+    None
+}
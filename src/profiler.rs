@@ -0,0 +1,201 @@
+//! A sampling profiler driven by the timer interrupt.
+//!
+//! While it runs, every `interval`th timer tick stores the interrupted code's backtrace in a buffer of the CPU
+//! taking it. The buffers are rings written without locks (see `ring`), so the samples can be read at any time;
+//! once one is full, new samples replace the oldest. They are aggregated by symbol into a flat profile of where the
+//! time was spent, or into collapsed stacks, one line per distinct stack, as `flamegraph.pl` reads them.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use crate::backtrace::{self, Backtrace};
+use crate::ring::SeqRing;
+use crate::sync::{PerCpu, MAX_CPUS};
+
+///Samples kept per CPU; older ones are overwritten.
+pub const SAMPLES_PER_CPU: usize = 2048;
+///Frames kept of a sample's backtrace; deeper stacks lose their outermost frames.
+pub const SAMPLE_DEPTH: usize = 16;
+///Timer ticks between two samples unless set otherwise.
+pub const DEFAULT_INTERVAL: u32 = 10;
+
+///Samples of a CPU, each the return addresses of a backtrace, innermost first, with the interrupted instruction
+///first; the unused ones are 0. CPUs without one drop theirs.
+static BUFFERS: PerCpu<SeqRing<SAMPLES_PER_CPU, SAMPLE_DEPTH>> = PerCpu::new([const {SeqRing::new()}; MAX_CPUS]);
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static INTERVAL: AtomicU32 = AtomicU32::new(DEFAULT_INTERVAL);
+///Ticks until the next sample.
+static COUNTDOWN: AtomicU32 = AtomicU32::new(0);
+
+///Throw the samples away and start sampling every `interval` timer ticks.
+pub fn start(interval: u32){
+    RUNNING.store(false, Ordering::SeqCst);
+    for (_, buffer) in BUFFERS.iter(){
+        buffer.clear();
+    }
+    BUFFERS.reset_misses();
+    INTERVAL.store(interval.max(1), Ordering::Relaxed);
+    COUNTDOWN.store(0, Ordering::Relaxed);
+    RUNNING.store(true, Ordering::SeqCst);
+}
+///Stop sampling, keeping the samples.
+pub fn stop(){
+    RUNNING.store(false, Ordering::SeqCst);
+}
+pub fn is_running() -> bool{
+    RUNNING.load(Ordering::Relaxed)
+}
+///Timer ticks between two samples.
+pub fn interval() -> u32{
+    INTERVAL.load(Ordering::Relaxed)
+}
+///Samples in the buffers.
+pub fn sample_count() -> u64{
+    BUFFERS.iter().map(|(_, buffer)| buffer.head() - buffer.oldest()).sum()
+}
+///Samples dropped since the profiler was started.
+pub fn dropped_count() -> u64{
+    BUFFERS.misses()
+}
+
+///Count a timer tick, and whether a sample is due with it.
+///
+///Called by the timer interrupt, which then takes the backtrace itself, as only it can see the interrupted frame.
+pub fn tick() -> bool{
+    if !is_running(){
+        return false;
+    }
+    match COUNTDOWN.load(Ordering::Relaxed){
+        0 => {
+            COUNTDOWN.store(interval() - 1, Ordering::Relaxed);
+            true
+        },
+        ticks => {
+            COUNTDOWN.store(ticks - 1, Ordering::Relaxed);
+            false
+        },
+    }
+}
+
+///Store a sample in the buffer of the current CPU.
+pub fn record(backtrace: &Backtrace){
+    if !is_running(){
+        return;
+    }
+    if let Some(buffer) = BUFFERS.current(){
+        let frames = backtrace.frames();
+        buffer.push(|| core::array::from_fn(|i| frames.get(i).copied().unwrap_or(0)));
+    }
+}
+
+///Call `f` with the frames of every sample, innermost first.
+fn for_each_sample(mut f: impl FnMut(&[u64])){
+    for (_, buffer) in BUFFERS.iter(){
+        for sample in (buffer.oldest()..buffer.head()).filter_map(|sequence| buffer.get(sequence)){
+            let depth = sample.iter().position(|&address| address == 0).unwrap_or(SAMPLE_DEPTH);
+            f(&sample[..depth]);
+        }
+    }
+}
+
+///Where a frame was: the containing function, or the bare address if it is unknown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Location{
+    Symbol(&'static str),
+    Address(u64),
+}
+impl Location{
+    ///Location of the `depth`th frame of a sample; all but the first are return addresses, pointing after the call.
+    fn of(address: u64, depth: usize) -> Location{
+        let call_site = if depth == 0 {address} else {address - 1};
+        match backtrace::lookup(call_site){
+            Some(symbol) => Location::Symbol(symbol.name),
+            None => Location::Address(address),
+        }
+    }
+}
+impl fmt::Display for Location{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        match self{
+            //';' separates the frames of a collapsed stack, and shows up in names of array types
+            Location::Symbol(name) => name.split(';').enumerate().try_for_each(|(i, part)|{
+                if i > 0{
+                    f.write_char(',')?;
+                }
+                f.write_str(part)
+            }),
+            Location::Address(address) => write!(f, "{:#x}", address),
+        }
+    }
+}
+
+///Print the functions samples were taken in, most samples first, at most `limit` of them.
+pub fn write_flat(out: &mut dyn Write, limit: usize) -> fmt::Result{
+    let mut counts: BTreeMap<Location, u64> = BTreeMap::new();
+    let mut total = 0;
+    for_each_sample(|frames|{
+        if let Some(&address) = frames.first(){
+            *counts.entry(Location::of(address, 0)).or_insert(0) += 1;
+            total += 1;
+        }
+    });
+    let mut counts: Vec<(Location, u64)> = counts.into_iter().collect();
+    counts.sort_by_key(|&(_, count)| core::cmp::Reverse(count));
+    writeln!(out, "{} samples, {} dropped", total, dropped_count())?;
+    for (location, count) in counts.iter().take(limit){
+        let permille = count * 1000 / total;
+        writeln!(out, "{:>7} {:>3}.{}% {}", count, permille / 10, permille % 10, location)?;
+    }
+    Ok(())
+}
+
+///Print every distinct stack sampled, outermost frame first and separated by ';', followed by its sample count.
+pub fn write_collapsed(out: &mut dyn Write) -> fmt::Result{
+    let mut stacks: BTreeMap<String, u64> = BTreeMap::new();
+    let mut stack = String::new();
+    for_each_sample(|frames|{
+        stack.clear();
+        for (depth, &address) in frames.iter().enumerate().rev(){
+            if !stack.is_empty(){
+                stack.push(';');
+            }
+            let _ = write!(stack, "{}", Location::of(address, depth));
+        }
+        match stacks.get_mut(&stack){
+            Some(count) => *count += 1,
+            None => {stacks.insert(stack.clone(), 1);},
+        }
+    });
+    for (stack, count) in &stacks{
+        writeln!(out, "{} {}", stack, count)?;
+    }
+    Ok(())
+}
+
+//----------TEST CASES------------
+#[test_case]
+fn samples_are_aggregated(){
+    #[inline(never)]
+    fn sampled() -> u64{
+        core::hint::black_box(1)
+    }
+    let address = sampled as *const () as u64;
+    let sample = Backtrace::from_registers(address, 0);
+    x86_64::instructions::interrupts::without_interrupts(||{
+        start(u32::MAX);
+        record(&sample);
+        record(&sample);
+        stop();
+    });
+    assert_eq!(sample_count(), 2);
+    let location = alloc::format!("{}", Location::of(address, 0));
+    let mut flat = String::new();
+    write_flat(&mut flat, 10).unwrap();
+    assert!(flat.starts_with("2 samples, 0 dropped\n"));
+    assert!(flat.lines().nth(1).unwrap().ends_with(&location));
+    let mut collapsed = String::new();
+    write_collapsed(&mut collapsed).unwrap();
+    assert_eq!(collapsed, alloc::format!("{} 2\n", location));
+}
@@ -0,0 +1,390 @@
+//! Driver for the 8042 PS/2 controller and the devices attached to it.
+//!
+//! All device commands are sent with interrupts disabled and their answers polled, so the
+//! interrupt handlers only ever see scancodes and mouse packets. Both devices share the data port; the
+//! status register tells which one a byte came from, so each only gets its own bytes.
+
+use x86_64::instructions::port::Port;
+use x86_64::instructions::interrupts;
+use crate::key_conversion::LockState;
+use crate::sync::Mutex;
+
+const DATA_PORT: u16 = 0x60;
+///Status register when read, command register when written.
+const STATUS_COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+const STATUS_SECOND_PORT_DATA: u8 = 1 << 5;
+
+const CONFIG_PORT1_IRQ: u8 = 1;
+const CONFIG_PORT2_IRQ: u8 = 1 << 1;
+const CONFIG_PORT2_CLOCK_DISABLED: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_PORT2: u8 = 0xa7;
+const CMD_ENABLE_PORT2: u8 = 0xa8;
+const CMD_TEST_PORT2: u8 = 0xa9;
+const CMD_SELF_TEST: u8 = 0xaa;
+const CMD_TEST_PORT1: u8 = 0xab;
+const CMD_DISABLE_PORT1: u8 = 0xad;
+const CMD_ENABLE_PORT1: u8 = 0xae;
+const CMD_WRITE_PORT2: u8 = 0xd4;
+
+const DEV_SET_LEDS: u8 = 0xed;
+const DEV_SCANCODE_SET: u8 = 0xf0;
+const DEV_IDENTIFY: u8 = 0xf2;
+const DEV_TYPEMATIC: u8 = 0xf3;
+///Same command byte as `DEV_TYPEMATIC`; mice read the data byte as samples per second.
+const DEV_SAMPLE_RATE: u8 = 0xf3;
+const DEV_ENABLE_SCANNING: u8 = 0xf4;
+const DEV_DISABLE_SCANNING: u8 = 0xf5;
+const DEV_RESET: u8 = 0xff;
+
+const RESPONSE_SELF_TEST_PASSED: u8 = 0x55;
+const RESPONSE_DEVICE_OK: u8 = 0xaa;
+const RESPONSE_ACK: u8 = 0xfa;
+const RESPONSE_RESEND: u8 = 0xfe;
+
+///Number of status polls before giving up on the controller or a device.
+const TIMEOUT: usize = 1_000_000;
+
+///One of the two PS/2 ports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Port{
+    First,
+    Second,
+}
+
+///Kind of device attached to a port, as reported by the identify command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType{
+    AtKeyboard,
+    Mf2Keyboard,
+    StandardMouse,
+    WheelMouse,
+    FiveButtonMouse,
+    Unknown(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error{
+    ///The controller or a device did not answer in time.
+    Timeout,
+    ///The controller self test returned something other than 0x55.
+    SelfTestFailed(u8),
+    ///The interface test of a port returned the given error code.
+    PortTestFailed(Ps2Port, u8),
+    ///A device kept answering a command with something other than ACK.
+    NoAck(u8),
+    ///The device reset did not report success.
+    ResetFailed(Ps2Port),
+    ///The controller only has one port.
+    NoSecondPort,
+}
+
+///The PS/2 controller, and what was found on its ports during `init`.
+pub struct Controller{
+    data: Port<u8>,
+    status_command: Port<u8>,
+    dual_channel: bool,
+    devices: [Option<DeviceType>; 2],
+}
+
+///The global PS/2 controller.
+pub static CONTROLLER: Mutex<Controller> = Mutex::new(Controller::new());
+
+impl Controller{
+    pub const fn new() -> Controller{
+        Controller{
+            data: Port::new(DATA_PORT),
+            status_command: Port::new(STATUS_COMMAND_PORT),
+            dual_channel: false,
+            devices: [None, None],
+        }
+    }
+    ///Device found on the given port, if any.
+    pub fn device(&self, port: Ps2Port) -> Option<DeviceType>{
+        self.devices[port as usize]
+    }
+    ///Whether the controller has a second (mouse) port.
+    pub fn is_dual_channel(&self) -> bool{
+        self.dual_channel
+    }
+
+    fn status(&mut self) -> u8{
+        unsafe{self.status_command.read()}
+    }
+    fn wait_for_input_empty(&mut self) -> Result<(), Ps2Error>{
+        for _ in 0..TIMEOUT{
+            if self.status() & STATUS_INPUT_FULL == 0{
+                return Ok(());
+            }
+        }
+        Err(Ps2Error::Timeout)
+    }
+    fn wait_for_output_full(&mut self) -> Result<(), Ps2Error>{
+        for _ in 0..TIMEOUT{
+            if self.status() & STATUS_OUTPUT_FULL != 0{
+                return Ok(());
+            }
+        }
+        Err(Ps2Error::Timeout)
+    }
+    fn command(&mut self, command: u8) -> Result<(), Ps2Error>{
+        self.wait_for_input_empty()?;
+        unsafe{self.status_command.write(command)};
+        Ok(())
+    }
+    fn read_data(&mut self) -> Result<u8, Ps2Error>{
+        self.wait_for_output_full()?;
+        Ok(unsafe{self.data.read()})
+    }
+    fn write_data(&mut self, data: u8) -> Result<(), Ps2Error>{
+        self.wait_for_input_empty()?;
+        unsafe{self.data.write(data)};
+        Ok(())
+    }
+    ///Read the next byte from the device on `port`. Bytes the other device sends meanwhile go to its queue.
+    fn read_from(&mut self, port: Ps2Port) -> Result<u8, Ps2Error>{
+        for _ in 0..TIMEOUT{
+            let status = self.status();
+            if status & STATUS_OUTPUT_FULL == 0{
+                continue;
+            }
+            let byte = unsafe{self.data.read()};
+            match port_of(status){
+                from if from == port => return Ok(byte),
+                Ps2Port::First => crate::task::keyboard::add_scancode(byte),
+                Ps2Port::Second => crate::task::mouse::add_mouse_byte(byte),
+            }
+        }
+        Err(Ps2Error::Timeout)
+    }
+    fn flush_output(&mut self){
+        while self.status() & STATUS_OUTPUT_FULL != 0{
+            unsafe{self.data.read()};
+        }
+    }
+    fn read_config(&mut self) -> Result<u8, Ps2Error>{
+        self.command(CMD_READ_CONFIG)?;
+        self.read_data()
+    }
+    fn write_config(&mut self, config: u8) -> Result<(), Ps2Error>{
+        self.command(CMD_WRITE_CONFIG)?;
+        self.write_data(config)
+    }
+
+    ///Send a byte to the device on `port` and wait for its ACK, resending a few times if asked to.
+    pub fn send_to_device(&mut self, port: Ps2Port, byte: u8) -> Result<(), Ps2Error>{
+        if port == Ps2Port::Second && !self.dual_channel{
+            return Err(Ps2Error::NoSecondPort);
+        }
+        let mut response = RESPONSE_RESEND;
+        for _ in 0..3{
+            if port == Ps2Port::Second{
+                self.command(CMD_WRITE_PORT2)?;
+            }
+            self.write_data(byte)?;
+            response = self.read_from(port)?;
+            if response != RESPONSE_RESEND{
+                break;
+            }
+        }
+        match response{
+            RESPONSE_ACK => Ok(()),
+            other => Err(Ps2Error::NoAck(other)),
+        }
+    }
+    ///Send a device command followed by its data byte.
+    fn send_with_data(&mut self, port: Ps2Port, command: u8, data: u8) -> Result<(), Ps2Error>{
+        self.send_to_device(port, command)?;
+        self.send_to_device(port, data)
+    }
+    ///Read the next byte from the device on `port`, e.g. the answer to a command.
+    pub fn read_from_device(&mut self, port: Ps2Port) -> Result<u8, Ps2Error>{
+        self.read_from(port)
+    }
+
+    ///Self test the controller, detect and reset the attached devices and enable their interrupts.
+    ///
+    ///Must run with interrupts disabled, otherwise the interrupt handlers eat the answers.
+    pub fn init(&mut self) -> Result<(), Ps2Error>{
+        self.command(CMD_DISABLE_PORT1)?;
+        self.command(CMD_DISABLE_PORT2)?;
+        self.flush_output();
+
+        //no IRQs while setting up; keep translation so the keyboard delivers scancode set 1
+        let mut config = self.read_config()?;
+        config &= !(CONFIG_PORT1_IRQ | CONFIG_PORT2_IRQ);
+        config |= CONFIG_TRANSLATION;
+        self.write_config(config)?;
+
+        self.command(CMD_SELF_TEST)?;
+        match self.read_data()?{
+            RESPONSE_SELF_TEST_PASSED => {},
+            other => return Err(Ps2Error::SelfTestFailed(other)),
+        }
+        //some controllers reset their configuration during the self test
+        self.write_config(config)?;
+
+        //the second port's clock only turns on if the port exists
+        self.command(CMD_ENABLE_PORT2)?;
+        self.dual_channel = self.read_config()? & CONFIG_PORT2_CLOCK_DISABLED == 0;
+        self.command(CMD_DISABLE_PORT2)?;
+
+        self.command(CMD_TEST_PORT1)?;
+        match self.read_data()?{
+            0 => {},
+            other => return Err(Ps2Error::PortTestFailed(Ps2Port::First, other)),
+        }
+        if self.dual_channel{
+            self.command(CMD_TEST_PORT2)?;
+            if self.read_data()? != 0{
+                self.dual_channel = false;
+            }
+        }
+
+        self.command(CMD_ENABLE_PORT1)?;
+        self.devices[Ps2Port::First as usize] = self.reset_and_identify(Ps2Port::First).ok();
+        if self.dual_channel{
+            self.command(CMD_ENABLE_PORT2)?;
+            self.devices[Ps2Port::Second as usize] = self.reset_and_identify(Ps2Port::Second)
+                .and_then(|device| match device{
+                    DeviceType::StandardMouse => self.enable_mouse_extensions(),
+                    other => Ok(other),
+                })
+                .ok();
+        }
+
+        if let Some(DeviceType::AtKeyboard | DeviceType::Mf2Keyboard) = self.device(Ps2Port::First){
+            //set 2 translated by the controller arrives as set 1
+            self.set_scancode_set(2)?;
+            self.set_typematic(0x00, 1)?;
+        }
+
+        let mut config = self.read_config()?;
+        config |= CONFIG_PORT1_IRQ;
+        if self.devices[Ps2Port::Second as usize].is_some(){
+            config |= CONFIG_PORT2_IRQ;
+        }
+        self.write_config(config)?;
+        self.flush_output();
+        Ok(())
+    }
+
+    ///Reset the device on `port` and find out what it is.
+    fn reset_and_identify(&mut self, port: Ps2Port) -> Result<DeviceType, Ps2Error>{
+        self.send_to_device(port, DEV_RESET)?;
+        if self.read_from(port)? != RESPONSE_DEVICE_OK{
+            return Err(Ps2Error::ResetFailed(port));
+        }
+        //mice follow the self test result with their ID
+        if port == Ps2Port::Second{
+            let _ = self.read_from(port);
+        }
+        self.identify(port)
+    }
+    ///Ask the device on `port` for its identification bytes.
+    pub fn identify(&mut self, port: Ps2Port) -> Result<DeviceType, Ps2Error>{
+        self.send_to_device(port, DEV_DISABLE_SCANNING)?;
+        self.send_to_device(port, DEV_IDENTIFY)?;
+        let first = self.read_from(port).ok();
+        let second = match first{
+            Some(0xab) => self.read_from(port).ok(),
+            _ => None,
+        };
+        self.send_to_device(port, DEV_ENABLE_SCANNING)?;
+        Ok(match (first, second){
+            (None, _) => DeviceType::AtKeyboard,
+            (Some(0x00), _) => DeviceType::StandardMouse,
+            (Some(0x03), _) => DeviceType::WheelMouse,
+            (Some(0x04), _) => DeviceType::FiveButtonMouse,
+            (Some(0xab), _) => DeviceType::Mf2Keyboard,
+            (Some(other), _) => DeviceType::Unknown(other),
+        })
+    }
+    ///Switch the mouse on the second port into IntelliMouse mode (wheel) and, if that works,
+    ///five button mode, using the magic sample rate sequences. Returns the resulting device type.
+    fn enable_mouse_extensions(&mut self) -> Result<DeviceType, Ps2Error>{
+        for rate in [200, 100, 80]{
+            self.send_with_data(Ps2Port::Second, DEV_SAMPLE_RATE, rate)?;
+        }
+        if self.identify(Ps2Port::Second)? != DeviceType::WheelMouse{
+            return Ok(DeviceType::StandardMouse);
+        }
+        for rate in [200, 200, 80]{
+            self.send_with_data(Ps2Port::Second, DEV_SAMPLE_RATE, rate)?;
+        }
+        let device = self.identify(Ps2Port::Second)?;
+        self.send_with_data(Ps2Port::Second, DEV_SAMPLE_RATE, 100)?;
+        Ok(device)
+    }
+    ///Select the scancode set (1-3) the keyboard sends.
+    pub fn set_scancode_set(&mut self, set: u8) -> Result<(), Ps2Error>{
+        self.send_with_data(Ps2Port::First, DEV_SCANCODE_SET, set)
+    }
+    ///Set the keyboard's repeat rate (0 = 30 Hz up to 0x1f = 2 Hz) and delay (0-3, 250 ms steps from 250 ms).
+    pub fn set_typematic(&mut self, rate: u8, delay: u8) -> Result<(), Ps2Error>{
+        self.send_with_data(Ps2Port::First, DEV_TYPEMATIC, (rate & 0x1f) | ((delay & 0x3) << 5))
+    }
+    ///Light the keyboard LEDs according to the lock state.
+    pub fn set_leds(&mut self, locks: LockState) -> Result<(), Ps2Error>{
+        let mask = (locks.scroll_lock as u8) | (locks.num_lock as u8) << 1 | (locks.caps_lock as u8) << 2;
+        self.send_with_data(Ps2Port::First, DEV_SET_LEDS, mask)
+    }
+}
+impl Default for Controller{
+    fn default() -> Controller{
+        Controller::new()
+    }
+}
+
+///Initialise the global PS/2 controller.
+pub fn init() -> Result<(), Ps2Error>{
+    interrupts::without_interrupts(||{
+        CONTROLLER.lock().init()
+    })
+}
+///Update the keyboard LEDs. Interrupts are disabled while waiting for the keyboard's answers.
+pub fn set_leds(locks: LockState) -> Result<(), Ps2Error>{
+    interrupts::without_interrupts(||{
+        CONTROLLER.lock().set_leds(locks)
+    })
+}
+///Port the byte in the data port came from, according to the status register.
+fn port_of(status: u8) -> Ps2Port{
+    if status & STATUS_SECOND_PORT_DATA != 0 {Ps2Port::Second} else {Ps2Port::First}
+}
+///Read a byte from the keyboard if the controller has one, dropping mouse bytes. For code polling the keyboard
+///with interrupts disabled.
+pub(crate) fn poll_keyboard() -> Option<u8>{
+    let mut status: Port<u8> = Port::new(STATUS_COMMAND_PORT);
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    unsafe{
+        let status = status.read();
+        if status & STATUS_OUTPUT_FULL == 0{
+            return None;
+        }
+        let byte = data.read();
+        if port_of(status) == Ps2Port::First {Some(byte)} else {None}
+    }
+}
+///Read the data port if the controller has a byte from the device on `port`. A byte from the other device is
+///left for its interrupt handler.
+///
+///Used by the interrupt handlers, which may fire for a byte that was already consumed by polling.
+pub(crate) fn read_data_if_ready(port: Ps2Port) -> Option<u8>{
+    let mut status: Port<u8> = Port::new(STATUS_COMMAND_PORT);
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    unsafe{
+        let status = status.read();
+        if status & STATUS_OUTPUT_FULL != 0 && port_of(status) == port{
+            Some(data.read())
+        } else {
+            None
+        }
+    }
+}
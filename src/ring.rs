@@ -0,0 +1,101 @@
+//! A ring buffer of fixed-size records that is written without locks.
+//!
+//! Each slot is written and read like a sequence lock: its sequence word is odd while a record is written into
+//! it. Everything in a slot is atomic, so a reader racing a writer sees a changed sequence, never undefined data,
+//! and writers never wait, which makes the ring safe to write from interrupt handlers. The kernel log, the tracer
+//! and the profiler keep their records in one.
+
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+
+struct Slot<const W: usize>{
+    ///2n + 1 while record n is written, 2n + 2 once it is complete.
+    sequence: AtomicU64,
+    words: [AtomicU64; W],
+}
+impl<const W: usize> Slot<W>{
+    const fn new() -> Slot<W>{
+        Slot{sequence: AtomicU64::new(0), words: [const {AtomicU64::new(0)}; W]}
+    }
+}
+
+///Ring buffer of the last `N` records of `W` words each.
+pub struct SeqRing<const N: usize, const W: usize>{
+    ///Sequence number of the next record.
+    head: AtomicU64,
+    slots: [Slot<W>; N],
+}
+impl<const N: usize, const W: usize> SeqRing<N, W>{
+    pub const fn new() -> SeqRing<N, W>{
+        SeqRing{head: AtomicU64::new(0), slots: [const {Slot::new()}; N]}
+    }
+    ///Sequence number the next record will get.
+    pub fn head(&self) -> u64{
+        self.head.load(Ordering::Acquire)
+    }
+    ///Sequence number of the oldest record that may still be in the buffer.
+    pub fn oldest(&self) -> u64{
+        self.head().saturating_sub(N as u64)
+    }
+    ///Add a record, overwriting the oldest one once the buffer is full. Returns its sequence number.
+    ///
+    ///`record` is only called once the slot is taken, so e.g. timestamps it reads grow with the sequence numbers,
+    ///even when an interrupt handler pushes a record meanwhile.
+    pub fn push(&self, record: impl FnOnce() -> [u64; W]) -> u64{
+        let sequence = self.head.fetch_add(1, Ordering::AcqRel);
+        let slot = &self.slots[(sequence % N as u64) as usize];
+        slot.sequence.store(2 * sequence + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        for (word, value) in slot.words.iter().zip(record()){
+            word.store(value, Ordering::Relaxed);
+        }
+        slot.sequence.store(2 * sequence + 2, Ordering::Release);
+        sequence
+    }
+    ///Read a record; `None` if it was overwritten, is still being written or does not exist yet.
+    pub fn get(&self, sequence: u64) -> Option<[u64; W]>{
+        let slot = &self.slots[(sequence % N as u64) as usize];
+        if slot.sequence.load(Ordering::Acquire) != 2 * sequence + 2{
+            return None;
+        }
+        let mut words = [0; W];
+        for (value, word) in words.iter_mut().zip(&slot.words){
+            *value = word.load(Ordering::Relaxed);
+        }
+        fence(Ordering::Acquire);
+        if slot.sequence.load(Ordering::Relaxed) != 2 * sequence + 2{
+            return None;
+        }
+        Some(words)
+    }
+    ///Throw all records away. Records pushed meanwhile may be lost too.
+    pub fn clear(&self){
+        self.head.store(0, Ordering::SeqCst);
+        for slot in &self.slots{
+            slot.sequence.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<const N: usize, const W: usize> Default for SeqRing<N, W>{
+    fn default() -> SeqRing<N, W>{
+        SeqRing::new()
+    }
+}
+
+//----------TEST CASES------------
+#[test_case]
+fn ring_keeps_newest_records(){
+    let ring: SeqRing<4, 2> = SeqRing::new();
+    for number in 0..6{
+        assert_eq!(ring.push(|| [number, number * 2]), number);
+    }
+    assert_eq!((ring.oldest(), ring.head()), (2, 6));
+    assert_eq!(ring.get(1), None);
+    assert_eq!(ring.get(5), Some([5, 10]));
+    assert_eq!(ring.get(6), None);
+    ring.clear();
+    assert_eq!((ring.oldest(), ring.head()), (0, 0));
+    assert_eq!(ring.get(2), None);
+    assert_eq!(ring.push(|| [7, 7]), 0);
+    assert_eq!(ring.get(0), Some([7, 7]));
+}
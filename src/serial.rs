@@ -1,22 +1,414 @@
-use uart_16550::SerialPort;
-use spin::Mutex;
+//! Driver for the 16550 UARTs of the four standard COM ports.
+//!
+//! Ports are probed with a loopback test on first use and set up with `LineConfig::default()`. Each port can be
+//! dedicated to a `Role`: `serial_print!` writes to the log port, the serial shell answers on the console port.
+
+use crate::sync::Mutex;
 use lazy_static::lazy_static;
+use core::fmt;
+use core::task::Waker;
+use futures_util::task::AtomicWaker;
+use x86_64::instructions::port::Port;
+use x86_64::instructions::interrupts;
+
+///Base clock of the UART divided by 16, the baud rate at divisor 1.
+const MAX_BAUD_RATE: u32 = 115200;
+
+//registers, relative to the port base
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+///Divisor latch, low and high byte, while `LINE_CONTROL_DLAB` is set.
+const DIVISOR_LOW: u16 = 0;
+const DIVISOR_HIGH: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const SCRATCH: u16 = 7;
+
+const INTERRUPT_RECEIVED_DATA: u8 = 1;
+///Enable and clear the FIFOs, interrupt at 14 bytes.
+const FIFO_ENABLE_CLEAR_14: u8 = 0xc7;
+const LINE_CONTROL_TWO_STOP_BITS: u8 = 1 << 2;
+const LINE_CONTROL_DLAB: u8 = 1 << 7;
+///DTR, RTS and OUT2, which connects the interrupt line.
+const MODEM_CONTROL_NORMAL: u8 = 0x0b;
+///RTS, OUT1, OUT2 and loopback, for the presence test.
+const MODEM_CONTROL_LOOPBACK: u8 = 0x1e;
+const LINE_STATUS_DATA_READY: u8 = 1;
+const LINE_STATUS_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+///One of the standard COM ports, and the handle for writing to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort{
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+impl ComPort{
+    pub const ALL: [ComPort; 4] = [ComPort::Com1, ComPort::Com2, ComPort::Com3, ComPort::Com4];
+    fn index(self) -> usize{
+        self as usize
+    }
+    ///I/O port base, as assigned by PC BIOSes.
+    fn base(self) -> u16{
+        match self{
+            ComPort::Com1 => 0x3f8,
+            ComPort::Com2 => 0x2f8,
+            ComPort::Com3 => 0x3e8,
+            ComPort::Com4 => 0x2e8,
+        }
+    }
+    pub fn name(self) -> &'static str{
+        ["COM1", "COM2", "COM3", "COM4"][self.index()]
+    }
+    ///Port by name, such as "com2", ignoring case.
+    pub fn from_name(name: &str) -> Option<ComPort>{
+        ComPort::ALL.iter().copied().find(|port| port.name().eq_ignore_ascii_case(name))
+    }
+    ///Whether a UART answered at this port.
+    pub fn is_present(self) -> bool{
+        with_uart(self, |_| ()).is_some()
+    }
+    ///Current line settings; `None` if the port is not present.
+    pub fn config(self) -> Option<LineConfig>{
+        with_uart(self, |uart| uart.config)
+    }
+    ///Change the line settings of the port.
+    pub fn configure(self, config: LineConfig) -> Result<(), SerialError>{
+        with_uart(self, |uart| uart.configure(config)).unwrap_or(Err(SerialError::NotPresent))
+    }
+    ///Send a byte unchanged, e.g. for binary protocols. Dropped if the port is not present.
+    pub fn send(self, byte: u8){
+        with_uart(self, |uart| uart.send(byte));
+    }
+}
+///Text written to a port is sent unchanged, and dropped if the port is not present.
+impl fmt::Write for ComPort{
+    fn write_str(&mut self, s: &str) -> fmt::Result{
+        with_uart(*self, |uart| uart.write_str(s));
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity{
+    None,
+    Odd,
+    Even,
+    ///The parity bit is always 1.
+    Mark,
+    ///The parity bit is always 0.
+    Space,
+}
+impl Parity{
+    ///Bits of the line control register.
+    fn line_control(self) -> u8{
+        match self{
+            Parity::None => 0,
+            Parity::Odd => 0x08,
+            Parity::Even => 0x18,
+            Parity::Mark => 0x28,
+            Parity::Space => 0x38,
+        }
+    }
+    ///Letter used in settings like "8N1".
+    fn letter(self) -> char{
+        match self{
+            Parity::None => 'N',
+            Parity::Odd => 'O',
+            Parity::Even => 'E',
+            Parity::Mark => 'M',
+            Parity::Space => 'S',
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits{
+    One,
+    ///Two stop bits; 1.5 with 5 data bits.
+    Two,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError{
+    ///No UART answered at the port.
+    NotPresent,
+    ///The baud rate is not 115200 divided by a whole number up to 65535.
+    UnsupportedBaudRate,
+    ///Data bits other than 5 to 8.
+    UnsupportedDataBits,
+}
+
+///Baud rate and frame format of a port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineConfig{
+    pub baud_rate: u32,
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+impl LineConfig{
+    ///The settings with the frame format written like "8N1" or "7E2"; `None` if it cannot be parsed.
+    pub fn with_format(self, format: &str) -> Option<LineConfig>{
+        let mut chars = format.chars();
+        let data_bits = chars.next()?.to_digit(10)? as u8;
+        let parity = match chars.next()?.to_ascii_uppercase(){
+            'N' => Parity::None,
+            'O' => Parity::Odd,
+            'E' => Parity::Even,
+            'M' => Parity::Mark,
+            'S' => Parity::Space,
+            _ => return None,
+        };
+        let stop_bits = match chars.next()?{
+            '1' => StopBits::One,
+            '2' => StopBits::Two,
+            _ => return None,
+        };
+        if chars.next().is_some(){
+            return None;
+        }
+        Some(LineConfig{data_bits, parity, stop_bits, ..self})
+    }
+    fn divisor(&self) -> Result<u16, SerialError>{
+        if self.baud_rate == 0 || !MAX_BAUD_RATE.is_multiple_of(self.baud_rate){
+            return Err(SerialError::UnsupportedBaudRate);
+        }
+        //the divisor latch has 16 bits, so the slowest rate is 2 baud
+        u16::try_from(MAX_BAUD_RATE / self.baud_rate).map_err(|_| SerialError::UnsupportedBaudRate)
+    }
+    ///Value of the line control register, without DLAB.
+    fn line_control(&self) -> Result<u8, SerialError>{
+        if !(5..=8).contains(&self.data_bits){
+            return Err(SerialError::UnsupportedDataBits);
+        }
+        let stop_bits = match self.stop_bits{
+            StopBits::One => 0,
+            StopBits::Two => LINE_CONTROL_TWO_STOP_BITS,
+        };
+        Ok((self.data_bits - 5) | stop_bits | self.parity.line_control())
+    }
+}
+///38400 baud, 8N1.
+impl Default for LineConfig{
+    fn default() -> LineConfig{
+        LineConfig{baud_rate: 38400, data_bits: 8, parity: Parity::None, stop_bits: StopBits::One}
+    }
+}
+impl fmt::Display for LineConfig{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        let stop_bits = match self.stop_bits{
+            StopBits::One => 1,
+            StopBits::Two => 2,
+        };
+        write!(f, "{} {}{}{}", self.baud_rate, self.data_bits, self.parity.letter(), stop_bits)
+    }
+}
+
+///A 16550 UART that passed the presence test.
+pub struct Uart{
+    base: u16,
+    config: LineConfig,
+}
+impl Uart{
+    ///Check for a UART at the port base by sending a byte in loopback mode, and set it up if there is one.
+    ///
+    ///Unsafe, as the ports written to could belong to another device.
+    unsafe fn probe(base: u16) -> Option<Uart>{
+        let mut scratch: Port<u8> = Port::new(base + SCRATCH);
+        scratch.write(0x5a);
+        if scratch.read() != 0x5a{
+            return None;
+        }
+        let mut uart = Uart{base, config: LineConfig::default()};
+        uart.configure(LineConfig::default()).ok()?;
+        uart.register(MODEM_CONTROL).write(MODEM_CONTROL_LOOPBACK);
+        uart.register(DATA).write(0xae);
+        let echoed = uart.register(DATA).read();
+        uart.register(MODEM_CONTROL).write(MODEM_CONTROL_NORMAL);
+        if echoed != 0xae{
+            return None;
+        }
+        uart.register(INTERRUPT_ENABLE).write(INTERRUPT_RECEIVED_DATA);
+        Some(uart)
+    }
+    fn register(&self, offset: u16) -> Port<u8>{
+        Port::new(self.base + offset)
+    }
+    fn configure(&mut self, config: LineConfig) -> Result<(), SerialError>{
+        let divisor = config.divisor()?;
+        let line_control = config.line_control()?;
+        unsafe{
+            let interrupts = self.register(INTERRUPT_ENABLE).read();
+            self.register(INTERRUPT_ENABLE).write(0);
+            self.register(LINE_CONTROL).write(LINE_CONTROL_DLAB);
+            self.register(DIVISOR_LOW).write(divisor as u8);
+            self.register(DIVISOR_HIGH).write((divisor >> 8) as u8);
+            self.register(LINE_CONTROL).write(line_control);
+            self.register(FIFO_CONTROL).write(FIFO_ENABLE_CLEAR_14);
+            self.register(MODEM_CONTROL).write(MODEM_CONTROL_NORMAL);
+            self.register(INTERRUPT_ENABLE).write(interrupts);
+        }
+        self.config = config;
+        Ok(())
+    }
+    fn line_status(&self) -> u8{
+        unsafe{self.register(LINE_STATUS).read()}
+    }
+    pub(crate) fn send(&mut self, byte: u8){
+        while self.line_status() & LINE_STATUS_TRANSMIT_EMPTY == 0{
+            core::hint::spin_loop();
+        }
+        unsafe{self.register(DATA).write(byte)};
+    }
+    pub(crate) fn try_receive(&mut self) -> Option<u8>{
+        if self.line_status() & LINE_STATUS_DATA_READY != 0{
+            Some(unsafe{self.register(DATA).read()})
+        } else {
+            None
+        }
+    }
+}
+impl fmt::Write for Uart{
+    fn write_str(&mut self, s: &str) -> fmt::Result{
+        for byte in s.bytes(){
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
 lazy_static!{
-    ///Serial port struct, to be used to print to serial (Mutex)
-    pub static ref SERIAL1 : Mutex<SerialPort> = {
-        let mut serial_port = unsafe{SerialPort::new(0x3F8)};
-        serial_port.init();
-        Mutex::new(serial_port)
-    };
+    ///The UARTs found at the COM ports, in `ComPort` order. Probing also enables their receive interrupts.
+    static ref UARTS: [Mutex<Option<Uart>>; 4] = ComPort::ALL.map(|port| Mutex::new(unsafe{Uart::probe(port.base())}));
+}
 
+///Run `f` on the UART of a port with interrupts disabled; `None` if the port is not present.
+fn with_uart<R>(port: ComPort, f: impl FnOnce(&mut Uart) -> R) -> Option<R>{
+    interrupts::without_interrupts(||{
+        UARTS[port.index()].lock().as_mut().map(f)
+    })
 }
+
+///Probe the COM ports now rather than on first use, so they raise receive interrupts before anything is printed.
+pub fn init(){
+    lazy_static::initialize(&UARTS);
+}
+
+///Read a received byte from a port, if there is one.
+///
+///Used by the interrupt handlers, which read until the receive FIFOs are empty.
+pub(crate) fn read_byte_if_ready(port: ComPort) -> Option<u8>{
+    UARTS[port.index()].lock().as_mut()?.try_receive()
+}
+
+///What a port is dedicated to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role{
+    ///Output of `serial_print!`.
+    Log,
+    ///The serial shell.
+    Console,
+    ///A remote debugger.
+    Debugger,
+}
+impl Role{
+    pub const ALL: [Role; 3] = [Role::Log, Role::Console, Role::Debugger];
+    pub fn name(self) -> &'static str{
+        match self{
+            Role::Log => "log",
+            Role::Console => "console",
+            Role::Debugger => "debugger",
+        }
+    }
+}
+
+///Port of each role, in `Role` order.
+static ROLES: Mutex<[Option<ComPort>; 3]> = Mutex::new([Some(ComPort::Com1), Some(ComPort::Com1), None]);
+///Woken when a role is given another port.
+static ROLES_WAKER: AtomicWaker = AtomicWaker::new();
+
+///Dedicate a port to a role, or give the role no port.
+pub fn assign(role: Role, port: Option<ComPort>){
+    interrupts::without_interrupts(||{
+        ROLES.lock()[role as usize] = port;
+    });
+    ROLES_WAKER.wake();
+}
+///Wake `waker` the next time a role is assigned. Only the latest waker registered is woken, which is enough for
+///the serial shell, the only task following the roles.
+pub fn register_role_waker(waker: &Waker){
+    ROLES_WAKER.register(waker);
+}
+///The port dedicated to a role, if it has one.
+pub fn port_for(role: Role) -> Option<ComPort>{
+    interrupts::without_interrupts(||{
+        ROLES.lock()[role as usize]
+    })
+}
+
+///Write to the log port without waiting for any lock, for panics and fatal exceptions. COM1 is used if the
+///roles are locked, and a port whose UART is locked is written to anyway.
+pub(crate) fn emergency_write(text: &str){
+    let port = try_port_for(Role::Log).unwrap_or(Some(ComPort::Com1));
+    if let Some(mut uart) = port.and_then(unlocked_uart){
+        for byte in text.bytes(){
+            uart.send(byte);
+        }
+    }
+}
+///The port of a role, or `None` if the roles are locked.
+pub(crate) fn try_port_for(role: Role) -> Option<Option<ComPort>>{
+    ROLES.try_lock().map(|roles| roles[role as usize])
+}
+///The UART of a port without taking its lock, for code that stops the kernel wherever it is; `None` if the port
+///is not present.
+pub(crate) fn unlocked_uart(port: ComPort) -> Option<Uart>{
+    if let Some(uart) = UARTS[port.index()].try_lock(){
+        if uart.is_none(){
+            return None;
+        }
+    }
+    Some(Uart{base: port.base(), config: LineConfig::default()})
+}
+
+///Terminal on the other end of a port, for the serial shell. Line feeds are sent as CR LF, and '\x08'
+///erases the previous character.
+pub struct SerialTerminal{
+    port: ComPort,
+}
+impl SerialTerminal{
+    pub fn new(port: ComPort) -> SerialTerminal{
+        SerialTerminal{port}
+    }
+}
+impl fmt::Write for SerialTerminal{
+    fn write_str(&mut self, s: &str) -> fmt::Result{
+        with_uart(self.port, |uart|{
+            for byte in s.bytes(){
+                match byte{
+                    b'\n' => uart.write_str("\r\n"),
+                    0x08 => uart.write_str("\x08 \x08"),
+                    byte => {
+                        uart.send(byte);
+                        Ok(())
+                    },
+                }?;
+            }
+            Ok(())
+        }).unwrap_or(Ok(()))
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
-    interrupts::without_interrupts(||{
-        SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
-    })
+    if let Some(mut port) = port_for(Role::Log){
+        port.write_fmt(args).expect("Printing to serial failed");
+    }
 }
 
 /// Prints to the host through the serial interface.
@@ -34,4 +426,25 @@ macro_rules! serial_println {
     ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
         concat!($fmt, "\n"), $($arg)*));
-}
\ No newline at end of file
+}
+
+//----------TEST CASES------------
+#[test_case]
+fn line_settings_encode(){
+    let config = LineConfig::default().with_format("7e2").unwrap();
+    assert_eq!((config.data_bits, config.parity, config.stop_bits), (7, Parity::Even, StopBits::Two));
+    assert_eq!(config.line_control(), Ok(0x02 | 0x04 | 0x18));
+    assert_eq!(LineConfig{baud_rate: 9600, ..config}.divisor(), Ok(12));
+    assert_eq!(LineConfig{baud_rate: 1000, ..config}.divisor(), Err(SerialError::UnsupportedBaudRate));
+    assert_eq!(LineConfig{baud_rate: 1, ..config}.divisor(), Err(SerialError::UnsupportedBaudRate));
+    assert_eq!(LineConfig{data_bits: 9, ..config}.line_control(), Err(SerialError::UnsupportedDataBits));
+    assert_eq!(LineConfig::default().with_format("8X1"), None);
+    assert_eq!(alloc::format!("{}", LineConfig::default()), "38400 8N1");
+}
+#[test_case]
+fn com1_is_detected(){
+    //the test runner reports over COM1
+    assert!(ComPort::Com1.is_present());
+    assert_eq!(ComPort::from_name("com1"), Some(ComPort::Com1));
+    assert_eq!(ComPort::Com4.configure(LineConfig::default()).is_ok(), ComPort::Com4.is_present());
+}
@@ -0,0 +1,401 @@
+//! A minimal line-based command shell fed by the keyboard task.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use crate::{desktop, fb_console, framebuffer, key_conversion, logger, profiler, serial, trace, watchdog};
+use crate::serial::{ComPort, LineConfig, Role};
+
+///A shell command: its name, a one line description and the function running it, which prints to `out`.
+struct Command{
+    name: &'static str,
+    help: &'static str,
+    run: fn(out: &mut dyn Write, args: &[&str]),
+}
+
+///All commands known to the shell.
+static COMMANDS: &[Command] = &[
+    Command{name: "help", help: "list available commands", run: cmd_help},
+    Command{name: "layout", help: "layout [code] - show or switch the keyboard layout", run: cmd_layout},
+    Command{name: "mode", help: "mode [text|WxH] - show or switch the display mode", run: cmd_mode},
+    Command{name: "desktop", help: "desktop [WxH] - show the desktop, 640x480 by default", run: cmd_desktop},
+    Command{name: "dmesg", help: "show the kernel log", run: cmd_dmesg},
+    Command{name: "logfile", help: "show the log file, which keeps more and more detailed records than dmesg", run: cmd_logfile},
+    Command{name: "loglevel", help: "loglevel [SINK LEVEL] - show or set where log records are shown", run: cmd_loglevel},
+    Command{name: "monitor", help: "stop the kernel in the monitor", run: cmd_monitor},
+    Command{name: "profile", help: "profile [start [TICKS] | stop | flat [N] | stacks] - sample where the kernel spends its time", run: cmd_profile},
+    Command{name: "trace", help: "trace [start | stop | dump | on|off NAME] - record kernel events for a trace viewer", run: cmd_trace},
+    Command{name: "watchdog", help: "watchdog [on|off | budget MS | stall MS | action log|panic|reboot] - show or set up the watchdog", run: cmd_watchdog},
+    Command{name: "serial", help: "serial [COMn BAUD [8N1] | ROLE COMn|none] - show or set up the serial ports", run: cmd_serial},
+];
+
+///A command that reads a block of text after its line, up to a line holding only ".", e.g. a file pasted into a
+///serial terminal.
+struct TextCommand{
+    name: &'static str,
+    help: &'static str,
+    run: fn(out: &mut dyn Write, text: &str),
+}
+
+static TEXT_COMMANDS: &[TextCommand] = &[
+    TextCommand{name: "loadkeys", help: "read a keymap file, ended by a line holding only \".\", and switch to it", run: cmd_loadkeys},
+];
+
+///Line editing state of the shell, and the terminal it echoes and prints to.
+///The terminal has to understand '\x08' as erasing the previous character.
+pub struct Shell<W: Write>{
+    line: String,
+    out: W,
+    ///Text command whose text is being read, and the lines read so far.
+    reading: Option<(&'static TextCommand, String)>,
+}
+impl<W: Write> Shell<W>{
+    pub fn new(out: W) -> Shell<W>{
+        Shell{line: String::new(), out, reading: None}
+    }
+    ///Print the prompt, unless the lines typed are the text of a command.
+    pub fn prompt(&mut self){
+        if self.reading.is_none(){
+            let _ = write!(self.out, "> ");
+        }
+    }
+    ///The terminal the shell prints to.
+    pub fn output(&mut self) -> &mut W{
+        &mut self.out
+    }
+    ///Feed one character into the shell. Echoes it, and runs the line on enter.
+    pub fn handle_char(&mut self, c: char){
+        match c{
+            '\n' => {
+                let _ = writeln!(self.out);
+                self.execute();
+                self.line.clear();
+                self.prompt();
+            },
+            '\x08' => {
+                if self.line.pop().is_some(){
+                    let _ = write!(self.out, "\x08");
+                }
+            },
+            c => {
+                self.line.push(c);
+                let _ = write!(self.out, "{}", c);
+            },
+        }
+    }
+    fn execute(&mut self){
+        if let Some((command, text)) = &mut self.reading{
+            if self.line.trim() == "."{
+                let run = command.run;
+                let text = core::mem::take(text);
+                self.reading = None;
+                run(&mut self.out, &text);
+            } else {
+                text.push_str(&self.line);
+                text.push('\n');
+            }
+            return;
+        }
+        let args: Vec<&str> = self.line.split_whitespace().collect();
+        let name = match args.first(){
+            Some(name) => *name,
+            None => return,
+        };
+        if let Some(command) = COMMANDS.iter().find(|command| command.name == name){
+            (command.run)(&mut self.out, &args[1..]);
+        } else if let Some(command) = TEXT_COMMANDS.iter().find(|command| command.name == name){
+            self.reading = Some((command, String::new()));
+        } else {
+            let _ = writeln!(self.out, "unknown command: {}", name);
+        }
+    }
+}
+
+fn cmd_help(out: &mut dyn Write, _args: &[&str]){
+    for command in COMMANDS{
+        let _ = writeln!(out, "{:<10}{}", command.name, command.help);
+    }
+    for command in TEXT_COMMANDS{
+        let _ = writeln!(out, "{:<10}{}", command.name, command.help);
+    }
+}
+fn cmd_layout(out: &mut dyn Write, args: &[&str]){
+    let _ = match args.first(){
+        Some(code) => match key_conversion::set_layout(code){
+            Ok(()) => writeln!(out, "keyboard layout: {}", code),
+            Err(_) => writeln!(out, "unknown layout: {}", code),
+        },
+        None => {
+            let current = key_conversion::current_layout();
+            for (code, name) in key_conversion::available_layouts(){
+                let marker = if code == current {'*'} else {' '};
+                let _ = writeln!(out, "{} {:<4}{}", marker, code, name);
+            }
+            Ok(())
+        },
+    };
+}
+///Parse a screen size written as "WxH".
+fn parse_size(text: &str) -> Option<(usize, usize)>{
+    let (width, height) = text.split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
+fn cmd_loadkeys(out: &mut dyn Write, text: &str){
+    let _ = match key_conversion::load_layout(text){
+        Ok(code) => match key_conversion::set_layout(&code){
+            Ok(()) => writeln!(out, "keyboard layout: {}", code),
+            Err(_) => writeln!(out, "unknown layout: {}", code),
+        },
+        Err(error) => writeln!(out, "keymap line {}: {:?}", error.line, error.kind),
+    };
+}
+fn cmd_mode(out: &mut dyn Write, args: &[&str]){
+    ///Bits per pixel of the graphics modes set by the shell.
+    const DEPTH: u8 = 32;
+    let _ = match args.first(){
+        Some(&"text") => {
+            fb_console::disable();
+            framebuffer::text_mode();
+            writeln!(out, "text mode")
+        },
+        Some(mode) => {
+            match parse_size(mode){
+                Some((width, height)) => {
+                    fb_console::disable();
+                    match framebuffer::set_mode(width, height, DEPTH){
+                        Ok(()) => {
+                            fb_console::enable();
+                            writeln!(out, "graphics mode {}x{}", width, height)
+                        },
+                        Err(error) => {
+                            framebuffer::text_mode();
+                            writeln!(out, "cannot set {}x{}: {:?}", width, height, error)
+                        },
+                    }
+                },
+                None => writeln!(out, "usage: mode [text|WxH]"),
+            }
+        },
+        None => match framebuffer::current_mode(){
+            Some((width, height, depth)) => writeln!(out, "graphics mode {}x{}, {} bits per pixel", width, height, depth),
+            None => writeln!(out, "text mode"),
+        },
+    };
+}
+fn cmd_desktop(out: &mut dyn Write, args: &[&str]){
+    let size = match args.first(){
+        Some(mode) => parse_size(mode),
+        None => Some((640, 480)),
+    };
+    let _ = match size{
+        Some((width, height)) => match desktop::start(width, height){
+            Ok(()) => Ok(()),
+            Err(error) => {
+                framebuffer::text_mode();
+                writeln!(out, "cannot show the desktop at {}x{}: {:?}", width, height, error)
+            },
+        },
+        None => writeln!(out, "usage: desktop [WxH]"),
+    };
+}
+fn cmd_serial(out: &mut dyn Write, args: &[&str]){
+    const USAGE: &str = "usage: serial [COMn BAUD [8N1] | ROLE COMn|none]";
+    let role = args.first().and_then(|name| Role::ALL.iter().copied().find(|role| role.name() == *name));
+    let _ = match (args, role){
+        ([], _) => {
+            for port in ComPort::ALL{
+                let roles: Vec<&str> = Role::ALL.iter()
+                    .filter(|&&role| serial::port_for(role) == Some(port))
+                    .map(|role| role.name())
+                    .collect();
+                let _ = match port.config(){
+                    Some(config) => writeln!(out, "{} {} {}", port.name(), config, roles.join(", ")),
+                    None => writeln!(out, "{} not present", port.name()),
+                };
+            }
+            Ok(())
+        },
+        ([_, "none"], Some(role)) => {
+            serial::assign(role, None);
+            Ok(())
+        },
+        ([_, name], Some(role)) => match ComPort::from_name(name).filter(|port| port.is_present()){
+            Some(port) => {
+                serial::assign(role, Some(port));
+                Ok(())
+            },
+            None => writeln!(out, "no such port: {}", name),
+        },
+        ([name, baud_rate, format @ ..], None) if format.len() <= 1 => {
+            let port = ComPort::from_name(name);
+            let config = port.and_then(|port| port.config())
+                .zip(baud_rate.parse().ok())
+                .map(|(config, baud_rate)| LineConfig{baud_rate, ..config})
+                .and_then(|config| match format.first(){
+                    Some(format) => config.with_format(format),
+                    None => Some(config),
+                });
+            match (port, config){
+                (Some(port), Some(config)) => match port.configure(config){
+                    Ok(()) => writeln!(out, "{} {}", port.name(), config),
+                    Err(error) => writeln!(out, "cannot set up {}: {:?}", port.name(), error),
+                },
+                (Some(port), None) if !port.is_present() => writeln!(out, "{} not present", port.name()),
+                _ => writeln!(out, "{}", USAGE),
+            }
+        },
+        _ => writeln!(out, "{}", USAGE),
+    };
+}
+fn cmd_monitor(_out: &mut dyn Write, _args: &[&str]){
+    x86_64::instructions::interrupts::int3();
+}
+fn cmd_profile(out: &mut dyn Write, args: &[&str]){
+    ///Functions shown by `profile flat` unless told otherwise.
+    const FLAT_LINES: usize = 20;
+    let _ = match args{
+        [] => writeln!(out, "{}, sampling every {} ms, {} samples, {} dropped",
+                       if profiler::is_running() {"running"} else {"stopped"},
+                       profiler::interval() * crate::interrupts::PIT_MS_PER_INTERRUPT,
+                       profiler::sample_count(), profiler::dropped_count()),
+        ["start"] => {
+            profiler::start(profiler::DEFAULT_INTERVAL);
+            Ok(())
+        },
+        ["start", ticks] => match ticks.parse(){
+            Ok(ticks) if ticks > 0 => {
+                profiler::start(ticks);
+                Ok(())
+            },
+            _ => writeln!(out, "not a number of timer ticks: {}", ticks),
+        },
+        ["stop"] => {
+            profiler::stop();
+            Ok(())
+        },
+        ["flat"] => profiler::write_flat(out, FLAT_LINES),
+        ["flat", lines] => match lines.parse(){
+            Ok(lines) => profiler::write_flat(out, lines),
+            Err(_) => writeln!(out, "not a number: {}", lines),
+        },
+        //collapsed stacks are long and meant for the host, so they go to the log port rather than the terminal
+        ["stacks"] => match serial::port_for(Role::Log){
+            Some(mut port) => {
+                let _ = writeln!(port, "--- collapsed stacks ---");
+                let _ = profiler::write_collapsed(&mut port);
+                let _ = writeln!(port, "--- end ---");
+                writeln!(out, "collapsed stacks written to {}", port.name())
+            },
+            None => writeln!(out, "no port has the log role"),
+        },
+        _ => writeln!(out, "usage: profile [start [TICKS] | stop | flat [N] | stacks]"),
+    };
+}
+fn cmd_trace(out: &mut dyn Write, args: &[&str]){
+    let _ = match args{
+        [] => {
+            let _ = writeln!(out, "{}, {} events, {} dropped", if trace::is_tracing() {"tracing"} else {"stopped"},
+                             trace::event_count(), trace::dropped_count());
+            for tracepoint in trace::TRACEPOINTS{
+                let _ = writeln!(out, "{:<12}{}", tracepoint.name, if tracepoint.is_enabled() {"on"} else {"off"});
+            }
+            Ok(())
+        },
+        ["start"] => {
+            trace::start();
+            Ok(())
+        },
+        ["stop"] => {
+            trace::stop();
+            Ok(())
+        },
+        [state @ ("on" | "off"), name] => match trace::tracepoint(name){
+            Some(tracepoint) => {
+                tracepoint.set_enabled(*state == "on");
+                Ok(())
+            },
+            None => writeln!(out, "no such tracepoint: {}", name),
+        },
+        //like collapsed stacks, the trace is meant for the host
+        ["dump"] => match serial::port_for(Role::Log){
+            Some(mut port) => {
+                trace::stop();
+                let _ = writeln!(port, "--- trace ---");
+                let _ = trace::write_chrome_json(&mut port);
+                let _ = writeln!(port, "--- end ---");
+                writeln!(out, "tracing stopped, trace written to {}", port.name())
+            },
+            None => writeln!(out, "no port has the log role"),
+        },
+        _ => writeln!(out, "usage: trace [start | stop | dump | on|off NAME]"),
+    };
+}
+fn cmd_watchdog(out: &mut dyn Write, args: &[&str]){
+    let _ = match args{
+        [] => writeln!(out, "{}, task budget {} ms, stall limit {} ms, action {}",
+                       if watchdog::is_enabled() {"on"} else {"off"},
+                       watchdog::task_budget(), watchdog::stall_limit(), watchdog::action()),
+        ["on"] => {
+            watchdog::set_enabled(true);
+            Ok(())
+        },
+        ["off"] => {
+            watchdog::set_enabled(false);
+            Ok(())
+        },
+        [setting @ ("budget" | "stall"), milliseconds] => match milliseconds.parse(){
+            Ok(milliseconds) if *setting == "budget" => {
+                watchdog::set_task_budget(milliseconds);
+                Ok(())
+            },
+            Ok(milliseconds) => {
+                watchdog::set_stall_limit(milliseconds);
+                Ok(())
+            },
+            Err(_) => writeln!(out, "not a number of milliseconds: {}", milliseconds),
+        },
+        ["action", action] => match action.parse(){
+            Ok(action) => {
+                watchdog::set_action(action);
+                Ok(())
+            },
+            Err(()) => writeln!(out, "actions are log, panic and reboot"),
+        },
+        _ => writeln!(out, "usage: watchdog [on|off | budget MS | stall MS | action log|panic|reboot]"),
+    };
+}
+fn cmd_dmesg(out: &mut dyn Write, _args: &[&str]){
+    let _ = logger::dump(out);
+}
+fn cmd_logfile(out: &mut dyn Write, _args: &[&str]){
+    let _ = out.write_str(&logger::log_file());
+}
+fn cmd_loglevel(out: &mut dyn Write, args: &[&str]){
+    let _ = match args{
+        [] => {
+            logger::for_each_sink(|name, level|{
+                let _ = writeln!(out, "{:<10}{}", name, level);
+            });
+            Ok(())
+        },
+        [sink, level] => match level.parse(){
+            Ok(level) if logger::set_sink_level(sink, level) => Ok(()),
+            Ok(_) => writeln!(out, "no such sink: {}", sink),
+            Err(_) => writeln!(out, "levels are off, error, warn, info, debug and trace"),
+        },
+        _ => writeln!(out, "usage: loglevel [SINK LEVEL]"),
+    };
+}
+
+//----------TEST CASES------------
+#[test_case]
+fn loadkeys_reads_keymap_until_dot(){
+    let previous = key_conversion::current_layout();
+    let mut shell = Shell::new(String::new());
+    for c in "loadkeys\nname = Test\ncode = tt\n0x10 q Q\n.\n".chars(){
+        shell.handle_char(c);
+    }
+    assert!(shell.output().ends_with("keyboard layout: tt\n> "));
+    assert_eq!(key_conversion::current_layout(), "tt");
+    key_conversion::set_layout(&previous).unwrap();
+}
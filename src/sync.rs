@@ -0,0 +1,188 @@
+//! A spin lock that knows its owner, and data kept per CPU.
+//!
+//! `Mutex` wraps `spin::Mutex` and, in debug builds, records which CPU holds it and where it was locked. Locking
+//! it again on the CPU that holds it can never succeed, e.g. when an interrupt handler prints while the code it
+//! interrupted is printing, so instead of spinning forever this panics and names both places.
+//!
+//! `PerCpu` gives each CPU its own value, e.g. a trace buffer only that CPU writes to.
+
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicU64, Ordering};
+
+///Owner value of a lock no CPU holds.
+const NO_OWNER: u32 = u32::MAX;
+
+///APIC ID of the CPU, read by the first `cpu_id`; `NO_OWNER` until then.
+///
+///The kernel only runs on the boot CPU, so one value does. Once other CPUs are started, this has to become
+///per-CPU data, e.g. behind the GS base.
+static CPU_ID: AtomicU32 = AtomicU32::new(NO_OWNER);
+
+///Local APIC ID of the CPU running this code. CPUID is slow, especially in a VM, so it is only run once.
+pub fn cpu_id() -> u32{
+    match CPU_ID.load(Ordering::Relaxed){
+        NO_OWNER => {
+            #[allow(unused_unsafe)]
+            let info = unsafe{core::arch::x86_64::__cpuid(1)};
+            let id = info.ebx >> 24;
+            CPU_ID.store(id, Ordering::Relaxed);
+            id
+        },
+        id => id,
+    }
+}
+
+///CPUs that get per-CPU data.
+pub const MAX_CPUS: usize = 4;
+
+///One `T` for each CPU, indexed by its APIC ID. CPUs with higher IDs have none.
+pub struct PerCpu<T>{
+    values: [T; MAX_CPUS],
+    ///Times a CPU without a value asked for it.
+    misses: AtomicU64,
+}
+impl<T> PerCpu<T>{
+    pub const fn new(values: [T; MAX_CPUS]) -> PerCpu<T>{
+        PerCpu{values, misses: AtomicU64::new(0)}
+    }
+    ///The value of the CPU running this code, counting a miss if it has none.
+    pub fn current(&self) -> Option<&T>{
+        let value = self.values.get(cpu_id() as usize);
+        if value.is_none(){
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        value
+    }
+    ///Every value, with the APIC ID of its CPU.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &T)>{
+        self.values.iter().enumerate()
+    }
+    ///Times `current` found no value, e.g. records dropped for lack of a buffer.
+    pub fn misses(&self) -> u64{
+        self.misses.load(Ordering::Relaxed)
+    }
+    pub fn reset_misses(&self){
+        self.misses.store(0, Ordering::Relaxed);
+    }
+}
+
+pub struct Mutex<T: ?Sized>{
+    ///CPU holding the lock, or `NO_OWNER`. Only kept in debug builds.
+    owner: AtomicU32,
+    ///Where the lock was taken.
+    location: AtomicPtr<Location<'static>>,
+    inner: spin::Mutex<T>,
+}
+impl<T> Mutex<T>{
+    pub const fn new(value: T) -> Mutex<T>{
+        Mutex{owner: AtomicU32::new(NO_OWNER), location: AtomicPtr::new(ptr::null_mut()), inner: spin::Mutex::new(value)}
+    }
+}
+impl<T: ?Sized> Mutex<T>{
+    ///Take the lock, spinning until it is free.
+    ///
+    ///Panics in debug builds if this CPU already holds it.
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T>{
+        if let Some(guard) = self.try_lock(){
+            return guard;
+        }
+        if cfg!(debug_assertions){
+            match (self.owner.load(Ordering::Relaxed), self.locked_at()){
+                //the lock is between being taken and its owner being recorded. Only this CPU runs the kernel, so
+                //it is code this CPU interrupted
+                (NO_OWNER, _) => panic!("deadlock: {} locked again at {} while it was being locked",
+                                        core::any::type_name::<T>(), Location::caller()),
+                (owner, Some(location)) if owner == cpu_id() =>
+                    panic!("deadlock: {} locked again at {}, CPU {} holds it since {}",
+                           core::any::type_name::<T>(), Location::caller(), owner, location),
+                (owner, None) if owner == cpu_id() => panic!("deadlock: {} locked again at {} by the CPU holding it",
+                                                             core::any::type_name::<T>(), Location::caller()),
+                _ => {},
+            }
+        }
+        let guard = self.inner.lock();
+        self.acquired(guard)
+    }
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>>{
+        self.inner.try_lock().map(|guard| self.acquired(guard))
+    }
+    #[track_caller]
+    fn acquired<'a>(&'a self, guard: spin::MutexGuard<'a, T>) -> MutexGuard<'a, T>{
+        if cfg!(debug_assertions){
+            self.owner.store(cpu_id(), Ordering::Relaxed);
+            self.location.store(Location::caller() as *const _ as *mut _, Ordering::Relaxed);
+        }
+        MutexGuard{mutex: self, guard: ManuallyDrop::new(guard)}
+    }
+    ///Where the lock was last taken, in debug builds.
+    pub fn locked_at(&self) -> Option<&'static Location<'static>>{
+        let location = self.location.load(Ordering::Relaxed);
+        unsafe{location.as_ref()}
+    }
+    ///Release the lock without a guard.
+    ///
+    ///# Safety
+    ///Whoever holds the guard could still be using the data. Only meant for code that never returns to the
+    ///holder, like a panic handler.
+    pub unsafe fn force_unlock(&self){
+        self.inner.force_unlock();
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+    }
+}
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T>{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        match self.try_lock(){
+            Some(guard) => f.debug_struct("Mutex").field("data", &&*guard).finish(),
+            None => f.debug_struct("Mutex").field("locked_at", &self.locked_at()).finish(),
+        }
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized>{
+    mutex: &'a Mutex<T>,
+    ///Dropped by hand, before the owner is forgotten.
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+}
+impl<T: ?Sized> Deref for MutexGuard<'_, T>{
+    type Target = T;
+    fn deref(&self) -> &T{
+        &self.guard
+    }
+}
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T>{
+    fn deref_mut(&mut self) -> &mut T{
+        &mut self.guard
+    }
+}
+impl<T: ?Sized> Drop for MutexGuard<'_, T>{
+    ///Release the lock, then forget the owner. In between, the lock is free while still naming this CPU, which
+    ///`lock` never mistakes for a deadlock as `try_lock` succeeds first.
+    fn drop(&mut self){
+        unsafe{ManuallyDrop::drop(&mut self.guard)};
+        //another CPU may have taken the lock meanwhile
+        let _ = self.mutex.owner.compare_exchange(cpu_id(), NO_OWNER, Ordering::Relaxed, Ordering::Relaxed);
+    }
+}
+
+//----------TEST CASES------------
+#[test_case]
+fn mutex_records_owner(){
+    static LOCK: Mutex<u32> = Mutex::new(1);
+    {
+        let mut guard = LOCK.lock();
+        *guard += 1;
+        assert!(LOCK.try_lock().is_none());
+        if cfg!(debug_assertions){
+            assert_eq!(LOCK.owner.load(Ordering::Relaxed), cpu_id());
+            assert_eq!(LOCK.locked_at().map(|location| location.file()), Some(file!()));
+        }
+    }
+    assert_eq!(LOCK.owner.load(Ordering::Relaxed), NO_OWNER);
+    assert_eq!(*LOCK.lock(), 2);
+}
@@ -2,6 +2,7 @@ use super::{TaskId, Task};
 use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
 use core::task::{Waker, Context, Poll};
 use crossbeam_queue::ArrayQueue;
+use crate::trace;
 
 pub struct Executor{
     tasks: BTreeMap<TaskId, Task>,
@@ -20,6 +21,7 @@ impl Executor{
     pub fn run(&mut self) -> !{
         loop {
             self.run_ready_tasks();
+            crate::watchdog::check_timer();
             self.sleep_if_idle();
         }
     }
@@ -35,6 +37,7 @@ impl Executor{
     }
     pub fn spawn(&mut self, task: Task){
         let task_id = task.id;
+        super::list_task(&task);
         if self.tasks.insert(task_id, task).is_some(){
             panic!("task with same ID already exists in queue")
         }
@@ -53,8 +56,14 @@ impl Executor{
                 .entry(task_id)
                 .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
             let mut context = Context::from_waker(waker);
-            match task.poll(&mut context){
+            super::set_running(Some(task_id));
+            trace::TASK_POLL.hit(task_id.0, 0);
+            let poll = task.poll(&mut context);
+            trace::TASK_POLLED.hit(task_id.0, poll.is_ready() as u64);
+            super::set_running(None);
+            match poll{
                 Poll::Ready(()) => {
+                    super::unlist_task(task_id);
                     tasks.remove(&task_id);
                     waker_cache.remove(&task_id);
                 },
@@ -84,6 +93,7 @@ impl TaskWaker{
         }))
     }
     fn wake_task(&self){
+        trace::TASK_WAKE.hit(self.task_id.0, 0);
         self.task_queue.push(self.task_id).expect("Task queue full")
     }
 }
\ No newline at end of file
@@ -1,25 +1,31 @@
 use conquer_once::spin::OnceCell;
 use crossbeam_queue::ArrayQueue;
 use core::{pin::Pin, task::{Poll, Context}};
-use core::iter::Scan;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
 use futures_util::stream::{Stream, StreamExt};
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, KeyEvent, KeyState, Keyboard, ScancodeSet1};
 use futures_util::task::AtomicWaker;
-use crate::key_conversion::KEYMAP_DE;
-use crate::{println, vga_buffer};
-use crate::print;
+use lazy_static::lazy_static;
+use crate::sync::Mutex;
+use crate::key_conversion::{self, KeyDecoder, LockState, ModifierMask};
+use crate::shell::Shell;
+use crate::ps2;
+use crate::vga_buffer;
+use core::fmt::Write;
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 
 pub(crate) fn add_scancode(scancode: u8){
     if let Ok(queue) = SCANCODE_QUEUE.try_get(){
-        if let Err(_) = queue.push(scancode) {
-            println!("warning: scancode queue full. dropping input.");
+        if queue.push(scancode).is_err() {
+            log::warn!("scancode queue full. dropping input.");
         } else{
             WAKER.wake(); //if a waker is registered, wake it. Otherwise, this is a nop
         }
     } else{
-        println!("warning: queue uninitialised");
+        log::warn!("scancode queue uninitialised. dropping input.");
     }
 }
 
@@ -44,7 +50,7 @@ impl Stream for ScancodeStream{
             return Poll::Ready(Some(scancode));
         }
 
-        WAKER.register(&cx.waker());
+        WAKER.register(cx.waker());
         match queue.pop(){
             Ok(scancode) => {
                 WAKER.take();
@@ -57,23 +63,263 @@ impl Stream for ScancodeStream{
 
 static WAKER: AtomicWaker = AtomicWaker::new();
 
-///Asynchronously print the queued key presses.
-pub async fn print_key_presses(){
+///A decoded key press or release, with the modifier and lock state after the event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyboardEvent{
+    pub code: KeyCode,
+    pub state: KeyState,
+    pub modifiers: ModifierMask,
+    pub locks: LockState,
+    ///Key as decoded by the current layout; only set for presses.
+    pub key: Option<DecodedKey>,
+}
+impl KeyboardEvent{
+    ///The character this event produced, if any.
+    pub fn character(&self) -> Option<char>{
+        match self.key{
+            Some(DecodedKey::Unicode(c)) => Some(c),
+            _ => None,
+        }
+    }
+}
+
+///Queue and waker of a single `KeyEventStream`.
+struct Subscriber{
+    queue: ArrayQueue<KeyboardEvent>,
+    waker: AtomicWaker,
+    ///Console whose input this is; `None` receives input regardless of the active console.
+    console: Option<usize>,
+}
+
+///A key combination that runs an action instead of being delivered to subscribers.
+struct Hotkey{
+    modifiers: ModifierMask,
+    code: KeyCode,
+    action: fn(),
+}
+impl Hotkey{
+    ///Whether an event presses the hotkey.
+    fn matches(&self, event: &KeyboardEvent) -> bool{
+        event.state == KeyState::Down && event.code == self.code && event.modifiers == self.modifiers
+    }
+}
+
+lazy_static!{
+    ///Every live `KeyEventStream`. Dropped streams are pruned when the next event is published.
+    static ref SUBSCRIBERS: Mutex<Vec<Weak<Subscriber>>> = Mutex::new(Vec::new());
+    ///Global hotkeys, matched against presses with exactly the given modifiers held.
+    static ref HOTKEYS: Mutex<Vec<Hotkey>> = Mutex::new(Vec::from([
+        Hotkey{modifiers: ModifierMask::CTRL | ModifierMask::ALT, code: KeyCode::Delete, action: reboot},
+        Hotkey{modifiers: ModifierMask::ALT | ModifierMask::SHIFT, code: KeyCode::ShiftLeft, action: cycle_layout},
+        Hotkey{modifiers: ModifierMask::ALT | ModifierMask::SHIFT, code: KeyCode::ShiftRight, action: cycle_layout},
+        Hotkey{modifiers: ModifierMask::ALT | ModifierMask::SHIFT, code: KeyCode::AltLeft, action: cycle_layout},
+        Hotkey{modifiers: ModifierMask::SHIFT, code: KeyCode::PageUp, action: vga_buffer::scroll_up},
+        Hotkey{modifiers: ModifierMask::SHIFT, code: KeyCode::PageDown, action: vga_buffer::scroll_down},
+        Hotkey{modifiers: ModifierMask::ALT, code: KeyCode::F1, action: console_1},
+        Hotkey{modifiers: ModifierMask::ALT, code: KeyCode::F2, action: console_2},
+        Hotkey{modifiers: ModifierMask::ALT, code: KeyCode::F3, action: console_3},
+        Hotkey{modifiers: ModifierMask::ALT, code: KeyCode::F4, action: console_4},
+        Hotkey{modifiers: ModifierMask::ALT, code: KeyCode::F5, action: console_5},
+        Hotkey{modifiers: ModifierMask::ALT, code: KeyCode::F6, action: console_6},
+    ]));
+}
+
+///Whether console streams are cut off from input, e.g. while the desktop is shown.
+static CONSOLE_INPUT_SUSPENDED: AtomicBool = AtomicBool::new(false);
+
+///Stop or resume delivering events to the streams of virtual consoles. Streams made with `KeyEventStream::new`
+///still get every event.
+pub fn suspend_console_input(suspended: bool){
+    CONSOLE_INPUT_SUSPENDED.store(suspended, Ordering::Relaxed);
+}
+
+fn reboot(){
+    crate::reboot();
+}
+fn cycle_layout(){
+    key_conversion::cycle_layout();
+}
+fn console_1(){ vga_buffer::switch_console(0); }
+fn console_2(){ vga_buffer::switch_console(1); }
+fn console_3(){ vga_buffer::switch_console(2); }
+fn console_4(){ vga_buffer::switch_console(3); }
+fn console_5(){ vga_buffer::switch_console(4); }
+fn console_6(){ vga_buffer::switch_console(5); }
+
+///Run `action` whenever `code` is pressed while exactly `modifiers` are held.
+///The key press is not delivered to any `KeyEventStream`.
+pub fn register_hotkey(modifiers: ModifierMask, code: KeyCode, action: fn()){
+    HOTKEYS.lock().push(Hotkey{modifiers, code, action});
+}
+
+///Stream of keyboard events. Every stream receives every event published after it was created,
+///unless it belongs to a console that is not active.
+pub struct KeyEventStream{
+    subscriber: Arc<Subscriber>,
+}
+impl KeyEventStream{
+    ///Stream receiving all keyboard events.
+    pub fn new() -> KeyEventStream{
+        KeyEventStream::subscribe(None)
+    }
+    ///Stream receiving the keyboard events typed while the given virtual console is active.
+    pub fn for_console(console: usize) -> KeyEventStream{
+        KeyEventStream::subscribe(Some(console))
+    }
+    fn subscribe(console: Option<usize>) -> KeyEventStream{
+        let subscriber = Arc::new(Subscriber{
+            queue: ArrayQueue::new(100),
+            waker: AtomicWaker::new(),
+            console,
+        });
+        SUBSCRIBERS.lock().push(Arc::downgrade(&subscriber));
+        KeyEventStream{subscriber}
+    }
+}
+impl Default for KeyEventStream{
+    fn default() -> KeyEventStream{
+        KeyEventStream::new()
+    }
+}
+impl Stream for KeyEventStream{
+    type Item = KeyboardEvent;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<KeyboardEvent>> {
+        let subscriber = &self.subscriber;
+        if let Ok(event) = subscriber.queue.pop(){
+            return Poll::Ready(Some(event));
+        }
+        subscriber.waker.register(cx.waker());
+        match subscriber.queue.pop(){
+            Ok(event) => {
+                subscriber.waker.take();
+                Poll::Ready(Some(event))
+            },
+            Err(crossbeam_queue::PopError) => Poll::Pending,
+        }
+    }
+}
+
+///Deliver an event to every live subscriber listening to the active console, unless console input is suspended.
+fn publish(event: KeyboardEvent){
+    let active = vga_buffer::active_console();
+    let suspended = CONSOLE_INPUT_SUSPENDED.load(Ordering::Relaxed);
+    let mut subscribers = SUBSCRIBERS.lock();
+    subscribers.retain(|subscriber| subscriber.strong_count() > 0);
+    for subscriber in subscribers.iter().filter_map(Weak::upgrade){
+        if subscriber.console.is_some_and(|console| suspended || console != active){
+            continue;
+        }
+        if subscriber.queue.push(event).is_err(){
+            log::warn!("key event queue full. dropping event.");
+        } else {
+            subscriber.waker.wake();
+        }
+    }
+}
+
+///Decode a key event, noting the modifiers and lock keys after it.
+fn keyboard_event(decoder: &mut KeyDecoder, key_event: KeyEvent) -> KeyboardEvent{
+    let (code, state) = (key_event.code, key_event.state);
+    let key = decoder.process_keyevent(key_event);
+    KeyboardEvent{
+        code,
+        state,
+        modifiers: decoder.modifier_mask(),
+        locks: decoder.lock_state(),
+        key,
+    }
+}
+///Action of the hotkey an event presses, if any.
+fn hotkey_action(event: &KeyboardEvent) -> Option<fn()>{
+    HOTKEYS.lock().iter().find(|hotkey| hotkey.matches(event)).map(|hotkey| hotkey.action)
+}
+
+///Decode queued scancodes into keyboard events, run hotkeys and publish the rest.
+pub async fn dispatch_key_events(){
     let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(layouts::Uk105Key, ScancodeSet1, HandleControl::Ignore);
+    //the layout only tells pc_keyboard how to split bytes into key events; decoding happens in KeyDecoder
+    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
+    let mut decoder = KeyDecoder::new();
+    let mut locks = decoder.lock_state();
+    let _ = ps2::set_leds(locks);
     while let Some(scancode) = scancodes.next().await{
         if let Ok(Some(key_event)) = keyboard.add_byte(scancode){
-            if let Some(key) = keyboard.process_keyevent(key_event){
-                match key{
-                    //TODO: write keyboard layout switching logic; global keymap var? files? todo.
-                    DecodedKey::Unicode(character) =>
-                        match KEYMAP_DE.convert_char(character){
-                            '\x08' => vga_buffer::WRITER.lock().backspace(0),
-                            c => print!("{}", c)},
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
+            let event = keyboard_event(&mut decoder, key_event);
+            //a dead key that did not compose gives its accent with the event for the key typed after it, which
+            //follows as an event of its own
+            let queued = decoder.take_queued().map(|key| KeyboardEvent{key: Some(key), ..event});
+            if event.locks != locks{
+                locks = event.locks;
+                if let Err(error) = ps2::set_leds(locks){
+                    log::warn!("could not update keyboard LEDs: {:?}", error);
                 }
             }
+            if let Some(action) = hotkey_action(&event){
+                action();
+                continue;
+            }
+            publish(event);
+            if let Some(queued) = queued{
+                publish(queued);
+            }
+        }
+    }
+}
+
+///Asynchronously feed the key presses typed on a virtual console to a shell running on it.
+pub async fn console_shell(console: usize){
+    let mut events = KeyEventStream::for_console(console);
+    let mut shell = Shell::new(vga_buffer::Console::new(console));
+    shell.prompt();
+    while let Some(event) = events.next().await{
+        match event.key{
+            Some(DecodedKey::Unicode(character)) => shell.handle_char(character),
+            Some(DecodedKey::RawKey(key)) => {
+                let _ = write!(shell.output(), "{:?}", key);
+            },
+            None => {},
         }
     }
+}
 
-}
\ No newline at end of file
+//----------TEST CASES------------
+///Feed presses and releases through a decoder, returning the (modifiers, key) of the hotkey the last one presses.
+#[cfg(test)]
+fn hotkey_after(keys: &[(KeyCode, KeyState)]) -> Option<(ModifierMask, KeyCode)>{
+    let mut decoder = KeyDecoder::new();
+    let mut event = None;
+    for &(code, state) in keys{
+        event = Some(keyboard_event(&mut decoder, KeyEvent::new(code, state)));
+    }
+    let event = event?;
+    HOTKEYS.lock().iter().find(|hotkey| hotkey.matches(&event)).map(|hotkey| (hotkey.modifiers, hotkey.code))
+}
+#[test_case]
+fn modifier_mask_combines(){
+    let mask = ModifierMask::CTRL | ModifierMask::ALT;
+    assert!(mask.contains(ModifierMask::CTRL));
+    assert!(mask.contains(ModifierMask::NONE));
+    assert!(!mask.contains(ModifierMask::CTRL | ModifierMask::SHIFT));
+    assert_eq!(ModifierMask::default(), ModifierMask::NONE);
+}
+#[test_case]
+fn hotkeys_match_exact_modifiers(){
+    use KeyState::{Down, Up};
+    let ctrl_alt = ModifierMask::CTRL | ModifierMask::ALT;
+    assert_eq!(hotkey_after(&[(KeyCode::ControlLeft, Down), (KeyCode::AltLeft, Down), (KeyCode::Delete, Down)]),
+               Some((ctrl_alt, KeyCode::Delete)));
+    //right control counts as control, but the release of the key or an extra modifier does not match
+    assert_eq!(hotkey_after(&[(KeyCode::ControlRight, Down), (KeyCode::AltLeft, Down), (KeyCode::Delete, Down)]),
+               Some((ctrl_alt, KeyCode::Delete)));
+    assert_eq!(hotkey_after(&[(KeyCode::ControlLeft, Down), (KeyCode::AltLeft, Down), (KeyCode::Delete, Up)]), None);
+    assert_eq!(hotkey_after(&[(KeyCode::ControlLeft, Down), (KeyCode::AltLeft, Down), (KeyCode::ShiftLeft, Down),
+                              (KeyCode::Delete, Down)]), None);
+    assert_eq!(hotkey_after(&[(KeyCode::ControlLeft, Down), (KeyCode::ControlLeft, Up), (KeyCode::AltLeft, Down),
+                              (KeyCode::Delete, Down)]), None);
+    //Alt+Shift in either order
+    let alt_shift = ModifierMask::ALT | ModifierMask::SHIFT;
+    assert_eq!(hotkey_after(&[(KeyCode::AltLeft, Down), (KeyCode::ShiftLeft, Down)]),
+               Some((alt_shift, KeyCode::ShiftLeft)));
+    assert_eq!(hotkey_after(&[(KeyCode::ShiftRight, Down), (KeyCode::AltLeft, Down)]),
+               Some((alt_shift, KeyCode::AltLeft)));
+}
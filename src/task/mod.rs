@@ -4,9 +4,12 @@ use alloc::boxed::Box;
 use futures_util::task::AtomicWaker;
 use lazy_static::lazy_static;
 use crate::task::executor::Executor;
+use crate::sync::Mutex;
 
 pub mod simple_executor;
 pub mod keyboard;
+pub mod mouse;
+pub mod serial;
 pub mod executor;
 
 
@@ -22,10 +25,13 @@ impl TaskId{
 pub struct Task{
     future: Pin<Box<dyn Future<Output= ()>>>,
     id: TaskId,
+    ///Type name of the future, which names the async fn it came from.
+    name: &'static str,
 }
 impl Task{
     pub fn new(future: impl Future<Output=()> + 'static) ->Task{
         Task{
+            name: core::any::type_name_of_val(&future),
             future: Box::pin(future),
             id: TaskId::new(),
         }
@@ -33,4 +39,76 @@ impl Task{
     pub fn poll(&mut self, context: &mut Context) -> Poll<()>{
         self.future.as_mut().poll(context)
     }
-}
\ No newline at end of file
+}
+
+///Most tasks the task list holds; later ones still run but are not listed.
+const LISTED_TASKS: usize = 64;
+const NO_TASK: u64 = u64::MAX;
+
+///A spawned task, as debuggers list it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskInfo{
+    pub id: u64,
+    pub name: &'static str,
+    ///How often the executor polled it.
+    pub polls: u64,
+}
+
+///The tasks the executor runs, kept up to date by it.
+static TASK_LIST: Mutex<[Option<TaskInfo>; LISTED_TASKS]> = Mutex::new([None; LISTED_TASKS]);
+///Task the executor is polling, or `NO_TASK`.
+static RUNNING_TASK: AtomicU64 = AtomicU64::new(NO_TASK);
+///When the executor started polling the running task, in milliseconds since boot.
+static POLL_STARTED: AtomicU64 = AtomicU64::new(0);
+
+fn list_task(task: &Task){
+    let mut list = TASK_LIST.lock();
+    if let Some(slot) = list.iter_mut().find(|slot| slot.is_none()){
+        *slot = Some(TaskInfo{id: task.id.0, name: task.name, polls: 0});
+    }
+}
+fn unlist_task(id: TaskId){
+    for slot in TASK_LIST.lock().iter_mut(){
+        if slot.is_some_and(|info| info.id == id.0){
+            *slot = None;
+        }
+    }
+}
+///Mark a task as being polled, or none with `None`.
+fn set_running(id: Option<TaskId>){
+    if let Some(id) = id{
+        if let Some(info) = TASK_LIST.lock().iter_mut().flatten().find(|info| info.id == id.0){
+            info.polls += 1;
+        }
+    }
+    POLL_STARTED.store(crate::interrupts::milliseconds_elapsed(), Ordering::Relaxed);
+    RUNNING_TASK.store(id.map_or(NO_TASK, |id| id.0), Ordering::Relaxed);
+}
+
+///Run `f` on every listed task. Returns false without doing so if the list is being changed, which happens when
+///the kernel is stopped in the middle of the executor.
+pub fn try_for_each_task(f: impl FnMut(&TaskInfo)) -> bool{
+    match TASK_LIST.try_lock(){
+        Some(list) => {
+            list.iter().flatten().for_each(f);
+            true
+        },
+        None => false,
+    }
+}
+///The ID of the task being polled, if any.
+pub fn running_task() -> Option<u64>{
+    match RUNNING_TASK.load(Ordering::Relaxed){
+        NO_TASK => None,
+        id => Some(id),
+    }
+}
+///The ID of the task being polled and when its poll started, in milliseconds since boot.
+pub fn running_since() -> Option<(u64, u64)>{
+    let started = POLL_STARTED.load(Ordering::Relaxed);
+    running_task().map(|id| (id, started))
+}
+///Name of a listed task, or `None` if it is not listed or the list is being changed.
+pub fn try_task_name(id: u64) -> Option<&'static str>{
+    TASK_LIST.try_lock()?.iter().flatten().find(|info| info.id == id).map(|info| info.name)
+}
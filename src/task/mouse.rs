@@ -0,0 +1,178 @@
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use core::{pin::Pin, task::{Poll, Context}};
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+use crate::ps2::{self, DeviceType, Ps2Port};
+
+static MOUSE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+pub(crate) fn add_mouse_byte(byte: u8){
+    if let Ok(queue) = MOUSE_QUEUE.try_get(){
+        if queue.push(byte).is_err(){
+            log::warn!("mouse queue full. dropping input.");
+        } else{
+            WAKER.wake();
+        }
+    }
+}
+
+///Set of pressed mouse buttons.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseButtons(u8);
+impl MouseButtons{
+    pub const NONE: MouseButtons = MouseButtons(0);
+    pub const LEFT: MouseButtons = MouseButtons(1);
+    pub const RIGHT: MouseButtons = MouseButtons(1 << 1);
+    pub const MIDDLE: MouseButtons = MouseButtons(1 << 2);
+    pub const FOURTH: MouseButtons = MouseButtons(1 << 3);
+    pub const FIFTH: MouseButtons = MouseButtons(1 << 4);
+    ///Whether every button in `other` is pressed.
+    pub fn contains(self, other: MouseButtons) -> bool{
+        self.0 & other.0 == other.0
+    }
+}
+
+///Movement and button state reported by one mouse packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent{
+    ///Horizontal movement, positive to the right.
+    pub dx: i16,
+    ///Vertical movement, positive downwards (screen direction, unlike the raw PS/2 packet).
+    pub dy: i16,
+    ///Wheel movement, positive towards the user.
+    pub wheel: i8,
+    pub buttons: MouseButtons,
+}
+
+///Packet protocol spoken by the mouse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseProtocol{
+    ///Three byte packets.
+    Standard,
+    ///Four byte packets with a wheel.
+    IntelliMouse,
+    ///Four byte packets with a wheel and buttons 4 and 5.
+    IntelliMouseExplorer,
+}
+
+///Assembles mouse bytes into packets and decodes them.
+pub struct MousePacketDecoder{
+    protocol: MouseProtocol,
+    packet: [u8; 4],
+    received: usize,
+}
+impl MousePacketDecoder{
+    pub fn new(protocol: MouseProtocol) -> MousePacketDecoder{
+        MousePacketDecoder{protocol, packet: [0; 4], received: 0}
+    }
+    fn packet_len(&self) -> usize{
+        match self.protocol{
+            MouseProtocol::Standard => 3,
+            MouseProtocol::IntelliMouse | MouseProtocol::IntelliMouseExplorer => 4,
+        }
+    }
+    ///Add a byte; returns the event once a packet is complete.
+    pub fn add_byte(&mut self, byte: u8) -> Option<MouseEvent>{
+        //bit 3 of the first byte is always set; skip bytes until we are back in sync
+        if self.received == 0 && byte & 0x08 == 0{
+            return None;
+        }
+        self.packet[self.received] = byte;
+        self.received += 1;
+        if self.received < self.packet_len(){
+            return None;
+        }
+        self.received = 0;
+        self.decode()
+    }
+    fn decode(&self) -> Option<MouseEvent>{
+        let [flags, x, y, extra] = self.packet;
+        //overflowed movement is garbage
+        if flags & 0xc0 != 0{
+            return None;
+        }
+        let dx = x as i16 - (((flags as i16) << 4) & 0x100);
+        let dy = y as i16 - (((flags as i16) << 3) & 0x100);
+        let mut buttons = MouseButtons(flags & 0x07);
+        let wheel = match self.protocol{
+            MouseProtocol::Standard => 0,
+            MouseProtocol::IntelliMouse => extra as i8,
+            MouseProtocol::IntelliMouseExplorer => {
+                buttons = MouseButtons(buttons.0 | (extra >> 1) & 0x18);
+                ((extra << 4) as i8) >> 4
+            },
+        };
+        Some(MouseEvent{dx, dy: -dy, wheel, buttons})
+    }
+}
+
+///Stream of decoded mouse events.
+pub struct MouseEventStream{
+    decoder: MousePacketDecoder,
+}
+impl MouseEventStream{
+    ///Create the stream, using the protocol the PS/2 driver detected on the second port.
+    pub fn new() -> MouseEventStream{
+        MOUSE_QUEUE.try_init_once(|| ArrayQueue::new(100))
+            .expect("MouseEventStream::new should only be called once.");
+        let protocol = match ps2::CONTROLLER.lock().device(Ps2Port::Second){
+            Some(DeviceType::WheelMouse) => MouseProtocol::IntelliMouse,
+            Some(DeviceType::FiveButtonMouse) => MouseProtocol::IntelliMouseExplorer,
+            _ => MouseProtocol::Standard,
+        };
+        MouseEventStream{decoder: MousePacketDecoder::new(protocol)}
+    }
+}
+impl Default for MouseEventStream{
+    fn default() -> MouseEventStream{
+        MouseEventStream::new()
+    }
+}
+impl Stream for MouseEventStream{
+    type Item = MouseEvent;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<MouseEvent>> {
+        let queue = MOUSE_QUEUE.try_get().expect("cannot get queue, possibly uninitialised");
+        loop{
+            let byte = match queue.pop(){
+                Ok(byte) => byte,
+                Err(crossbeam_queue::PopError) => {
+                    WAKER.register(cx.waker());
+                    match queue.pop(){
+                        Ok(byte) => {
+                            WAKER.take();
+                            byte
+                        },
+                        Err(crossbeam_queue::PopError) => return Poll::Pending,
+                    }
+                },
+            };
+            if let Some(event) = self.decoder.add_byte(byte){
+                return Poll::Ready(Some(event));
+            }
+        }
+    }
+}
+
+//----------TEST CASES------------
+#[test_case]
+fn decode_standard_packet(){
+    let mut decoder = MousePacketDecoder::new(MouseProtocol::Standard);
+    //left button, x = -2, y = +3 (up)
+    assert_eq!(decoder.add_byte(0x19), None);
+    assert_eq!(decoder.add_byte(0xfe), None);
+    let event = decoder.add_byte(0x03).unwrap();
+    assert_eq!((event.dx, event.dy, event.buttons), (-2, -3, MouseButtons::LEFT));
+}
+#[test_case]
+fn decode_wheel_and_resync(){
+    let mut decoder = MousePacketDecoder::new(MouseProtocol::IntelliMouse);
+    //a stray byte without the always-one bit is skipped
+    assert_eq!(decoder.add_byte(0x00), None);
+    for byte in [0x08, 0x01, 0x00]{
+        assert_eq!(decoder.add_byte(byte), None);
+    }
+    let event = decoder.add_byte(0xff).unwrap();
+    assert_eq!((event.dx, event.dy, event.wheel), (1, 0, -1));
+}
@@ -0,0 +1,209 @@
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use core::{future::Future, pin::Pin, task::{Poll, Context}};
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::task::AtomicWaker;
+use crate::serial::{self, ComPort, Role, SerialTerminal};
+use crate::shell::Shell;
+
+///Received bytes of each port, in `ComPort` order.
+static SERIAL_QUEUES: [OnceCell<ArrayQueue<u8>>; 4] = [OnceCell::uninit(), OnceCell::uninit(), OnceCell::uninit(), OnceCell::uninit()];
+static WAKERS: [AtomicWaker; 4] = [AtomicWaker::new(), AtomicWaker::new(), AtomicWaker::new(), AtomicWaker::new()];
+
+pub(crate) fn add_byte(port: ComPort, byte: u8){
+    if let Ok(queue) = SERIAL_QUEUES[port as usize].try_get(){
+        if queue.push(byte).is_err() {
+            log::warn!("serial queue full. dropping input.");
+        } else{
+            WAKERS[port as usize].wake();
+        }
+    }
+}
+
+///Stream of the bytes received on a port.
+///
+///A port can get a new stream after the one before was dropped, but only one should be read at a time, as they
+///share the queue of the port.
+pub struct SerialStream{
+    port: ComPort,
+    ///Role the stream ends at once it moves to another port.
+    role: Option<Role>,
+}
+impl SerialStream{
+    ///Create the stream of a port. Bytes received before are dropped.
+    pub fn new(port: ComPort) -> SerialStream{
+        let queue = SERIAL_QUEUES[port as usize].get_or_init(|| ArrayQueue::new(256));
+        while queue.pop().is_ok(){}
+        SerialStream{port, role: None}
+    }
+    ///Create the stream of the port a role has, which ends when the role is given another port.
+    pub fn for_role(role: Role, port: ComPort) -> SerialStream{
+        SerialStream{role: Some(role), ..SerialStream::new(port)}
+    }
+}
+impl Stream for SerialStream{
+    type Item = u8;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let index = self.port as usize;
+        if let Some(role) = self.role{
+            serial::register_role_waker(cx.waker());
+            if serial::port_for(role) != Some(self.port){
+                return Poll::Ready(None);
+            }
+        }
+        let queue = SERIAL_QUEUES[index].try_get().expect("cannot get queue, possibly uninitialised");
+        if let Ok(byte) = queue.pop(){
+            return Poll::Ready(Some(byte));
+        }
+        WAKERS[index].register(cx.waker());
+        match queue.pop(){
+            Ok(byte) => {
+                WAKERS[index].take();
+                Poll::Ready(Some(byte))
+            },
+            Err(crossbeam_queue::PopError) => Poll::Pending,
+        }
+    }
+}
+
+///Where the decoder is within an escape sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EscapeState{
+    None,
+    ///After ESC.
+    Escape,
+    ///After ESC [, until the final byte.
+    ControlSequence,
+    ///After ESC O, which is followed by a single byte.
+    SingleShift,
+}
+
+///Turns the bytes typed on a terminal into characters for the shell: UTF-8 is decoded, CR and CR LF become
+///'\n', DEL becomes '\x08', and escape sequences (such as the cursor keys) and other control bytes are dropped.
+pub struct TerminalDecoder{
+    utf8: [u8; 4],
+    utf8_len: usize,
+    escape: EscapeState,
+    after_cr: bool,
+}
+impl TerminalDecoder{
+    pub fn new() -> TerminalDecoder{
+        TerminalDecoder{utf8: [0; 4], utf8_len: 0, escape: EscapeState::None, after_cr: false}
+    }
+    pub fn add_byte(&mut self, byte: u8) -> Option<char>{
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        match self.escape{
+            EscapeState::None => {},
+            EscapeState::Escape => {
+                self.escape = match byte{
+                    b'[' => EscapeState::ControlSequence,
+                    b'O' => EscapeState::SingleShift,
+                    _ => EscapeState::None,
+                };
+                return None;
+            },
+            EscapeState::ControlSequence => {
+                if (0x40..=0x7e).contains(&byte){
+                    self.escape = EscapeState::None;
+                }
+                return None;
+            },
+            EscapeState::SingleShift => {
+                self.escape = EscapeState::None;
+                return None;
+            },
+        }
+        match byte{
+            0x1b => {
+                self.utf8_len = 0;
+                self.escape = EscapeState::Escape;
+                None
+            },
+            b'\r' => Some('\n'),
+            b'\n' if after_cr => None,
+            b'\n' => Some('\n'),
+            0x08 | 0x7f => Some('\x08'),
+            0x00..=0x1f => None,
+            0x20..=0x7e => {
+                self.utf8_len = 0;
+                Some(byte as char)
+            },
+            _ => {
+                self.utf8[self.utf8_len] = byte;
+                self.utf8_len += 1;
+                match core::str::from_utf8(&self.utf8[..self.utf8_len]){
+                    Ok(text) => {
+                        self.utf8_len = 0;
+                        text.chars().next()
+                    },
+                    //an incomplete sequence waits for more bytes
+                    Err(error) if error.error_len().is_none() && self.utf8_len < 4 => None,
+                    Err(_) => {
+                        self.utf8_len = 0;
+                        Some(char::REPLACEMENT_CHARACTER)
+                    },
+                }
+            },
+        }
+    }
+}
+impl Default for TerminalDecoder{
+    fn default() -> TerminalDecoder{
+        TerminalDecoder::new()
+    }
+}
+
+///Resolves to the port a role is given once it is not `port` anymore.
+struct RoleMoved{
+    role: Role,
+    port: Option<ComPort>,
+}
+impl Future for RoleMoved{
+    type Output = Option<ComPort>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<ComPort>>{
+        serial::register_role_waker(cx.waker());
+        match serial::port_for(self.role){
+            port if port != self.port => Poll::Ready(port),
+            _ => Poll::Pending,
+        }
+    }
+}
+
+///Asynchronously feed the characters received on the console port to a shell answering on it. When the console
+///is moved to another port, e.g. with the `serial` command, a new shell is started there.
+pub async fn serial_shell(){
+    let mut port = serial::port_for(Role::Console);
+    loop{
+        if let Some(console) = port.filter(|port| port.is_present()){
+            let mut bytes = SerialStream::for_role(Role::Console, console);
+            let mut decoder = TerminalDecoder::new();
+            let mut shell = Shell::new(SerialTerminal::new(console));
+            shell.prompt();
+            while let Some(byte) = bytes.next().await{
+                if let Some(c) = decoder.add_byte(byte){
+                    shell.handle_char(c);
+                }
+            }
+        }
+        port = RoleMoved{role: Role::Console, port}.await;
+    }
+}
+
+//----------TEST CASES------------
+#[test_case]
+fn terminal_line_endings_and_erase(){
+    let mut decoder = TerminalDecoder::new();
+    let decoded: alloc::string::String = b"ls\r\nab\x7f\rx\n".iter().filter_map(|&byte| decoder.add_byte(byte)).collect();
+    assert_eq!(decoded, "ls\nab\x08\nx\n");
+}
+#[test_case]
+fn terminal_utf8_and_escape_sequences(){
+    let mut decoder = TerminalDecoder::new();
+    //cursor up, F1 and a colour sequence are dropped
+    let input = "a\x1b[A\x1bOP\x1b[1;31mä€\u{1f600}".as_bytes();
+    let decoded: alloc::string::String = input.iter().filter_map(|&byte| decoder.add_byte(byte)).collect();
+    assert_eq!(decoded, "aä€\u{1f600}");
+    //a stray continuation byte is replaced
+    assert_eq!(decoder.add_byte(0x80), Some(char::REPLACEMENT_CHARACTER));
+    assert_eq!(decoder.add_byte(b'b'), Some('b'));
+}
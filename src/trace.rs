@@ -0,0 +1,233 @@
+//! Event tracing through static tracepoints.
+//!
+//! A tracepoint is a static placed in the code whose events are wanted, e.g. interrupts entered and left, tasks
+//! polled and woken, allocations and page faults. While tracing, hitting an enabled tracepoint writes an event,
+//! stamped with the TSC, into a ring buffer of the CPU hitting it; otherwise a hit costs two loads. The buffers are
+//! written without locks (see `ring`), so tracepoints can be anywhere, including interrupt handlers and the
+//! allocator. They are exported in the Chrome trace event format, which chrome://tracing and Perfetto load.
+
+use core::arch::x86_64::_rdtsc;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::ring::SeqRing;
+use crate::sync::{PerCpu, MAX_CPUS};
+use crate::{task, watchdog};
+
+///Events kept per CPU; older ones are overwritten.
+pub const EVENTS_PER_CPU: usize = 4096;
+
+///How a tracepoint's events show up in a trace viewer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase{
+    ///Starts a span, which the next `End` event on the CPU ends.
+    Begin,
+    End,
+    ///A point in time.
+    Instant,
+}
+
+pub struct Tracepoint{
+    ///Unique name, used to enable and disable it.
+    pub name: &'static str,
+    ///Name of its events in a trace viewer; spans are named after their beginning, followed by its first value.
+    pub event: &'static str,
+    pub phase: Phase,
+    ///Names of the two values recorded with an event, empty if unused. A value named "task" is a task ID.
+    pub args: [&'static str; 2],
+    enabled: AtomicBool,
+}
+impl Tracepoint{
+    pub const fn new(name: &'static str, event: &'static str, phase: Phase, args: [&'static str; 2]) -> Tracepoint{
+        Tracepoint{name, event, phase, args, enabled: AtomicBool::new(true)}
+    }
+    ///Record an event with two values, if tracing and the tracepoint is enabled.
+    #[inline]
+    pub fn hit(&'static self, first: u64, second: u64){
+        if TRACING.load(Ordering::Relaxed) && self.enabled.load(Ordering::Relaxed){
+            record(self, [first, second]);
+        }
+    }
+    pub fn is_enabled(&self) -> bool{
+        self.enabled.load(Ordering::Relaxed)
+    }
+    pub fn set_enabled(&self, enabled: bool){
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+}
+
+pub static IRQ_ENTER: Tracepoint = Tracepoint::new("irq_enter", "irq", Phase::Begin, ["vector", ""]);
+pub static IRQ_EXIT: Tracepoint = Tracepoint::new("irq_exit", "irq", Phase::End, ["vector", ""]);
+pub static TASK_POLL: Tracepoint = Tracepoint::new("task_poll", "poll", Phase::Begin, ["task", ""]);
+pub static TASK_POLLED: Tracepoint = Tracepoint::new("task_polled", "poll", Phase::End, ["task", "ready"]);
+pub static TASK_WAKE: Tracepoint = Tracepoint::new("task_wake", "wake", Phase::Instant, ["task", ""]);
+pub static ALLOC: Tracepoint = Tracepoint::new("alloc", "alloc", Phase::Instant, ["size", "address"]);
+pub static DEALLOC: Tracepoint = Tracepoint::new("dealloc", "dealloc", Phase::Instant, ["size", "address"]);
+pub static PAGE_FAULT: Tracepoint = Tracepoint::new("page_fault", "page fault", Phase::Instant, ["address", "error"]);
+
+///All tracepoints, which are the only ones events are read back for.
+pub static TRACEPOINTS: [&Tracepoint; 8] = [
+    &IRQ_ENTER, &IRQ_EXIT, &TASK_POLL, &TASK_POLLED, &TASK_WAKE, &ALLOC, &DEALLOC, &PAGE_FAULT,
+];
+
+///Events of a CPU, each the TSC, the address of the tracepoint and the two values. CPUs without one drop theirs.
+static BUFFERS: PerCpu<SeqRing<EVENTS_PER_CPU, 4>> = PerCpu::new([const {SeqRing::new()}; MAX_CPUS]);
+static TRACING: AtomicBool = AtomicBool::new(false);
+
+fn record(tracepoint: &'static Tracepoint, args: [u64; 2]){
+    if let Some(buffer) = BUFFERS.current(){
+        buffer.push(|| [unsafe{_rdtsc()}, tracepoint as *const Tracepoint as u64, args[0], args[1]]);
+    }
+}
+
+///An event read from a buffer.
+#[derive(Clone, Copy)]
+struct Event{
+    ///TSC when it happened.
+    timestamp: u64,
+    tracepoint: &'static Tracepoint,
+    args: [u64; 2],
+}
+impl Event{
+    ///Read an event; `None` if it was overwritten or is still being written.
+    fn get(buffer: &SeqRing<EVENTS_PER_CPU, 4>, sequence: u64) -> Option<Event>{
+        let [timestamp, tracepoint, first, second] = buffer.get(sequence)?;
+        let tracepoint = TRACEPOINTS.iter().copied().find(|&known| known as *const Tracepoint as u64 == tracepoint)?;
+        Some(Event{timestamp, tracepoint, args: [first, second]})
+    }
+}
+
+///Throw the events away and start tracing.
+pub fn start(){
+    TRACING.store(false, Ordering::SeqCst);
+    for (_, buffer) in BUFFERS.iter(){
+        buffer.clear();
+    }
+    BUFFERS.reset_misses();
+    TRACING.store(true, Ordering::SeqCst);
+}
+///Stop tracing, keeping the events.
+pub fn stop(){
+    TRACING.store(false, Ordering::SeqCst);
+}
+pub fn is_tracing() -> bool{
+    TRACING.load(Ordering::Relaxed)
+}
+///The tracepoint with a name.
+pub fn tracepoint(name: &str) -> Option<&'static Tracepoint>{
+    TRACEPOINTS.iter().copied().find(|tracepoint| tracepoint.name == name)
+}
+///Events in the buffers, counting those overwritten since tracing started.
+pub fn event_count() -> u64{
+    BUFFERS.iter().map(|(_, buffer)| buffer.head()).sum()
+}
+///Events dropped since tracing started.
+pub fn dropped_count() -> u64{
+    BUFFERS.misses()
+}
+
+///Call `f` with the number of every CPU that has events, and each of them in order.
+fn for_each_event(mut f: impl FnMut(usize, &Event) -> fmt::Result) -> fmt::Result{
+    for (cpu, buffer) in BUFFERS.iter(){
+        for sequence in buffer.oldest()..buffer.head(){
+            if let Some(event) = Event::get(buffer, sequence){
+                f(cpu, &event)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+///Writes a string as the contents of a JSON string.
+struct JsonEscaped<'a>(&'a str);
+impl fmt::Display for JsonEscaped<'_>{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        for c in self.0.chars(){
+            match c{
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => f.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+///Write the events as a Chrome trace: one thread per CPU, spans for the `Begin` and `End` tracepoints and instant
+///events for the others. Stop tracing first, or the events written meanwhile may be left out.
+pub fn write_chrome_json(out: &mut dyn Write) -> fmt::Result{
+    //before the TSC is calibrated, pretend it counts nanoseconds
+    let cycles_per_ms = watchdog::cycles_per_ms().unwrap_or(1_000_000);
+    let mut start = u64::MAX;
+    for_each_event(|_, event|{
+        start = start.min(event.timestamp);
+        Ok(())
+    })?;
+    writeln!(out, "{{\"displayTimeUnit\":\"ns\",\"traceEvents\":[")?;
+    let mut cpus = [false; MAX_CPUS];
+    let mut first = true;
+    for_each_event(|cpu, event|{
+        if !first{
+            writeln!(out, ",")?;
+        }
+        first = false;
+        if !cpus[cpu]{
+            cpus[cpu] = true;
+            writeln!(out, "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":0,\"tid\":{},\"args\":{{\"name\":\"CPU {}\"}}}},",
+                     cpu, cpu)?;
+        }
+        let tracepoint = event.tracepoint;
+        let nanoseconds = (event.timestamp - start) as u128 * 1_000_000 / cycles_per_ms as u128;
+        let phase = match tracepoint.phase{
+            Phase::Begin => "B",
+            Phase::End => "E",
+            Phase::Instant => "i",
+        };
+        write!(out, "{{\"name\":\"{}", JsonEscaped(tracepoint.event))?;
+        if tracepoint.phase == Phase::Begin{
+            write!(out, " {}", event.args[0])?;
+            if let Some(name) = task_name(tracepoint.args[0], event.args[0]){
+                write!(out, " {}", JsonEscaped(name))?;
+            }
+        }
+        write!(out, "\",\"cat\":\"{}\",\"ph\":\"{}\",\"ts\":{}.{:03},\"pid\":0,\"tid\":{}",
+               tracepoint.name, phase, nanoseconds / 1000, nanoseconds % 1000, cpu)?;
+        if tracepoint.phase == Phase::Instant{
+            write!(out, ",\"s\":\"t\"")?;
+        }
+        write!(out, ",\"args\":{{")?;
+        for (i, (name, value)) in tracepoint.args.iter().zip(event.args).filter(|(name, _)| !name.is_empty()).enumerate(){
+            if i > 0{
+                write!(out, ",")?;
+            }
+            //addresses are easier to read in hex, which JSON numbers cannot be
+            match *name{
+                "address" => write!(out, "\"{}\":\"{:#x}\"", name, value)?,
+                _ => write!(out, "\"{}\":{}", name, value)?,
+            }
+        }
+        write!(out, "}}}}")
+    })?;
+    writeln!(out, "\n]}}")
+}
+///Name of the task a value is the ID of, if it is one.
+fn task_name(arg: &str, value: u64) -> Option<&'static str>{
+    if arg == "task" {task::try_task_name(value)} else {None}
+}
+
+//----------TEST CASES------------
+#[test_case]
+fn events_are_exported(){
+    x86_64::instructions::interrupts::without_interrupts(||{
+        start();
+        TASK_WAKE.hit(42, 0);
+        PAGE_FAULT.hit(0xdead000, 2);
+        stop();
+    });
+    assert_eq!(event_count(), 2);
+    let mut json = alloc::string::String::new();
+    write_chrome_json(&mut json).unwrap();
+    assert!(json.starts_with("{\"displayTimeUnit\":\"ns\",\"traceEvents\":[\n"));
+    assert!(json.contains("{\"name\":\"wake\",\"cat\":\"task_wake\",\"ph\":\"i\",\"ts\":0.000,\"pid\":0,\"tid\":0,\"s\":\"t\",\"args\":{\"task\":42}},\n"));
+    assert!(json.contains("\"args\":{\"address\":\"0xdead000\",\"error\":2}}\n]}\n"));
+}
@@ -1,6 +1,10 @@
 #![feature(asm)]
 use volatile::Volatile;
-use spin::Mutex;
+use crate::sync::Mutex;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::port::Port;
+use crate::fb_console;
+use crate::ansi::{AnsiAction, AnsiParser, EraseMode, TextAttributes};
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -28,9 +32,17 @@ pub enum Color {
 ///Struct to represent a color code (foreground and background) for VGA mode 3 representation.
 pub struct ColorCode(u8);
 impl ColorCode{
-    pub fn new(foreground:Color, background:Color) -> ColorCode{
+    pub const fn new(foreground:Color, background:Color) -> ColorCode{
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+    ///Colour code for attributes set through ANSI escape sequences.
+    fn from_attributes(attributes: TextAttributes) -> ColorCode{
+        ColorCode((attributes.background & 0xf) << 4 | (attributes.effective_foreground() & 0xf))
+    }
+    ///Attributes showing the same colours, e.g. on the framebuffer console.
+    fn to_attributes(self) -> TextAttributes{
+        TextAttributes::new(self.0 & 0xf, self.0 >> 4)
+    }
 }
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
@@ -39,11 +51,70 @@ struct VGAChar{
     ascii_character : u8,
     color_code : ColorCode
 }
+impl VGAChar{
+    ///An empty cell with the given colour.
+    const fn blank(color_code: ColorCode) -> VGAChar{
+        VGAChar{ascii_character: 0x0, color_code}
+    }
+    ///The character shown, for the framebuffer console.
+    fn to_unicode(self) -> char{
+        match self.ascii_character{
+            0x0 => ' ',
+            byte => cp437_to_unicode(byte),
+        }
+    }
+}
+
+///Glyphs of code page 437 bytes 0x00 to 0x1f; 0x00 is left empty.
+const CP437_LOW: [char; 32] = [
+    '\0', '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼',
+    '►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+///Glyphs of code page 437 bytes 0x80 to 0xff.
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+///Glyph used for characters code page 437 has no equivalent for.
+const CP437_FALLBACK: u8 = 0xfe;
+
+///Translate a character to the code page 437 byte showing it in VGA text mode.
+pub fn unicode_to_cp437(c: char) -> u8{
+    match c{
+        ' '..='~' => c as u8,
+        '⌂' => 0x7f,
+        //characters sharing a glyph with one in the table
+        'β' => 0xe1,
+        'μ' => 0xe6,
+        '\u{2126}' => 0xea, //ohm sign
+        '∈' => 0xee,
+        c => CP437_LOW.iter().skip(1).position(|&glyph| glyph == c).map(|i| i as u8 + 1)
+            .or_else(|| CP437_HIGH.iter().position(|&glyph| glyph == c).map(|i| i as u8 + 0x80))
+            .unwrap_or(CP437_FALLBACK),
+    }
+}
+
+///The character a code page 437 byte shows in VGA text mode. The empty byte 0x00 is a space.
+pub fn cp437_to_unicode(byte: u8) -> char{
+    match byte{
+        0x00 => ' ',
+        0x01..=0x1f => CP437_LOW[byte as usize],
+        0x7f => '⌂',
+        0x80..=0xff => CP437_HIGH[byte as usize - 0x80],
+        byte => byte as char,
+    }
+}
 
 ///Height of VGA mode 3 buffer.
-const BUFFER_WIDTH : usize = 80;
+pub const BUFFER_WIDTH : usize = 80;
 ///Width of VGA mode 3 buffer.
-const BUFFER_HEIGHT : usize = 25;
+pub const BUFFER_HEIGHT : usize = 25;
 
 #[repr(transparent)]
 ///Struct representing the VGA mode 3 buffer.
@@ -63,11 +134,10 @@ impl Writer{
     pub fn new(col_pos: usize, col_code: ColorCode, buf : &'static mut Buffer) -> Writer{
         Writer{column_position: col_pos, color_code: col_code, buffer: buf}
     }
-    ///Write a byte to the Buffer of the writer.
+    ///Write a code page 437 byte to the Buffer of the writer.
     pub fn write_byte(&mut self, byte:u8){
         match byte{
             b'\n' => self.new_line(),
-            0xff => self.backspace(0),
             byte => {
                 if self.column_position >= BUFFER_WIDTH {
                     self.new_line();
@@ -96,26 +166,13 @@ impl Writer{
         }
         self.column_position = 0
     }
-    ///Roll up the buffer and place column_position at the end of a line
-    fn roll_up(&mut self){
-        for y in (0..(BUFFER_HEIGHT-1)).rev(){
-            for x in 0..BUFFER_WIDTH{
-                self.buffer.chars[y+1][x].write(self.buffer.chars[y][x].read());
-                self.buffer.chars[y][x].write(VGAChar{
-                    ascii_character: 0x0,
-                    color_code: self.color_code
-                });
-            }
-        }
-        self.column_position = BUFFER_WIDTH - 1;
-    }
-
-    ///Write a string to the buffer.
+    ///Write a string to the buffer, translating it to code page 437.
     pub fn write_string(&mut self, text : &str){
-        for byte in text.bytes(){
-            match byte{
-                b'\n' | 0x20..=0x7e => self.write_byte(byte),
-                _ => self.write_byte(0xfe)
+        for c in text.chars(){
+            match c{
+                '\n' => self.write_byte(b'\n'),
+                '\x08' => self.backspace(),
+                c => self.write_byte(unicode_to_cp437(c)),
             }
         }
     }
@@ -132,55 +189,567 @@ impl Writer{
     pub fn get_column_position(&mut self) -> usize{
         self.column_position
     }
-    pub fn backspace(&mut self, num_times_rolled_up : usize) {
-        //inelegant hack
-        let mut num_times_rolled_up_new = num_times_rolled_up;
+    ///Erase the character before the cursor on the bottom line.
+    pub fn backspace(&mut self){
         if self.column_position > 0{
             self.column_position -= 1;
-        }else{
-            num_times_rolled_up_new += 1;
-            self.roll_up();
-        }
-        //skip null characters, stop if rolled over the whole screen
-        let null_char = VGAChar{
-            color_code: self.color_code,
-            ascii_character: 0x0
-        };
-        let mut is_empty = false;
-        if self.buffer.chars[BUFFER_HEIGHT - 1][self.column_position].read() == null_char{
-            if num_times_rolled_up_new < 1 {
-                self.backspace(num_times_rolled_up_new);
-            } else {
-                self.column_position = 0;
-                is_empty = true;
-                while self.buffer.chars[BUFFER_HEIGHT - 1][self.column_position].read() != null_char{
-                    self.column_position += 1;
-                }
+            self.buffer.chars[BUFFER_HEIGHT - 1][self.column_position].write(VGAChar::blank(self.color_code));
+        }
+    }
+    ///Write a code page 437 byte with the given colour at a screen cell. Cells off screen are ignored.
+    pub fn write_at(&mut self, row: usize, col: usize, byte: u8, color_code: ColorCode){
+        if row < BUFFER_HEIGHT && col < BUFFER_WIDTH{
+            self.buffer.chars[row][col].write(VGAChar{ascii_character: byte, color_code});
+        }
+    }
+    ///Code page 437 byte and colour of a screen cell.
+    pub fn read_at(&self, row: usize, col: usize) -> Option<(u8, ColorCode)>{
+        if row < BUFFER_HEIGHT && col < BUFFER_WIDTH{
+            let character = self.buffer.chars[row][col].read();
+            Some((character.ascii_character, character.color_code))
+        } else {
+            None
+        }
+    }
+    ///Write a string starting at a screen cell, translating it to code page 437. Text past the end of the row is cut off.
+    pub fn write_str_at(&mut self, row: usize, col: usize, text: &str, color_code: ColorCode){
+        for (offset, c) in text.chars().enumerate(){
+            self.write_at(row, col + offset, unicode_to_cp437(c), color_code);
+        }
+    }
+    ///Fill every cell of a rectangle with the same byte and colour.
+    pub fn fill_rect(&mut self, rect: Rect, byte: u8, color_code: ColorCode){
+        let rect = rect.clipped();
+        for row in rect.row..rect.row + rect.height{
+            for col in rect.col..rect.col + rect.width{
+                self.buffer.chars[row][col].write(VGAChar{ascii_character: byte, color_code});
             }
         }
-        if !is_empty{
-            self.write_byte(0x0);
-            self.column_position -= 1;
+    }
+    ///Blank a rectangle.
+    pub fn clear_rect(&mut self, rect: Rect, color_code: ColorCode){
+        self.fill_rect(rect, 0x0, color_code);
+    }
+    ///Draw the outline of a rectangle with code page 437 line characters. The inside is left untouched.
+    pub fn draw_box(&mut self, rect: Rect, style: BoxStyle, color_code: ColorCode){
+        if rect.width < 2 || rect.height < 2{
+            return;
+        }
+        let [top_left, top_right, bottom_left, bottom_right, horizontal, vertical] = style.glyphs();
+        let (top, bottom) = (rect.row, rect.row + rect.height - 1);
+        let (left, right) = (rect.col, rect.col + rect.width - 1);
+        for col in left + 1..right{
+            self.write_at(top, col, horizontal, color_code);
+            self.write_at(bottom, col, horizontal, color_code);
+        }
+        for row in top + 1..bottom{
+            self.write_at(row, left, vertical, color_code);
+            self.write_at(row, right, vertical, color_code);
         }
+        self.write_at(top, left, top_left, color_code);
+        self.write_at(top, right, top_right, color_code);
+        self.write_at(bottom, left, bottom_left, color_code);
+        self.write_at(bottom, right, bottom_right, color_code);
+    }
+    ///Scroll the contents of a rectangle up by `lines` (down if negative), blanking the rows that are freed.
+    pub fn scroll_region(&mut self, rect: Rect, lines: isize, color_code: ColorCode){
+        let rect = rect.clipped();
+        let shift = lines.unsigned_abs().min(rect.height);
+        for i in 0..rect.height{
+            //copy towards the direction of the scroll so no source row is overwritten before it is read
+            let row = if lines >= 0 {rect.row + i} else {rect.row + rect.height - 1 - i};
+            let source = if lines >= 0 {row + shift} else {row.wrapping_sub(shift)};
+            let in_rect = source >= rect.row && source < rect.row + rect.height;
+            for col in rect.col..rect.col + rect.width{
+                let character = if in_rect {
+                    self.buffer.chars[source][col].read()
+                } else {
+                    VGAChar::blank(color_code)
+                };
+                self.buffer.chars[row][col].write(character);
+            }
+        }
+    }
+}
+
+///A rectangle of screen cells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect{
+    pub row: usize,
+    pub col: usize,
+    pub width: usize,
+    pub height: usize,
+}
+impl Rect{
+    pub const fn new(row: usize, col: usize, width: usize, height: usize) -> Rect{
+        Rect{row, col, width, height}
+    }
+    ///The part of the rectangle that lies on the screen.
+    fn clipped(self) -> Rect{
+        let row = self.row.min(BUFFER_HEIGHT);
+        let col = self.col.min(BUFFER_WIDTH);
+        Rect{
+            row,
+            col,
+            width: self.width.min(BUFFER_WIDTH - col),
+            height: self.height.min(BUFFER_HEIGHT - row),
+        }
+    }
+}
 
+///Line style of boxes drawn with `Writer::draw_box`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BoxStyle{
+    Single,
+    Double,
+}
+impl BoxStyle{
+    ///Code page 437 corners (top left, top right, bottom left, bottom right), then horizontal and vertical lines.
+    fn glyphs(self) -> [u8; 6]{
+        match self{
+            BoxStyle::Single => [0xda, 0xbf, 0xc0, 0xd9, 0xc4, 0xb3],
+            BoxStyle::Double => [0xc9, 0xbb, 0xc8, 0xbc, 0xcd, 0xba],
+        }
     }
 }
 
-const LONG_BUFFER_HEIGHT:usize = 1000 as usize;
+///Ring buffer of console lines; the oldest line is dropped when it is full.
 struct LongBuffer{
-    buffer: [[Volatile<VGAChar>; BUFFER_WIDTH];LONG_BUFFER_HEIGHT]
+    buffer: &'static mut [[VGAChar; BUFFER_WIDTH]],
+    ///Index of the oldest line.
+    start: usize,
+    ///Number of lines in use.
+    len: usize,
+}
+impl LongBuffer{
+    ///A buffer holding one screen of empty lines, keeping at most as many lines as `buffer` has.
+    fn new(buffer: &'static mut [[VGAChar; BUFFER_WIDTH]], color_code: ColorCode) -> LongBuffer{
+        let mut long_buffer = LongBuffer{buffer, start: 0, len: BUFFER_HEIGHT};
+        long_buffer.clear(color_code);
+        long_buffer
+    }
+    ///Index of the newest line.
+    fn last(&self) -> usize{
+        self.len - 1
+    }
+    ///The line with the given index, counted from the oldest line.
+    fn line(&self, index: usize) -> &[VGAChar; BUFFER_WIDTH]{
+        &self.buffer[(self.start + index) % self.buffer.len()]
+    }
+    fn line_mut(&mut self, index: usize) -> &mut [VGAChar; BUFFER_WIDTH]{
+        let capacity = self.buffer.len();
+        &mut self.buffer[(self.start + index) % capacity]
+    }
+    ///Number of written cells at the start of the given line.
+    fn line_len(&self, index: usize) -> usize{
+        self.line(index).iter().rposition(|c| c.ascii_character != 0x0).map_or(0, |i| i + 1)
+    }
+    ///Append an empty line, dropping the oldest one if the buffer is full.
+    fn push_line(&mut self, color_code: ColorCode){
+        if self.len < self.buffer.len(){
+            self.len += 1;
+        } else {
+            self.start = (self.start + 1) % self.buffer.len();
+        }
+        let last = self.last();
+        *self.line_mut(last) = [VGAChar::blank(color_code); BUFFER_WIDTH];
+    }
+    ///Reset to one screen of empty lines.
+    fn clear(&mut self, color_code: ColorCode){
+        self.start = 0;
+        self.len = BUFFER_HEIGHT;
+        for index in 0..BUFFER_HEIGHT{
+            *self.line_mut(index) = [VGAChar::blank(color_code); BUFFER_WIDTH];
+        }
+    }
+}
+const CRTC_ADDRESS_PORT: u16 = 0x3d4;
+const CRTC_DATA_PORT: u16 = 0x3d5;
+const CRTC_CURSOR_START: u8 = 0x0a;
+const CRTC_CURSOR_END: u8 = 0x0b;
+const CRTC_CURSOR_LOCATION_HIGH: u8 = 0x0e;
+const CRTC_CURSOR_LOCATION_LOW: u8 = 0x0f;
+///Bit of the cursor start register that hides the cursor.
+const CURSOR_DISABLE: u8 = 1 << 5;
+///Mask of the scanline bits in the cursor start and end registers.
+const CURSOR_SCANLINE_MASK: u8 = 0x1f;
+
+///The blinking text mode cursor, driven through the CRT controller registers.
+pub struct HardwareCursor{
+    address: Port<u8>,
+    data: Port<u8>,
+}
+impl HardwareCursor{
+    pub const fn new() -> HardwareCursor{
+        HardwareCursor{
+            address: Port::new(CRTC_ADDRESS_PORT),
+            data: Port::new(CRTC_DATA_PORT),
+        }
+    }
+    fn read_register(&mut self, register: u8) -> u8{
+        unsafe{
+            self.address.write(register);
+            self.data.read()
+        }
+    }
+    fn write_register(&mut self, register: u8, value: u8){
+        unsafe{
+            self.address.write(register);
+            self.data.write(value);
+        }
+    }
+    ///Show the cursor as the scanlines `start` to `end` of a character cell (0-15 for the default font).
+    pub fn set_shape(&mut self, start: u8, end: u8){
+        let start_register = self.read_register(CRTC_CURSOR_START) & !(CURSOR_DISABLE | CURSOR_SCANLINE_MASK);
+        self.write_register(CRTC_CURSOR_START, start_register | (start & CURSOR_SCANLINE_MASK));
+        let end_register = self.read_register(CRTC_CURSOR_END) & !CURSOR_SCANLINE_MASK;
+        self.write_register(CRTC_CURSOR_END, end_register | (end & CURSOR_SCANLINE_MASK));
+    }
+    ///Show or hide the cursor, keeping its shape.
+    pub fn set_visible(&mut self, visible: bool){
+        let start_register = self.read_register(CRTC_CURSOR_START);
+        let start_register = if visible {start_register & !CURSOR_DISABLE} else {start_register | CURSOR_DISABLE};
+        self.write_register(CRTC_CURSOR_START, start_register);
+    }
+    ///Move the cursor to a screen cell.
+    pub fn set_position(&mut self, row: usize, col: usize){
+        let position = (row * BUFFER_WIDTH + col) as u16;
+        self.write_register(CRTC_CURSOR_LOCATION_HIGH, (position >> 8) as u8);
+        self.write_register(CRTC_CURSOR_LOCATION_LOW, position as u8);
+    }
+    ///Screen cell the cursor is at, as the CRT controller has it.
+    pub fn position(&mut self) -> (usize, usize){
+        let position = (self.read_register(CRTC_CURSOR_LOCATION_HIGH) as usize) << 8
+            | self.read_register(CRTC_CURSOR_LOCATION_LOW) as usize;
+        (position / BUFFER_WIDTH, position % BUFFER_WIDTH)
+    }
+    ///Whether the cursor is shown, as the CRT controller has it.
+    pub fn is_visible(&mut self) -> bool{
+        self.read_register(CRTC_CURSOR_START) & CURSOR_DISABLE == 0
+    }
+}
+impl Default for HardwareCursor{
+    fn default() -> HardwareCursor{
+        HardwareCursor::new()
+    }
 }
+
 ///Struct enabling double buffered writing to the VGA mode 3 buffer; enables multiple line backspacing, rolling up
+///
+///All output goes into the long buffer; the screen shows a window onto it, which is the newest lines unless
+///the user scrolled back. Output may contain the ANSI escape sequences understood by `ansi::AnsiParser`.
+///The console can be limited to a band of screen rows, leaving the others to the screen API (see `with_screen`).
+///
+///Every virtual console has one; all of their writers point at the VGA buffer, but only the active console
+///touches it. The others just record their output in the long buffer until they are switched to.
+///The active console also shows its screen on the framebuffer console, if it is enabled.
 pub struct BufferedWriter{
     writer: Writer,
-    backup_buffer: LongBuffer
+    ///Whether this console is shown on the screen.
+    active: bool,
+    ///First screen row of the console.
+    top: usize,
+    ///Number of screen rows used by the console.
+    height: usize,
+    backup_buffer: LongBuffer,
+    ///Number of lines the window is scrolled back from the newest line.
+    scroll_offset: usize,
+    ///Row of the cursor within the console; the bottom row shows the newest line.
+    row: usize,
+    parser: AnsiParser,
+    attributes: TextAttributes,
+    default_attributes: TextAttributes,
+    ///Row, column and attributes stored by a save cursor sequence.
+    saved_cursor: (usize, usize, TextAttributes),
+    cursor: HardwareCursor,
+    ///Whether the hardware cursor is shown; it is hidden while scrolled back.
+    cursor_visible: bool,
 }
 impl BufferedWriter{
+    fn new(backup_buffer: &'static mut [[VGAChar; BUFFER_WIDTH]], active: bool) -> BufferedWriter{
+        let color_code = ColorCode::from_attributes(DEFAULT_ATTRIBUTES);
+        BufferedWriter{
+            writer: Writer {
+                column_position: 0,
+                color_code,
+                buffer: unsafe{&mut *(0xb8000 as *mut Buffer)}
+            },
+            active,
+            top: 0,
+            height: BUFFER_HEIGHT,
+            backup_buffer: LongBuffer::new(backup_buffer, color_code),
+            scroll_offset: 0,
+            row: BUFFER_HEIGHT - 1,
+            parser: AnsiParser::new(),
+            attributes: DEFAULT_ATTRIBUTES,
+            default_attributes: DEFAULT_ATTRIBUTES,
+            saved_cursor: (BUFFER_HEIGHT - 1, 0, DEFAULT_ATTRIBUTES),
+            cursor: HardwareCursor::new(),
+            cursor_visible: false,
+        }
+    }
+    ///Render the visible window of the long buffer to the screen.
     fn write_buf(&mut self){
-        for _ in 0..BUFFER_HEIGHT{
-            //TODO: convert to longbuffer coords
-            //TODO: write last part of longbuffer to writer
+        if !self.active{
+            return;
+        }
+        let bottom = self.backup_buffer.last() as isize - self.scroll_offset as isize;
+        for row in 0..self.height{
+            let index = bottom - (self.height - 1 - row) as isize;
+            for col in 0..BUFFER_WIDTH{
+                let character = if index >= 0 {
+                    self.backup_buffer.line(index as usize)[col]
+                } else {
+                    VGAChar::blank(self.writer.color_code)
+                };
+                self.writer.buffer.chars[self.top + row][col].write(character);
+            }
+        }
+        self.redraw_framebuffer();
+    }
+    ///Show the visible window on the framebuffer console, if it is enabled. The long buffer only holds code
+    ///page 437, so characters outside it that were shown before come back as their replacement.
+    fn redraw_framebuffer(&self){
+        fb_console::with_console(|console|{
+            for row in self.top..self.top + self.height{
+                for col in 0..BUFFER_WIDTH{
+                    let character = self.writer.buffer.chars[row][col].read();
+                    console.set_cell(row, col, character.to_unicode(), character.color_code.to_attributes());
+                }
+            }
+        });
+    }
+    ///Store a character in the long buffer, and on screen if its line is visible. `c` is what the framebuffer
+    ///console shows for it, which may be outside code page 437.
+    fn put(&mut self, index: usize, col: usize, character: VGAChar, c: char){
+        self.backup_buffer.line_mut(index)[col] = character;
+        let distance = self.backup_buffer.last() - index;
+        if self.active && self.scroll_offset == 0 && distance < self.height{
+            let row = self.top + self.height - 1 - distance;
+            self.writer.buffer.chars[row][col].write(character);
+            fb_console::with_console(|console| console.set_cell(row, col, c, character.color_code.to_attributes()));
+        }
+    }
+    ///Jump back to the newest lines if the user scrolled back.
+    fn snap_to_bottom(&mut self){
+        if self.scroll_offset != 0{
+            self.scroll_offset = 0;
+            self.write_buf();
+        }
+    }
+    ///Index in the long buffer of the line shown on the given console row.
+    fn line_at_row(&self, row: usize) -> usize{
+        self.backup_buffer.last() - (self.height - 1 - row)
+    }
+    ///Console row and column of the cursor.
+    pub fn cursor_position(&self) -> (usize, usize){
+        (self.row, self.writer.column_position.min(BUFFER_WIDTH - 1))
+    }
+    ///Move the hardware cursor to the cursor position, or hide it while the window is scrolled back. Ends every
+    ///change to the screen, so it also draws the changes on the framebuffer console.
+    fn sync_cursor(&mut self){
+        if !self.active{
+            return;
+        }
+        let visible = self.scroll_offset == 0;
+        if visible != self.cursor_visible{
+            self.cursor.set_visible(visible);
+            self.cursor_visible = visible;
+        }
+        let (row, col) = self.cursor_position();
+        if visible{
+            self.cursor.set_position(self.top + row, col);
+        }
+        fb_console::with_console(|console| console.set_cursor(Some((self.top + row, col)).filter(|_| visible)));
+        fb_console::flush();
+    }
+    ///Set the scanlines covered by the hardware cursor.
+    pub fn set_cursor_shape(&mut self, start: u8, end: u8){
+        self.cursor.set_shape(start, end);
+        self.cursor_visible = true;
+        self.sync_cursor();
+    }
+    ///Write a code page 437 byte at the cursor.
+    pub fn write_byte(&mut self, byte: u8){
+        self.put_byte(byte, cp437_to_unicode(byte));
+        self.sync_cursor();
+    }
+    ///Write a code page 437 byte at the cursor, shown as `c` on the framebuffer console.
+    fn put_byte(&mut self, byte: u8, c: char){
+        match byte{
+            b'\n' => self.new_line(),
+            byte => {
+                self.snap_to_bottom();
+                if self.writer.column_position >= BUFFER_WIDTH {
+                    self.new_line();
+                }
+                let index = self.line_at_row(self.row);
+                let col = self.writer.column_position;
+                self.put(index, col, VGAChar{
+                    ascii_character: byte,
+                    color_code: self.writer.color_code
+                }, c);
+                self.writer.column_position += 1;
+            }
+        }
+    }
+    ///Move the cursor to the start of the next line, scrolling if it is on the bottom row.
+    fn new_line(&mut self){
+        self.snap_to_bottom();
+        if self.row < self.height - 1{
+            self.row += 1;
+        } else {
+            self.backup_buffer.push_line(self.writer.color_code);
+            if self.active{
+                let region = Rect::new(self.top, 0, BUFFER_WIDTH, self.height);
+                self.writer.scroll_region(region, 1, self.writer.color_code);
+                let attributes = self.writer.color_code.to_attributes();
+                fb_console::with_console(|console| console.scroll_up(self.top, self.height, attributes));
+            }
+        }
+        self.writer.column_position = 0;
+    }
+    ///Write a string to the buffer, interpreting escape sequences and translating it to code page 437.
+    pub fn write_string(&mut self, text : &str){
+        for c in text.chars(){
+            if let Some(action) = self.parser.advance(c){
+                self.apply(action);
+            }
+        }
+        self.sync_cursor();
+    }
+    fn apply(&mut self, action: AnsiAction){
+        let col = self.writer.column_position;
+        match action{
+            AnsiAction::Print('\n') => self.new_line(),
+            AnsiAction::Print('\r') => self.writer.column_position = 0,
+            AnsiAction::Print('\x08') => self.erase_previous(),
+            AnsiAction::Print(c) => self.put_byte(unicode_to_cp437(c), c),
+            AnsiAction::Sgr(sgr) => {
+                self.attributes.apply_sgr(&sgr, self.default_attributes);
+                self.writer.color_code = ColorCode::from_attributes(self.attributes);
+            },
+            AnsiAction::CursorTo(row, col) => self.move_cursor(row as isize, col as isize),
+            AnsiAction::CursorMove(rows, cols) => self.move_cursor(self.row as isize + rows, col as isize + cols),
+            AnsiAction::EraseDisplay(mode) => {
+                let rows = match mode{
+                    EraseMode::ToEnd => self.row..self.height,
+                    EraseMode::ToStart => 0..self.row + 1,
+                    EraseMode::All => 0..self.height,
+                };
+                for row in rows{
+                    let (from, to) = match mode{
+                        EraseMode::ToEnd if row == self.row => (col, BUFFER_WIDTH),
+                        EraseMode::ToStart if row == self.row => (0, col + 1),
+                        _ => (0, BUFFER_WIDTH),
+                    };
+                    self.erase(row, from, to);
+                }
+            },
+            AnsiAction::EraseLine(mode) => {
+                let (from, to) = match mode{
+                    EraseMode::ToEnd => (col, BUFFER_WIDTH),
+                    EraseMode::ToStart => (0, col + 1),
+                    EraseMode::All => (0, BUFFER_WIDTH),
+                };
+                self.erase(self.row, from, to);
+            },
+            AnsiAction::SaveCursor => self.saved_cursor = (self.row, col, self.attributes),
+            AnsiAction::RestoreCursor => {
+                let (row, col, attributes) = self.saved_cursor;
+                self.row = row.min(self.height - 1);
+                self.writer.column_position = col;
+                self.attributes = attributes;
+                self.writer.color_code = ColorCode::from_attributes(attributes);
+            },
+        }
+    }
+    ///Move the cursor to a console position, clamped to the console.
+    fn move_cursor(&mut self, row: isize, col: isize){
+        self.snap_to_bottom();
+        self.row = row.clamp(0, self.height as isize - 1) as usize;
+        self.writer.column_position = col.clamp(0, BUFFER_WIDTH as isize - 1) as usize;
+    }
+    ///Blank the columns `from..to` of a console row.
+    fn erase(&mut self, row: usize, from: usize, to: usize){
+        self.snap_to_bottom();
+        let index = self.line_at_row(row);
+        for col in from..to.min(BUFFER_WIDTH){
+            self.put(index, col, VGAChar::blank(self.writer.color_code), ' ');
+        }
+    }
+    ///Erase the character before the cursor, continuing on the previous line if the cursor is at the start
+    ///of an empty line.
+    pub fn backspace(&mut self){
+        self.erase_previous();
+        self.sync_cursor();
+    }
+    fn erase_previous(&mut self){
+        self.snap_to_bottom();
+        if self.writer.column_position == 0{
+            let index = self.line_at_row(self.row);
+            if self.row == 0 || self.backup_buffer.line_len(index) > 0{
+                return;
+            }
+            self.row -= 1;
+            self.writer.column_position = self.backup_buffer.line_len(index - 1);
+            if self.writer.column_position == 0{
+                return;
+            }
         }
+        self.writer.column_position -= 1;
+        let index = self.line_at_row(self.row);
+        let col = self.writer.column_position;
+        self.put(index, col, VGAChar::blank(self.writer.color_code), ' ');
+    }
+    ///Scroll the window back by the given number of lines, as far as the scrollback goes.
+    pub fn scroll_up(&mut self, lines: usize){
+        let max_offset = self.backup_buffer.len - self.height.min(self.backup_buffer.len);
+        self.scroll_offset = (self.scroll_offset + lines).min(max_offset);
+        self.write_buf();
+        self.sync_cursor();
+    }
+    ///Scroll the window forward by the given number of lines, at most back to the newest line.
+    pub fn scroll_down(&mut self, lines: usize){
+        self.scroll_offset = self.scroll_offset.saturating_sub(lines);
+        self.write_buf();
+        self.sync_cursor();
+    }
+    ///Clear the scrollback and the screen.
+    pub fn clear(&mut self){
+        self.backup_buffer.clear(self.writer.color_code);
+        self.scroll_offset = 0;
+        self.row = self.height - 1;
+        self.writer.column_position = 0;
+        self.write_buf();
+        self.sync_cursor();
+    }
+    ///Limit the console to `height` screen rows starting at `top`, keeping the newest lines on screen.
+    ///Rows outside the console are not touched by it anymore.
+    pub fn set_region(&mut self, top: usize, height: usize){
+        let top = top.min(BUFFER_HEIGHT - 1);
+        let height = height.clamp(1, BUFFER_HEIGHT - top);
+        let distance = (self.height - 1 - self.row).min(height - 1);
+        self.top = top;
+        self.height = height;
+        self.row = height - 1 - distance;
+        self.write_buf();
+        self.sync_cursor();
+    }
+    ///Show or hide this console. A console that becomes active redraws the screen and takes over the cursor.
+    fn set_active(&mut self, active: bool){
+        self.active = active;
+        if active{
+            let visible = self.scroll_offset == 0;
+            self.cursor.set_visible(visible);
+            self.cursor_visible = visible;
+            self.write_buf();
+            self.sync_cursor();
+        }
+    }
+    ///The screen, for drawing outside the console region.
+    pub fn screen(&mut self) -> &mut Writer{
+        &mut self.writer
     }
 }
 use core::fmt;
@@ -191,23 +760,135 @@ impl fmt::Write for Writer{
         Ok(())
     }
 }
+impl fmt::Write for BufferedWriter{
+    ///Write a string into the scrollback and the screen. Complies with fmt::Write.
+    fn write_str(&mut self, s:&str) -> fmt::Result{
+        self.write_string(s);
+        Ok(())
+    }
+}
 
 //lazy static is used to avoid compiletime definition of static variables, making them initialize at runtime instead.
 use lazy_static::lazy_static;
+///Colours of the console before any escape sequence changes them.
+const DEFAULT_ATTRIBUTES: TextAttributes = TextAttributes::new(Color::White as u8, Color::Black as u8);
+///Number of virtual consoles, switched between with Alt+F1 and following.
+pub const CONSOLE_COUNT: usize = 6;
+///Scrollback lines of the first console, which is kept for the kernel output and runs no shell.
+const KERNEL_CONSOLE_LINES: usize = 1000;
+///Scrollback lines of each other console.
+const CONSOLE_LINES: usize = 200;
+const SCROLLBACK_LINES: usize = KERNEL_CONSOLE_LINES + (CONSOLE_COUNT - 1) * CONSOLE_LINES;
+///Scrollback of all consoles. Too large for the stack, so it lives in a static like the double fault stack in
+///gdt.rs; it is all zeroes so it ends up in .bss instead of the kernel image.
+static mut SCROLLBACK: [[VGAChar; BUFFER_WIDTH]; SCROLLBACK_LINES] =
+    [[VGAChar::blank(ColorCode(0)); BUFFER_WIDTH]; SCROLLBACK_LINES];
+///Index of the console shown on the screen.
+static ACTIVE_CONSOLE: AtomicUsize = AtomicUsize::new(0);
+
+///Console with the given index, using its part of `SCROLLBACK`.
+fn new_console(index: usize) -> Mutex<BufferedWriter>{
+    let (start, lines) = match index{
+        0 => (0, KERNEL_CONSOLE_LINES),
+        index => (KERNEL_CONSOLE_LINES + (index - 1) * CONSOLE_LINES, CONSOLE_LINES),
+    };
+    //every console is created once and gets its own lines, so the slices never overlap
+    let backup_buffer = unsafe{
+        let first_line = (core::ptr::addr_of_mut!(SCROLLBACK) as *mut [VGAChar; BUFFER_WIDTH]).add(start);
+        core::slice::from_raw_parts_mut(first_line, lines)
+    };
+    Mutex::new(BufferedWriter::new(backup_buffer, index == 0))
+}
+
 lazy_static!{
-    ///Global writer, to be used by other functions.
-    pub static ref WRITER : Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::White, Color::Black),
-        buffer: unsafe{&mut *(0xb8000 as *mut Buffer)}
-    });
+    ///All virtual consoles.
+    pub static ref CONSOLES: [Mutex<BufferedWriter>; CONSOLE_COUNT] = [
+        new_console(0), new_console(1), new_console(2), new_console(3), new_console(4), new_console(5),
+    ];
+    ///Global writer, to be used by other functions. This is the first console, which gets all kernel output.
+    pub static ref WRITER : &'static Mutex<BufferedWriter> = &CONSOLES[0];
 }
 
+///Scanlines of the default underline cursor in the 16 scanline font.
+const CURSOR_SHAPE: (u8, u8) = (14, 15);
 pub fn init(){
-    for _ in 0..BUFFER_HEIGHT{
-        for _ in 0..BUFFER_WIDTH{
-            WRITER.lock().write_byte(0x0);
+    let mut writer = WRITER.lock();
+    writer.clear();
+    writer.set_cursor_shape(CURSOR_SHAPE.0, CURSOR_SHAPE.1);
+}
+///Run `f` with the active console locked, to draw status bars, menus and the like with the `Writer` screen
+///methods.
+///Use `BufferedWriter::set_region` to keep the console out of the rows being drawn to.
+pub fn with_screen<R>(f: impl FnOnce(&mut Writer) -> R) -> R{
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(||{
+        f(CONSOLES[active_console()].lock().screen())
+    })
+}
+///Draw the active console again, e.g. after the framebuffer console was enabled.
+pub fn redraw(){
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(||{
+        let mut console = CONSOLES[active_console()].lock();
+        console.write_buf();
+        console.sync_cursor();
+    })
+}
+///Index of the console shown on the screen.
+pub fn active_console() -> usize{
+    ACTIVE_CONSOLE.load(Ordering::Relaxed)
+}
+///Show the console with the given index. Indices past the last console are ignored.
+pub fn switch_console(index: usize){
+    use x86_64::instructions::interrupts;
+    if index >= CONSOLE_COUNT{
+        return;
+    }
+    interrupts::without_interrupts(||{
+        let previous = ACTIVE_CONSOLE.swap(index, Ordering::Relaxed);
+        if previous != index{
+            CONSOLES[previous].lock().set_active(false);
+            CONSOLES[index].lock().set_active(true);
         }
+    })
+}
+///Scroll the active console back by half a screen.
+pub fn scroll_up(){
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(||{
+        CONSOLES[active_console()].lock().scroll_up(BUFFER_HEIGHT / 2);
+    })
+}
+///Scroll the active console forward by half a screen.
+pub fn scroll_down(){
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(||{
+        CONSOLES[active_console()].lock().scroll_down(BUFFER_HEIGHT / 2);
+    })
+}
+
+///Handle for writing to one virtual console, e.g. from a shell running on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Console{
+    index: usize,
+}
+impl Console{
+    ///Handle for the console with the given index; panics if there is no such console.
+    pub fn new(index: usize) -> Console{
+        assert!(index < CONSOLE_COUNT, "there are only {} consoles", CONSOLE_COUNT);
+        Console{index}
+    }
+    pub fn index(&self) -> usize{
+        self.index
+    }
+}
+impl fmt::Write for Console{
+    fn write_str(&mut self, s: &str) -> fmt::Result{
+        use x86_64::instructions::interrupts;
+        interrupts::without_interrupts(||{
+            CONSOLES[self.index].lock().write_string(s);
+        });
+        Ok(())
     }
 }
 
@@ -222,6 +903,46 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+///Column the unlocked output has reached on the bottom line.
+static EMERGENCY_COLUMN: AtomicUsize = AtomicUsize::new(0);
+///Write to the bottom line of the text screen without taking any console lock, for code that stops the kernel
+///wherever it is. The consoles do not know about the text and may draw over it.
+pub(crate) fn write_unlocked(text: &str, color_code: ColorCode){
+    let buffer = unsafe{&mut *(0xb8000 as *mut Buffer)};
+    let mut writer = Writer::new(EMERGENCY_COLUMN.load(Ordering::Relaxed), color_code, buffer);
+    writer.write_string(text);
+    EMERGENCY_COLUMN.store(writer.column_position, Ordering::Relaxed);
+}
+///Write white on red, for panics and fatal exceptions.
+pub(crate) fn emergency_write(text: &str){
+    write_unlocked(text, ColorCode::new(Color::White, Color::Red));
+}
+///The characters on the text screen, for code that draws over the consoles and puts them back afterwards.
+pub(crate) struct ScreenBackup{
+    chars: [[VGAChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+}
+impl ScreenBackup{
+    ///Copy the screen, without taking any console lock.
+    pub(crate) fn take() -> ScreenBackup{
+        let buffer = unsafe{&*(0xb8000 as *const Buffer)};
+        let mut chars = [[VGAChar::blank(ColorCode::new(Color::White, Color::Black)); BUFFER_WIDTH]; BUFFER_HEIGHT];
+        for (row, line) in chars.iter_mut().enumerate(){
+            for (col, c) in line.iter_mut().enumerate(){
+                *c = buffer.chars[row][col].read();
+            }
+        }
+        ScreenBackup{chars}
+    }
+    pub(crate) fn restore(&self){
+        let buffer = unsafe{&mut *(0xb8000 as *mut Buffer)};
+        for (row, line) in self.chars.iter().enumerate(){
+            for (col, &c) in line.iter().enumerate(){
+                buffer.chars[row][col].write(c);
+            }
+        }
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
@@ -257,8 +978,157 @@ fn printed_exists(){
         writeln!(writer, "");
         write!(writer, "{}", s);
         for (i,c) in s.chars().enumerate(){
-            assert_eq!(writer.buffer.chars[BUFFER_HEIGHT - 1][i].read().ascii_character, c as u8);
+            assert_eq!(writer.writer.buffer.chars[BUFFER_HEIGHT - 1][i].read().ascii_character, c as u8);
         }
     })
     
-}
\ No newline at end of file
+}
+#[test_case]
+fn scrollback_keeps_old_lines(){
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(||{
+        let mut writer = WRITER.lock();
+        writer.write_string("\nfirst");
+        for _ in 0..BUFFER_HEIGHT + 5{
+            writer.write_byte(b'\n');
+        }
+        writer.scroll_up(BUFFER_HEIGHT + 5);
+        assert_eq!(writer.writer.buffer.chars[BUFFER_HEIGHT - 1][0].read().ascii_character, b'f');
+        writer.scroll_down(BUFFER_HEIGHT + 5);
+        assert_eq!(writer.writer.buffer.chars[BUFFER_HEIGHT - 1][0].read().ascii_character, 0x0);
+    })
+}
+#[test_case]
+fn backspace_crosses_wrapped_line(){
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(||{
+        let mut writer = WRITER.lock();
+        writer.write_byte(b'\n');
+        for _ in 0..BUFFER_WIDTH + 1{
+            writer.write_byte(b'x');
+        }
+        writer.backspace();
+        writer.backspace();
+        assert_eq!(writer.writer.column_position, BUFFER_WIDTH - 1);
+        assert_eq!(writer.writer.buffer.chars[BUFFER_HEIGHT - 2][BUFFER_WIDTH - 1].read().ascii_character, 0x0);
+        assert_eq!(writer.writer.buffer.chars[BUFFER_HEIGHT - 2][BUFFER_WIDTH - 2].read().ascii_character, b'x');
+        //leave the cursor on the bottom row for the other tests
+        writer.write_byte(b'\n');
+    })
+}
+#[test_case]
+fn unicode_is_stored_as_cp437(){
+    use x86_64::instructions::interrupts;
+    let s = "°§ß ┌─┐ äÖü ░█ αΣπ";
+    let expected = [0xf8, 0x15, 0xe1, b' ', 0xda, 0xc4, 0xbf, b' ', 0x84, 0x99, 0x81, b' ', 0xb0, 0xdb, b' ', 0xe0, 0xe4, 0xe3];
+    interrupts::without_interrupts(||{
+        let mut writer = WRITER.lock();
+        writer.write_byte(b'\n');
+        writer.write_string(s);
+        for (i, byte) in expected.iter().enumerate(){
+            assert_eq!(writer.writer.buffer.chars[BUFFER_HEIGHT - 1][i].read().ascii_character, *byte);
+        }
+    })
+}
+#[test_case]
+fn unmappable_uses_fallback(){
+    assert_eq!(unicode_to_cp437('€'), CP437_FALLBACK);
+    assert_eq!(unicode_to_cp437('\t'), CP437_FALLBACK);
+    assert_eq!(unicode_to_cp437('A'), b'A');
+    assert_eq!(unicode_to_cp437('♥'), 0x03);
+}
+#[test_case]
+fn ansi_colour_cursor_and_erase(){
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(||{
+        let mut writer = WRITER.lock();
+        writer.write_string("\n\x1b[31mR\x1b[0m");
+        let cell = writer.writer.buffer.chars[BUFFER_HEIGHT - 1][0].read();
+        assert_eq!((cell.ascii_character, cell.color_code), (b'R', ColorCode::new(Color::Red, Color::Black)));
+        writer.write_string("\x1b[s\x1b[1;1HX\x1b[u");
+        assert_eq!(writer.writer.buffer.chars[0][0].read().ascii_character, b'X');
+        writer.write_string("\x1b[2K");
+        assert_eq!(writer.writer.buffer.chars[BUFFER_HEIGHT - 1][0].read().ascii_character, 0x0);
+        assert_eq!(writer.row, BUFFER_HEIGHT - 1);
+    })
+}
+#[test_case]
+fn cursor_follows_backspace_and_scrolling(){
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(||{
+        let mut writer = WRITER.lock();
+        //make sure there is something to scroll back to
+        for _ in 0..BUFFER_HEIGHT{
+            writer.write_byte(b'\n');
+        }
+        writer.write_string("abc");
+        assert_eq!(writer.cursor_position(), (BUFFER_HEIGHT - 1, 3));
+        assert_eq!(writer.cursor.position(), (writer.top + BUFFER_HEIGHT - 1, 3));
+        writer.backspace();
+        assert_eq!(writer.cursor_position(), (BUFFER_HEIGHT - 1, 2));
+        assert_eq!(writer.cursor.position(), (writer.top + BUFFER_HEIGHT - 1, 2));
+        writer.scroll_up(1);
+        assert!(!writer.cursor_visible);
+        assert!(!writer.cursor.is_visible());
+        writer.scroll_down(1);
+        assert!(writer.cursor_visible);
+        assert!(writer.cursor.is_visible());
+    })
+}
+#[test_case]
+fn screen_box_and_scroll_region(){
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(||{
+        let mut writer = WRITER.lock();
+        let color = ColorCode::new(Color::Yellow, Color::Blue);
+        let screen = writer.screen();
+        screen.draw_box(Rect::new(2, 10, 6, 4), BoxStyle::Double, color);
+        screen.write_str_at(3, 11, "ab", color);
+        assert_eq!(screen.read_at(2, 10), Some((0xc9, color)));
+        assert_eq!(screen.read_at(5, 15), Some((0xbc, color)));
+        assert_eq!(screen.read_at(3, 12), Some((b'b', color)));
+        screen.write_str_at(4, 11, "cd", color);
+        screen.scroll_region(Rect::new(3, 11, 4, 2), 1, color);
+        assert_eq!(screen.read_at(3, 11), Some((b'c', color)));
+        assert_eq!(screen.read_at(4, 11), Some((0x0, color)));
+        assert_eq!(screen.read_at(2, 10), Some((0xc9, color)));
+        writer.write_buf();
+    })
+}
+#[test_case]
+fn console_region_leaves_status_row(){
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(||{
+        let mut writer = WRITER.lock();
+        writer.set_region(0, BUFFER_HEIGHT - 1);
+        let color = ColorCode::new(Color::Black, Color::LightGray);
+        writer.screen().write_str_at(BUFFER_HEIGHT - 1, 0, "status", color);
+        for _ in 0..BUFFER_HEIGHT{
+            writer.write_string("scrolling\n");
+        }
+        assert_eq!(writer.screen().read_at(BUFFER_HEIGHT - 1, 0), Some((b's', color)));
+        assert_eq!(writer.screen().read_at(BUFFER_HEIGHT - 2, 0), Some((0x0, writer.writer.color_code)));
+        writer.set_region(0, BUFFER_HEIGHT);
+        assert_eq!(writer.row, BUFFER_HEIGHT - 1);
+    })
+}
+#[test_case]
+fn inactive_console_keeps_screen(){
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(||{
+        let mut writer = WRITER.lock();
+        writer.write_string("\nshown");
+        let mut background = CONSOLES[1].lock();
+        background.write_string("\nhidden");
+        assert_eq!(writer.writer.buffer.chars[BUFFER_HEIGHT - 1][0].read().ascii_character, b's');
+        let index = background.line_at_row(BUFFER_HEIGHT - 1);
+        assert_eq!(background.backup_buffer.line(index)[0].ascii_character, b'h');
+    });
+    switch_console(1);
+    assert_eq!(WRITER.lock().writer.buffer.chars[BUFFER_HEIGHT - 1][0].read().ascii_character, b'h');
+    let _ = write!(Console::new(0), "\nlog");
+    assert_eq!(WRITER.lock().writer.buffer.chars[BUFFER_HEIGHT - 1][0].read().ascii_character, b'h');
+    switch_console(0);
+    assert_eq!(WRITER.lock().writer.buffer.chars[BUFFER_HEIGHT - 1][0].read().ascii_character, b'l');
+}
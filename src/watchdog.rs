@@ -0,0 +1,312 @@
+//! A software watchdog, checked by the timer interrupt.
+//!
+//! It notices a task the executor has been polling for longer than its budget, and timer interrupts arriving
+//! late, i.e. interrupts having been disabled for too long. The time between ticks is measured with the TSC, whose
+//! rate is calibrated against the first ticks. Both problems are logged with a backtrace of the interrupted code,
+//! then the configured action is taken. Timer interrupts that stop altogether are noticed by the executor instead,
+//! which only checks between polls: it does not notice while a task never yields, nor while it sleeps in `hlt`
+//! until the next interrupt, which without the timer may be a long time. Catching those would take a second
+//! timer, such as the local APIC's, which the kernel does not set up.
+//!
+//! Records logged from the interrupt handler only reach the sinks once tasks run again, so a task that hangs for
+//! good shows up in `dmesg` rather than on screen, unless the action is a panic.
+
+use core::arch::x86_64::_rdtsc;
+use core::fmt::{self, Write};
+use core::str::FromStr;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use crate::backtrace::Backtrace;
+use crate::interrupts::{milliseconds_elapsed, PIT_MS_PER_INTERRUPT};
+use crate::task;
+
+///Milliseconds a task may be polled for before it is reported, unless set otherwise.
+pub const DEFAULT_TASK_BUDGET: u64 = 1000;
+///Milliseconds without a timer interrupt after which it is reported, unless set otherwise.
+pub const DEFAULT_STALL_LIMIT: u64 = 500;
+///Ticks the TSC rate is measured over.
+const CALIBRATION_TICKS: u64 = 100;
+
+///What the watchdog does after logging a problem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Action{
+    Log,
+    Panic,
+    Reboot,
+}
+impl Action{
+    pub const ALL: [Action; 3] = [Action::Log, Action::Panic, Action::Reboot];
+    pub fn name(self) -> &'static str{
+        match self{
+            Action::Log => "log",
+            Action::Panic => "panic",
+            Action::Reboot => "reboot",
+        }
+    }
+}
+impl FromStr for Action{
+    type Err = ();
+    fn from_str(name: &str) -> Result<Action, ()>{
+        Action::ALL.iter().copied().find(|action| action.name() == name).ok_or(())
+    }
+}
+impl fmt::Display for Action{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        f.write_str(self.name())
+    }
+}
+
+///A problem the watchdog found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Problem{
+    ///A task has been polled for this many milliseconds.
+    TaskOverBudget{task: u64, milliseconds: u64},
+    ///A timer interrupt came this many milliseconds after the one before it.
+    LateTick{milliseconds: u64},
+    ///No timer interrupt has come for this many milliseconds.
+    TimerStopped{milliseconds: u64},
+}
+impl fmt::Display for Problem{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result{
+        match *self{
+            Problem::TaskOverBudget{task, milliseconds} => write!(f, "task {} ({}) has not yielded for {} ms",
+                task, task::try_task_name(task).unwrap_or("?"), milliseconds),
+            Problem::LateTick{milliseconds} => write!(f, "no timer interrupt for {} ms", milliseconds),
+            Problem::TimerStopped{milliseconds} => write!(f, "timer interrupts stopped {} ms ago", milliseconds),
+        }
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+static TASK_BUDGET: AtomicU64 = AtomicU64::new(DEFAULT_TASK_BUDGET);
+static STALL_LIMIT: AtomicU64 = AtomicU64::new(DEFAULT_STALL_LIMIT);
+static ACTION: AtomicU8 = AtomicU8::new(Action::Log as u8);
+
+///TSC at the last timer interrupt; 0 before the first.
+static LAST_TICK: AtomicU64 = AtomicU64::new(0);
+///TSC and tick count when calibration started.
+static CALIBRATION_START: AtomicU64 = AtomicU64::new(0);
+static CALIBRATION_TICK: AtomicU64 = AtomicU64::new(0);
+///TSC cycles per millisecond; 0 until calibrated.
+static CYCLES_PER_MS: AtomicU64 = AtomicU64::new(0);
+///Start of the last poll reported, plus 1 so that 0 means none, so each poll is reported once.
+static REPORTED_POLL: AtomicU64 = AtomicU64::new(0);
+///Whether the timer was reported as stopped since its last interrupt.
+static STOP_REPORTED: AtomicBool = AtomicBool::new(false);
+
+pub fn is_enabled() -> bool{
+    ENABLED.load(Ordering::Relaxed)
+}
+pub fn set_enabled(enabled: bool){
+    reset();
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+///Milliseconds a task may be polled for.
+pub fn task_budget() -> u64{
+    TASK_BUDGET.load(Ordering::Relaxed)
+}
+pub fn set_task_budget(milliseconds: u64){
+    TASK_BUDGET.store(milliseconds, Ordering::Relaxed);
+}
+///Milliseconds without a timer interrupt that are tolerated.
+pub fn stall_limit() -> u64{
+    STALL_LIMIT.load(Ordering::Relaxed)
+}
+pub fn set_stall_limit(milliseconds: u64){
+    STALL_LIMIT.store(milliseconds, Ordering::Relaxed);
+}
+pub fn action() -> Action{
+    Action::ALL[ACTION.load(Ordering::Relaxed) as usize]
+}
+pub fn set_action(action: Action){
+    ACTION.store(action as u8, Ordering::Relaxed);
+}
+
+///Forget the time spent so far, e.g. after the kernel was stopped in a debugger.
+pub fn reset(){
+    if LAST_TICK.load(Ordering::Relaxed) != 0{
+        LAST_TICK.store(unsafe{_rdtsc()}, Ordering::Relaxed);
+    }
+    if let Some((_, started)) = task::running_since(){
+        REPORTED_POLL.store(started + 1, Ordering::Relaxed);
+    }
+}
+
+///Count a timer interrupt, and find what is wrong.
+///
+///Called by the timer interrupt, which then reports the problem, as only it can see the interrupted frame.
+pub fn tick() -> Option<Problem>{
+    let now = unsafe{_rdtsc()};
+    let last = LAST_TICK.swap(now, Ordering::Relaxed);
+    STOP_REPORTED.store(false, Ordering::Relaxed);
+    let cycles_per_ms = CYCLES_PER_MS.load(Ordering::Relaxed);
+    if cycles_per_ms == 0{
+        calibrate(now);
+        return None;
+    }
+    if !is_enabled(){
+        return None;
+    }
+    let running = task::running_since();
+    let limits = Limits{task_budget: task_budget(), stall_limit: stall_limit()};
+    let state = TickState{now, last, cycles_per_ms, milliseconds: milliseconds_elapsed(), running};
+    let problem = check_tick(state, REPORTED_POLL.load(Ordering::Relaxed), limits);
+    if let (Some(Problem::TaskOverBudget{..}), Some((_, started))) = (problem, running){
+        REPORTED_POLL.store(started + 1, Ordering::Relaxed);
+    }
+    problem
+}
+
+///Limits a timer interrupt is checked against, in milliseconds.
+#[derive(Debug, Clone, Copy)]
+struct Limits{
+    task_budget: u64,
+    stall_limit: u64,
+}
+///What the watchdog knows at a timer interrupt.
+#[derive(Debug, Clone, Copy)]
+struct TickState{
+    ///TSC now and at the timer interrupt before.
+    now: u64,
+    last: u64,
+    cycles_per_ms: u64,
+    ///Milliseconds since boot.
+    milliseconds: u64,
+    ///Task being polled and the millisecond its poll started, see `task::running_since`.
+    running: Option<(u64, u64)>,
+}
+///Problem found at a timer interrupt. `reported` is the start of the last poll reported plus 1, as kept in
+///`REPORTED_POLL`, so a poll over its budget is only found once.
+fn check_tick(state: TickState, reported: u64, limits: Limits) -> Option<Problem>{
+    let milliseconds = state.now.saturating_sub(state.last) / state.cycles_per_ms;
+    if milliseconds > limits.stall_limit{
+        return Some(Problem::LateTick{milliseconds});
+    }
+    let (task, started) = state.running?;
+    let milliseconds = state.milliseconds.saturating_sub(started);
+    if milliseconds > limits.task_budget && reported != started + 1{
+        return Some(Problem::TaskOverBudget{task, milliseconds});
+    }
+    None
+}
+
+fn calibrate(now: u64){
+    let tick = milliseconds_elapsed() / PIT_MS_PER_INTERRUPT as u64;
+    match CALIBRATION_START.load(Ordering::Relaxed){
+        0 => {
+            CALIBRATION_START.store(now, Ordering::Relaxed);
+            CALIBRATION_TICK.store(tick, Ordering::Relaxed);
+        },
+        start => {
+            let start = (start, CALIBRATION_TICK.load(Ordering::Relaxed));
+            if let Some(cycles_per_ms) = measure_cycles_per_ms(start, (now, tick)){
+                CYCLES_PER_MS.store(cycles_per_ms, Ordering::Relaxed);
+            }
+        },
+    }
+}
+///TSC cycles per millisecond between two (TSC, timer tick) readings, once they are `CALIBRATION_TICKS` apart.
+fn measure_cycles_per_ms(start: (u64, u64), end: (u64, u64)) -> Option<u64>{
+    let ticks = end.1.saturating_sub(start.1);
+    if ticks < CALIBRATION_TICKS{
+        return None;
+    }
+    let cycles_per_ms = end.0.saturating_sub(start.0) / (ticks * PIT_MS_PER_INTERRUPT as u64);
+    Some(cycles_per_ms.max(1))
+}
+
+///TSC cycles per millisecond, once measured against the timer.
+pub fn cycles_per_ms() -> Option<u64>{
+    match CYCLES_PER_MS.load(Ordering::Relaxed){
+        0 => None,
+        cycles_per_ms => Some(cycles_per_ms),
+    }
+}
+
+///Check that timer interrupts still come. Called by the executor between polls, which keep running while they do
+///not; see the module documentation for what this misses.
+pub fn check_timer(){
+    let cycles_per_ms = CYCLES_PER_MS.load(Ordering::Relaxed);
+    if !is_enabled() || cycles_per_ms == 0{
+        return;
+    }
+    let milliseconds = unsafe{_rdtsc()}.saturating_sub(LAST_TICK.load(Ordering::Relaxed)) / cycles_per_ms;
+    if milliseconds > stall_limit() && !STOP_REPORTED.swap(true, Ordering::Relaxed){
+        report(Problem::TimerStopped{milliseconds}, None);
+    }
+}
+
+///Log a problem with the backtrace of the code it was found in, then take the configured action.
+pub fn report(problem: Problem, backtrace: Option<&Backtrace>){
+    log::error!("watchdog: {}", problem);
+    if let Some(backtrace) = backtrace{
+        let mut lines = LogLines{line: [0; LINE_BYTES], len: 0};
+        let _ = write!(lines, "{}", backtrace);
+    }
+    match action(){
+        Action::Log => {},
+        Action::Panic => panic!("watchdog: {}", problem),
+        Action::Reboot => crate::reboot(),
+    }
+}
+
+const LINE_BYTES: usize = 128;
+///Logs what is written to it line by line, as log records are meant to be single lines.
+struct LogLines{
+    line: [u8; LINE_BYTES],
+    len: usize,
+}
+impl Write for LogLines{
+    fn write_str(&mut self, s: &str) -> fmt::Result{
+        for &byte in s.as_bytes(){
+            if byte == b'\n'{
+                //a line cut short may end in part of a character
+                let line = &self.line[..self.len];
+                let line = core::str::from_utf8(line).unwrap_or_else(|error|{
+                    core::str::from_utf8(&line[..error.valid_up_to()]).unwrap_or_default()
+                });
+                log::error!("watchdog: {}", line);
+                self.len = 0;
+            } else if self.len < LINE_BYTES{
+                self.line[self.len] = byte;
+                self.len += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+//----------TEST CASES------------
+#[test_case]
+fn actions_parse(){
+    for action in Action::ALL{
+        assert_eq!(action.name().parse(), Ok(action));
+        assert_eq!(Action::ALL[action as usize], action);
+    }
+    assert_eq!("halt".parse::<Action>(), Err(()));
+}
+#[test_case]
+fn ticks_find_late_ticks_and_slow_tasks(){
+    let limits = Limits{task_budget: 1000, stall_limit: 500};
+    let state = TickState{now: 2_010_000, last: 2_000_000, cycles_per_ms: 1000, milliseconds: 5000, running: None};
+    assert_eq!(check_tick(state, 0, limits), None);
+    //600 ms since the last tick
+    let late = TickState{last: 1_410_000, ..state};
+    assert_eq!(check_tick(late, 0, limits), Some(Problem::LateTick{milliseconds: 600}));
+    //a poll started at 4500 ms is within its budget, one started at 3000 ms is not, until it was reported
+    let polling = TickState{running: Some((7, 4500)), ..state};
+    assert_eq!(check_tick(polling, 0, limits), None);
+    let slow = TickState{running: Some((7, 3000)), ..state};
+    assert_eq!(check_tick(slow, 0, limits), Some(Problem::TaskOverBudget{task: 7, milliseconds: 2000}));
+    assert_eq!(check_tick(slow, 3001, limits), None);
+    assert_eq!(check_tick(TickState{running: Some((8, 3500)), ..slow}, 3001, limits),
+               Some(Problem::TaskOverBudget{task: 8, milliseconds: 1500}));
+}
+#[test_case]
+fn calibration_waits_for_enough_ticks(){
+    let cycles_per_tick = 3000 * PIT_MS_PER_INTERRUPT as u64;
+    let start = (1_000_000, 10);
+    assert_eq!(measure_cycles_per_ms(start, (1_000_000 + 50 * cycles_per_tick, 60)), None);
+    let end = (1_000_000 + CALIBRATION_TICKS * cycles_per_tick, 10 + CALIBRATION_TICKS);
+    assert_eq!(measure_cycles_per_ms(start, end), Some(3000));
+}
@@ -0,0 +1,113 @@
+#!/usr/bin/env python3
+"""Cargo runner: embed the kernel's symbol table, then boot it with `bootimage runner`.
+
+The kernel reserves the `.ksyms` section for its symbol table (see src/backtrace.rs). This fills it in from the
+ELF symbol table of the linked kernel, so backtraces can name functions.
+"""
+import os
+import re
+import struct
+import sys
+
+MAGIC = b"KSYMTAB\0"
+HEADER = struct.Struct("<8sII")
+ENTRY = struct.Struct("<QII")
+SECTION = struct.Struct("<IIQQQQIIQQ")
+SYMBOL = struct.Struct("<IBBHQQ")
+SHT_SYMTAB = 2
+STT_FUNC = 2
+
+ESCAPES = {"$SP$": "@", "$BP$": "*", "$RF$": "&", "$LT$": "<", "$GT$": ">", "$LP$": "(", "$RP$": ")", "$C$": ","}
+
+
+def demangle(name):
+    """Demangle a legacy Rust symbol name, dropping its hash."""
+    if not name.startswith("_ZN"):
+        return name
+    parts, i = [], 3
+    while i < len(name) and name[i] != "E":
+        j = i
+        while j < len(name) and name[j].isdigit():
+            j += 1
+        if j == i:
+            return name
+        length = int(name[i:j])
+        parts.append(name[j:j + length])
+        i = j + length
+    if parts and re.fullmatch(r"h[0-9a-f]{16}", parts[-1]):
+        parts.pop()
+    result = []
+    for part in parts:
+        if part.startswith("_$"):
+            part = part[1:]
+        for escape, text in ESCAPES.items():
+            part = part.replace(escape, text)
+        part = re.sub(r"\$u([0-9a-f]+)\$", lambda match: chr(int(match.group(1), 16)), part)
+        result.append(part.replace("..", "::"))
+    return "::".join(result)
+
+
+def sections(elf):
+    shoff, = struct.unpack_from("<Q", elf, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", elf, 0x3a)
+    headers = [SECTION.unpack_from(elf, shoff + i * shentsize) for i in range(shnum)]
+    names = headers[shstrndx][4]
+    for header in headers:
+        name = elf[names + header[0]:elf.index(b"\0", names + header[0])].decode()
+        yield name, header
+
+
+def build_table(elf, all_sections):
+    """The symbol table in the kernel's format, from the functions in the ELF symbol table."""
+    symbols = {}
+    for _, (_, kind, _, _, offset, size, link, _, _, entsize) in all_sections:
+        if kind != SHT_SYMTAB:
+            continue
+        strings = all_sections[link][1][4]
+        for index in range(size // entsize):
+            name, info, _, shndx, value, symbol_size = SYMBOL.unpack_from(elf, offset + index * entsize)
+            if info & 0xf != STT_FUNC or shndx == 0 or value == 0:
+                continue
+            raw = elf[strings + name:elf.index(b"\0", strings + name)].decode(errors="replace")
+            section_end = all_sections[shndx][1][3] + all_sections[shndx][1][5]
+            symbols.setdefault(value, (symbol_size, section_end, demangle(raw)))
+    entries, names = bytearray(), bytearray()
+    addresses = sorted(symbols)
+    for index, address in enumerate(addresses):
+        size, end, name = symbols[address]
+        if size == 0:
+            # assembly symbols have no size: they reach to the next symbol or the end of their section
+            if index + 1 < len(addresses):
+                end = min(end, addresses[index + 1])
+            size = max(end - address, 0)
+        entries += ENTRY.pack(address, min(size, 0xffffffff), len(names))
+        names += name.encode() + b"\0"
+    header = HEADER.pack(MAGIC, len(symbols), HEADER.size + len(entries))
+    return header + entries + names, len(symbols)
+
+
+def embed_symbols(path):
+    with open(path, "r+b") as file:
+        elf = file.read()
+        all_sections = list(sections(elf))
+        ksyms = [header for name, header in all_sections if name == ".ksyms"]
+        if not ksyms:
+            print("embed symbols: no .ksyms section in", path, file=sys.stderr)
+            return
+        offset, size = ksyms[0][4], ksyms[0][5]
+        if elf[offset:offset + len(MAGIC)] != MAGIC:
+            print("embed symbols: .ksyms does not start with the table magic", file=sys.stderr)
+            return
+        table, count = build_table(elf, all_sections)
+        if len(table) > size:
+            print("embed symbols: {} bytes of symbols do not fit into {} bytes".format(len(table), size),
+                  file=sys.stderr)
+            return
+        file.seek(offset)
+        file.write(table.ljust(size, b"\0"))
+    print("embed symbols: {} functions".format(count), file=sys.stderr)
+
+
+if __name__ == "__main__":
+    embed_symbols(sys.argv[1])
+    os.execvp("bootimage", ["bootimage", "runner"] + sys.argv[1:])